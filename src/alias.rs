@@ -0,0 +1,134 @@
+//! User-defined command aliases from the `[alias]` table of the [`Config`]
+//!
+//! ```toml
+//! [alias]
+//! stream = "diff streaming.toml --apply && health"
+//! ```
+//!
+//! Commands separated by `&&` run one after another until one of them fails. Arguments given after
+//! the alias are appended to the last command, options given before it apply to all of them.
+
+use anyhow::{Context, Result, bail};
+use std::mem;
+use tidal_wave::config::Config;
+
+/// Maximum depth of aliases referring to other aliases
+pub const MAX_DEPTH: usize = 8;
+
+/// Expand an alias into the argument lists of the commands to run
+///
+/// `argv` is the full command line and `args` its tail starting at the alias name.
+pub fn expand(config: &Config, argv: &[String], args: &[String]) -> Result<Vec<Vec<String>>> {
+    let (name, extra) = args.split_first().context("missing alias name")?;
+    let alias = config
+        .alias
+        .get(name)
+        .with_context(|| format!("unknown command or alias `{name}`"))?;
+    let prefix = &argv[..argv.len() - args.len()];
+
+    let mut commands = split(alias).with_context(|| format!("in alias `{name}`"))?;
+    if let Some(last) = commands.last_mut() {
+        last.extend_from_slice(extra);
+    }
+    Ok(commands
+        .into_iter()
+        .map(|command| [prefix, &command].concat())
+        .collect())
+}
+
+/// Split an alias into commands at `&&` and those into arguments at whitespace, honoring single
+/// and double quotes
+///
+/// Only an unquoted `&&` separates commands, inside quotes it is part of the argument.
+fn split(alias: &str) -> Result<Vec<Vec<String>>> {
+    let mut commands = Vec::new();
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = alias.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => arg.get_or_insert_default().push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                arg.get_or_insert_default();
+            }
+            (None, '&') if chars.next_if_eq(&'&').is_some() => {
+                args.extend(arg.take());
+                if args.is_empty() {
+                    bail!("empty command before `&&`");
+                }
+                commands.push(mem::take(&mut args));
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (None, c) => arg.get_or_insert_default().push(c),
+        }
+    }
+
+    if let Some(q) = quote {
+        bail!("unterminated {q}");
+    }
+    args.extend(arg);
+    if args.is_empty() {
+        bail!("empty command");
+    }
+    commands.push(args);
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn splits_commands_at_unquoted_ands() {
+        for (alias, expected) in [
+            (
+                "diff streaming.toml --apply && health",
+                vec![
+                    args(&["diff", "streaming.toml", "--apply"]),
+                    args(&["health"]),
+                ],
+            ),
+            ("get&&health", vec![args(&["get"]), args(&["health"])]),
+            (
+                r#"set --name "a && b""#,
+                vec![args(&["set", "--name", "a && b"])],
+            ),
+            ("set name='a&&b'", vec![args(&["set", "name=a&&b"])]),
+            ("set name=''", vec![args(&["set", "name="])]),
+        ] {
+            assert_eq!(split(alias).unwrap(), expected, "{alias}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_aliases() {
+        for alias in ["", "get &&", "&& get", "get && && health", "set 'mute"] {
+            assert!(split(alias).is_err(), "{alias:?}");
+        }
+    }
+
+    #[test]
+    fn appends_arguments_to_the_last_command() {
+        let mut config = Config::default();
+        config
+            .alias
+            .insert("stream".to_owned(), "get mute && set".to_owned());
+        let argv = args(&["tidal-wave", "--mock", "stream", "mute=true"]);
+
+        assert_eq!(
+            expand(&config, &argv, &argv[2..]).unwrap(),
+            [
+                args(&["tidal-wave", "--mock", "get", "mute"]),
+                args(&["tidal-wave", "--mock", "set", "mute=true"]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,282 @@
+//! LED animations, driven by a task writing temporary configs at a fixed frame rate
+//!
+//! Started with an `animation` field on an input line:
+//!
+//! ```json
+//! {"animation": {"kind": "breathe", "period_ms": 2000}}
+//! ```
+//!
+//! and stopped with `{"animation": {"kind": "off"}}`. The animated color field keeps its regular
+//! value in the state, which is what gets reported and what the animation is based on.
+//!
+//! With the `pipewire` feature, the `level` and `level_hue` animations follow an audio signal
+//! instead of a period, see [`audio`](crate::audio).
+
+#[cfg(feature = "pipewire")]
+use crate::audio::Capture;
+use crate::{
+    backend::DeviceBackend,
+    state_bus::StateBus,
+    usb_device::{Color, DeviceConfiguration, Mode},
+    watchdog,
+};
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use std::{
+    f64::consts::TAU,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+/// How often the task checks for a new animation while none is running
+const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// Stop the running animation
+    Off,
+    /// Fade the brightness smoothly in and out
+    Breathe,
+    /// Flash at full brightness and fade out
+    Pulse,
+    /// Cycle through all hues at the color's brightness
+    Rainbow,
+    /// Scale the brightness with the level of a PipeWire source
+    #[cfg(feature = "pipewire")]
+    Level,
+    /// Sweep the hue from green to red with the level of a PipeWire source
+    #[cfg(feature = "pipewire")]
+    LevelHue,
+}
+
+/// Which color field an animation drives
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    #[default]
+    ColorGen,
+    ColorMute,
+}
+
+impl Target {
+    fn field(self, config: &mut DeviceConfiguration) -> &mut Color {
+        match self {
+            Target::ColorGen => &mut config.color_gen,
+            Target::ColorMute => &mut config.color_mute,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Animation {
+    pub kind: Kind,
+    #[serde(default)]
+    pub target: Target,
+    /// Length of one cycle
+    #[serde(default = "default_period_ms")]
+    pub period_ms: u64,
+    /// Frames written per second
+    #[serde(default = "default_fps")]
+    pub fps: u32,
+    /// PipeWire node the level animations capture, the default source if not given
+    #[cfg(feature = "pipewire")]
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Level in dBFS the level animations show as silence
+    #[cfg(feature = "pipewire")]
+    #[serde(default = "default_floor_db")]
+    pub floor_db: f64,
+}
+
+fn default_period_ms() -> u64 {
+    2000
+}
+
+fn default_fps() -> u32 {
+    30
+}
+
+#[cfg(feature = "pipewire")]
+fn default_floor_db() -> f64 {
+    -60.0
+}
+
+impl Animation {
+    /// Start the animation, or `None` if it stops the running one
+    pub fn start(self) -> Result<Option<Running>> {
+        if !(1..=60).contains(&self.fps) {
+            bail!("animation fps must be between 1 and 60, got {}", self.fps);
+        }
+        if self.period_ms < 100 {
+            bail!(
+                "animation period must be at least 100ms, got {}ms",
+                self.period_ms
+            );
+        }
+
+        Ok(match self.kind {
+            Kind::Off => None,
+            _ => Some(Running {
+                #[cfg(feature = "pipewire")]
+                capture: self.capture()?,
+                animation: self,
+                start: Instant::now(),
+            }),
+        })
+    }
+
+    /// Start capturing for the level animations
+    #[cfg(feature = "pipewire")]
+    fn capture(&self) -> Result<Option<Capture>> {
+        if !matches!(self.kind, Kind::Level | Kind::LevelHue) {
+            return Ok(None);
+        }
+        if self.floor_db >= 0.0 {
+            bail!("floor_db must be below 0dBFS, got {}", self.floor_db);
+        }
+        Capture::start(self.source.as_deref(), self.floor_db).map(Some)
+    }
+}
+
+#[derive(Debug)]
+pub struct Running {
+    animation: Animation,
+    start: Instant,
+    /// Only for the level animations, stopped along with them
+    #[cfg(feature = "pipewire")]
+    capture: Option<Capture>,
+}
+
+impl Running {
+    /// Replace the animated color in a config read from the device with its regular value
+    pub fn mask(
+        &self,
+        mut config: DeviceConfiguration,
+        mut regular: DeviceConfiguration,
+    ) -> DeviceConfiguration {
+        let target = self.animation.target;
+        *target.field(&mut config) = *target.field(&mut regular);
+        config
+    }
+
+    /// `config` with the animated color of the current frame
+    fn frame(&self, mut config: DeviceConfiguration) -> DeviceConfiguration {
+        let Animation {
+            kind,
+            target,
+            period_ms,
+            ..
+        } = self.animation;
+        let phase =
+            (self.start.elapsed().as_millis() % u128::from(period_ms)) as f64 / period_ms as f64;
+
+        let color = target.field(&mut config);
+        *color = match kind {
+            Kind::Off => *color,
+            Kind::Breathe => color.scaled(0.5 - 0.5 * (TAU * phase).cos()),
+            Kind::Pulse => color.scaled((-5.0 * phase).exp()),
+            Kind::Rainbow => {
+                let (_, _, v) = color.to_hsv();
+                Color::from_hsv(360.0 * phase, 1.0, v)
+            }
+            #[cfg(feature = "pipewire")]
+            Kind::Level => color.scaled(self.level()),
+            #[cfg(feature = "pipewire")]
+            Kind::LevelHue => {
+                let (_, _, v) = color.to_hsv();
+                Color::from_hsv(120.0 * (1.0 - self.level()), 1.0, v)
+            }
+        };
+        config
+    }
+
+    #[cfg(feature = "pipewire")]
+    fn level(&self) -> f64 {
+        self.capture.as_ref().map_or(0.0, Capture::level)
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.animation.fps
+    }
+}
+
+/// Write the frames of the running animation, and the regular colors once it stops
+pub async fn animate<D: DeviceBackend>(device: D, state: StateBus) {
+    let mut animating = false;
+
+    loop {
+        let (config, interval, running) = state
+            .with(move |state| match &state.animation {
+                Some(running) => (
+                    Some(state.outgoing(running.frame(state.cached))),
+                    running.interval(),
+                    true,
+                ),
+                None if animating => (Some(state.outgoing(state.cached)), IDLE_INTERVAL, false),
+                None => (None, IDLE_INTERVAL, false),
+            })
+            .await;
+
+        if let Some(config) = config {
+            animating = running;
+            let timeout = device.timeouts().write;
+            let res = watchdog::guard(&device, "write_config", timeout, || {
+                device.write_config(&config, Mode::Temporary, timeout)
+            })
+            .await;
+            if let Err(err) = res {
+                let err = err.to_string();
+                state.update(|state| state.io.err = Some(err));
+            }
+        }
+
+        sleep(interval).await;
+    }
+}
+
+#[cfg(all(test, feature = "pipewire"))]
+mod tests {
+    use super::*;
+
+    fn running(kind: Kind, level: f64) -> Running {
+        let animation = Animation {
+            kind,
+            target: Target::ColorGen,
+            period_ms: default_period_ms(),
+            fps: default_fps(),
+            source: None,
+            floor_db: default_floor_db(),
+        };
+        Running {
+            animation,
+            start: Instant::now(),
+            capture: Some(Capture::fixed(level)),
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_the_audio_level() {
+        let config = DeviceConfiguration {
+            color_gen: Color([0x00, 0x80, 0xff]),
+            ..Default::default()
+        };
+
+        for (level, expected) in [(0.0, [0x00, 0x00, 0x00]), (0.5, [0x00, 0x40, 0x80])] {
+            let frame = running(Kind::Level, level).frame(config);
+            assert_eq!(frame.color_gen, Color(expected), "{level}");
+        }
+        for (level, expected) in [(0.0, [0x00, 0xff, 0x00]), (1.0, [0xff, 0x00, 0x00])] {
+            let frame = running(Kind::LevelHue, level).frame(config);
+            assert_eq!(frame.color_gen, Color(expected), "{level}");
+        }
+    }
+
+    #[test]
+    fn rejects_a_floor_at_or_above_full_scale() {
+        let animation: Animation =
+            serde_json::from_str(r#"{"kind": "level", "floor_db": 0}"#).unwrap();
+        assert!(animation.start().is_err());
+    }
+}
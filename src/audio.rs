@@ -0,0 +1,182 @@
+//! Audio-reactive lighting, driven by the level of a PipeWire source
+//!
+//! Built with the `pipewire` feature and started like any other animation:
+//!
+//! ```json
+//! {"animation": {"kind": "level", "source": "alsa_input.usb-Elgato_Systems_Elgato_Wave_XLR"}}
+//! ```
+//!
+//! `level` scales the brightness of the color with the signal, `level_hue` sweeps it from green to
+//! red at the color's brightness like a VU meter. Without a `source`, PipeWire's default one is
+//! captured.
+//!
+//! Capturing goes through `pw-record --raw` (PipeWire 1.0 or later) rather than libpipewire, so
+//! there is nothing to link against and PipeWire's main loop stays out of the tokio runtime.
+
+use anyhow::{Context, Result};
+use std::process::Stdio;
+use tokio::{io::AsyncReadExt, process::Command, sync::watch, task::JoinHandle};
+
+/// Sample rate to capture at, plenty for a level meter
+const RATE: u32 = 16_000;
+
+/// Samples per level update, 20ms worth
+const CHUNK: usize = 320;
+
+/// Share of the level kept per update once the signal drops, so the meter falls to a tenth in
+/// about half a second instead of flickering
+const RELEASE: f64 = 0.9;
+
+/// Level of a source as captured by `pw-record`, which is stopped when this is dropped
+#[derive(Debug)]
+pub struct Capture {
+    level: watch::Receiver<f64>,
+    task: JoinHandle<()>,
+}
+
+impl Capture {
+    /// Start capturing `source`, levels at or below `floor_db` dBFS count as silence
+    pub fn start(source: Option<&str>, floor_db: f64) -> Result<Self> {
+        let mut command = Command::new("pw-record");
+        command.args(["--raw", "--format", "s16", "--channels", "1"]);
+        command.args(["--rate", &RATE.to_string()]);
+        if let Some(source) = source {
+            command.args(["--target", source]);
+        }
+        let mut child = command
+            .arg("-")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("starting pw-record")?;
+        let mut stdout = child.stdout.take().context("pw-record has no stdout")?;
+
+        let (tx, level) = watch::channel(0.0);
+        let task = tokio::spawn(async move {
+            let mut meter = Meter::new(floor_db);
+            let mut buf = [0; CHUNK * 2];
+            while stdout.read_exact(&mut buf).await.is_ok() {
+                let samples = buf
+                    .chunks_exact(2)
+                    .map(|sample| i16::from_ne_bytes([sample[0], sample[1]]));
+                tx.send_replace(meter.update(samples));
+            }
+            tx.send_replace(0.0);
+            match child.wait().await {
+                Ok(status) => log::warn!("pw-record stopped capturing ({status})"),
+                Err(err) => log::warn!("pw-record stopped capturing: {err}"),
+            }
+        });
+
+        Ok(Self { level, task })
+    }
+
+    /// The current level, from 0 at or below the floor to 1 at full scale
+    pub fn level(&self) -> f64 {
+        *self.level.borrow()
+    }
+
+    /// A capture stuck at `level`, without running `pw-record`
+    #[cfg(test)]
+    pub(crate) fn fixed(level: f64) -> Self {
+        let (_, level) = watch::channel(level);
+        Self {
+            level,
+            task: tokio::spawn(async {}),
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        // Drops the child as well, which kills it
+        self.task.abort();
+    }
+}
+
+/// Turns chunks of samples into a level that rises at once and falls slowly
+#[derive(Debug)]
+struct Meter {
+    floor_db: f64,
+    level: f64,
+}
+
+impl Meter {
+    fn new(floor_db: f64) -> Self {
+        Self {
+            floor_db,
+            level: 0.0,
+        }
+    }
+
+    fn update(&mut self, samples: impl IntoIterator<Item = i16>) -> f64 {
+        let level = scale(rms_db(samples), self.floor_db);
+        self.level = level.max(self.level * RELEASE);
+        self.level
+    }
+}
+
+/// Root mean square of the samples in dBFS, negative infinity for silence
+fn rms_db(samples: impl IntoIterator<Item = i16>) -> f64 {
+    let (sum, count) = samples.into_iter().fold((0.0, 0), |(sum, count), sample| {
+        let sample = f64::from(sample) / 32768.0;
+        (sum + sample * sample, count + 1)
+    });
+    if count == 0 {
+        return f64::NEG_INFINITY;
+    }
+    10.0 * (sum / f64::from(count)).log10()
+}
+
+/// Map dBFS from `floor_db` up to 0 onto 0 to 1
+fn scale(db: f64, floor_db: f64) -> f64 {
+    (1.0 - db / floor_db).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.01, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn measures_the_level_in_dbfs() {
+        assert_eq!(rms_db([0; CHUNK]), f64::NEG_INFINITY);
+        assert_eq!(rms_db([]), f64::NEG_INFINITY);
+
+        let square = |amplitude: i16| (0..CHUNK).map(move |i| [amplitude, -amplitude][i % 2]);
+        assert_close(rms_db(square(i16::MAX)), 0.0);
+        assert_close(rms_db(square(16384)), -6.02);
+        assert_close(rms_db(square(328)), -40.0);
+    }
+
+    #[test]
+    fn scales_levels_above_the_floor() {
+        for (db, expected) in [
+            (0.0, 1.0),
+            (-30.0, 0.5),
+            (-60.0, 0.0),
+            (-90.0, 0.0),
+            (f64::NEG_INFINITY, 0.0),
+            (3.0, 1.0),
+        ] {
+            assert_close(scale(db, -60.0), expected);
+        }
+    }
+
+    #[test]
+    fn rises_at_once_and_falls_slowly() {
+        let mut meter = Meter::new(-60.0);
+        let loud = [i16::MAX, -i16::MAX].repeat(CHUNK / 2);
+
+        assert_close(meter.update(loud.iter().copied()), 1.0);
+        assert_close(meter.update([0; CHUNK]), RELEASE);
+        assert_close(meter.update([0; CHUNK]), RELEASE * RELEASE);
+        let level = (0..50).fold(0.0, |_, _| meter.update([0; CHUNK]));
+        assert!(level < 0.01, "{level}");
+        assert_close(meter.update(loud.iter().copied()), 1.0);
+    }
+}
@@ -0,0 +1,196 @@
+//! What the control loop needs from a device
+//!
+//! [`stdio`](crate::stdio::stdio) and everything it drives only talk to the device through
+//! [`DeviceBackend`], so tests can run the protocol against a double instead of a plugged in
+//! Wave XLR.
+
+use crate::{
+    capabilities::Capabilities,
+    usb_device::{
+        Device, DeviceConfiguration, DeviceInfo, Mode, ReservedByte, RetryPolicy, Timeouts,
+        UndecodedChange,
+    },
+};
+use anyhow::{Result, anyhow};
+use nusb::transfer::{ControlIn, ControlOut};
+use std::{collections::BTreeMap, future::Future, time::Duration};
+use tokio::sync::watch;
+
+pub trait DeviceBackend: Clone + Send + Sync + 'static {
+    fn read_config(
+        &self,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<DeviceConfiguration>> + Send;
+
+    fn write_config(
+        &self,
+        config: &DeviceConfiguration,
+        mode: Mode,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Write a configuration buffer as is, unsupported unless the backend has a buffer
+    fn write_buffer(
+        &self,
+        buf: &[u8],
+        mode: Mode,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let _ = (buf, mode, timeout);
+        async { Err(anyhow!("raw config writes aren't supported by this device")) }
+    }
+
+    fn info(&self) -> Result<DeviceInfo>;
+
+    /// How long reads and writes of the configuration may take
+    fn timeouts(&self) -> Timeouts {
+        Timeouts::default()
+    }
+
+    /// How transient failures of reads and writes are retried, [`RetryPolicy::NONE`] for
+    /// backends that don't retry
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::NONE
+    }
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// Claim the interface again, a no-op for backends without one
+    fn reopen(&self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Reset the device, a no-op for backends that can't get stuck
+    fn reset(&self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Notified whenever the device may have changed on its own, `None` for backends that can't
+    /// tell
+    ///
+    /// Only makes the next read come sooner, the configuration is still polled.
+    fn interrupts(&self) -> Option<watch::Receiver<()>> {
+        None
+    }
+
+    /// Changes of bytes that aren't decoded into any field since the last call
+    fn take_undecoded_changes(&self) -> Vec<UndecodedChange> {
+        Vec::new()
+    }
+
+    /// Reserved bytes seen with unexpected values since the last call
+    fn take_unexpected_reserved(&self) -> Vec<ReservedByte> {
+        Vec::new()
+    }
+
+    /// The fields whose meaning isn't known yet as last read, for backends that have them
+    fn unknown_fields(&self) -> Option<BTreeMap<&'static str, Vec<u8>>> {
+        None
+    }
+
+    /// Write `value` to an unknown field from now on, instead of its default
+    fn set_unknown(&self, name: &str, value: Vec<u8>) -> Result<()> {
+        let _ = value;
+        Err(anyhow!("no unknown field {name}"))
+    }
+
+    /// The configuration buffer as last read, for backends that have one
+    fn last_buffer(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Send a raw IN transfer, unsupported unless the backend speaks USB
+    fn control_in(
+        &self,
+        control: ControlIn,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        let _ = (control, timeout);
+        async { Err(anyhow!("raw transfers aren't supported by this device")) }
+    }
+
+    /// Send a raw OUT transfer, unsupported unless the backend speaks USB
+    fn control_out(
+        &self,
+        control: ControlOut<'_>,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let _ = (control, timeout);
+        async { Err(anyhow!("raw transfers aren't supported by this device")) }
+    }
+}
+
+impl DeviceBackend for Device {
+    async fn read_config(&self, timeout: Duration) -> Result<DeviceConfiguration> {
+        Device::read_config(self, timeout).await
+    }
+
+    async fn write_config(
+        &self,
+        config: &DeviceConfiguration,
+        mode: Mode,
+        timeout: Duration,
+    ) -> Result<()> {
+        Device::write_config(self, config, mode, timeout).await
+    }
+
+    async fn write_buffer(&self, buf: &[u8], mode: Mode, timeout: Duration) -> Result<()> {
+        Device::write_buffer(self, buf, mode, timeout).await
+    }
+
+    fn info(&self) -> Result<DeviceInfo> {
+        Device::info(self)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Device::capabilities(self)
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        Device::timeouts(self)
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        Device::retry_policy(self)
+    }
+
+    async fn reopen(&self) -> Result<()> {
+        Device::reopen(self).await
+    }
+
+    async fn reset(&self) -> Result<()> {
+        Device::reset(self).await
+    }
+
+    fn interrupts(&self) -> Option<watch::Receiver<()>> {
+        Device::interrupts(self)
+    }
+
+    fn take_undecoded_changes(&self) -> Vec<UndecodedChange> {
+        Device::take_undecoded_changes(self)
+    }
+
+    fn take_unexpected_reserved(&self) -> Vec<ReservedByte> {
+        Device::take_unexpected_reserved(self)
+    }
+
+    fn unknown_fields(&self) -> Option<BTreeMap<&'static str, Vec<u8>>> {
+        Device::unknown_fields(self)
+    }
+
+    fn set_unknown(&self, name: &str, value: Vec<u8>) -> Result<()> {
+        Device::set_unknown(self, name, value)
+    }
+
+    fn last_buffer(&self) -> Option<Vec<u8>> {
+        Device::last_buffer(self)
+    }
+
+    async fn control_in(&self, control: ControlIn, timeout: Duration) -> Result<Vec<u8>> {
+        Device::control_in(self, control, timeout).await
+    }
+
+    async fn control_out(&self, control: ControlOut<'_>, timeout: Duration) -> Result<()> {
+        Device::control_out(self, control, timeout).await
+    }
+}
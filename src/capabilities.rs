@@ -0,0 +1,104 @@
+//! What a model supports, so clients can render only the controls that exist
+//!
+//! Requested with `{"get": "capabilities"}` on stdin and answered with a `capabilities` event.
+
+use crate::{
+    ui_state::{Decibel, MAX_GAIN},
+    usb_device::LowcutFilter,
+};
+use serde::{Deserialize, Serialize};
+
+/// The settings a model supports and their valid values
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub model: &'static str,
+    /// Fields of [`DeviceConfiguration`](crate::DeviceConfiguration) the model has
+    pub fields: &'static [&'static str],
+    pub gain: Bounds<Decibel<u16>>,
+    pub volume: Bounds<Decibel<i16>>,
+    pub mix: Bounds<u8>,
+    pub lowcut: &'static [LowcutFilter],
+}
+
+/// Inclusive range of valid values
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Bounds<T> {
+    pub min: T,
+    pub max: T,
+}
+
+/// Things that can be asked for with `{"get": ...}`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Query {
+    Capabilities,
+    Info,
+    /// The last configuration buffer read, as hex
+    Raw,
+}
+
+impl Capabilities {
+    pub const WAVE_XLR: Self = Self {
+        model: "Elgato Wave XLR",
+        fields: &[
+            "gain",
+            "mute",
+            "clipguard",
+            "phantom",
+            "lowcut",
+            "volume",
+            "mix",
+            "color_mute",
+            "color_gen",
+            "gain_lock",
+            "color_gain_reduction",
+            "clipguard_indicator",
+            "lim",
+        ],
+        gain: Bounds {
+            min: Decibel(0),
+            max: Decibel(MAX_GAIN as u16),
+        },
+        volume: Bounds {
+            min: Decibel(i16::MIN),
+            max: Decibel(0),
+        },
+        mix: Bounds { min: 0, max: 100 },
+        lowcut: &LowcutFilter::ALL,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb_device::DeviceConfiguration;
+    use serde_json::{Value, json};
+
+    #[test]
+    fn lists_every_field_of_the_configuration() {
+        let Value::Object(config) = serde_json::to_value(DeviceConfiguration::default()).unwrap()
+        else {
+            panic!("configuration isn't an object");
+        };
+        let mut fields = Capabilities::WAVE_XLR.fields.to_vec();
+        fields.sort_unstable();
+        let mut expected: Vec<_> = config.keys().map(String::as_str).collect();
+        expected.sort_unstable();
+        assert_eq!(fields, expected);
+    }
+
+    #[test]
+    fn reports_levels_with_their_unit() {
+        let capabilities = serde_json::to_value(Capabilities::WAVE_XLR).unwrap();
+        assert_eq!(capabilities["gain"], json!({"min": "0dB", "max": "75dB"}));
+        assert_eq!(
+            capabilities["volume"],
+            json!({"min": "-128dB", "max": "0dB"})
+        );
+        assert_eq!(capabilities["mix"], json!({"min": 0, "max": 100}));
+        assert_eq!(
+            capabilities["lowcut"],
+            json!(["Off", "Cutoff080Hz", "Cutoff120Hz"])
+        );
+    }
+}
@@ -0,0 +1,492 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+#[cfg(feature = "mock")]
+use tidal_wave::mock::Fault;
+use tidal_wave::{
+    BusAddress,
+    logging::LogFormat,
+    metrics,
+    policy::ConflictPolicy,
+    probe::{self, Span},
+    raw::{self, Transfer},
+    stdio,
+    ui_state::Decibel,
+};
+
+/// Control an Elgato Wave XLR via USB
+///
+/// Without a subcommand, reads JSON lines with settings from stdin and reports changes of the
+/// device state as JSON lines on stdout, until stdin ends.
+///
+/// Exits with 0 on success, 69 if no device is connected, 77 without permission to open it, 76
+/// if the device or the input didn't follow the protocol, 74 if stdout was closed and 1 on any
+/// other error.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path of the config file [default: $XDG_CONFIG_HOME/tidal-wave/config.toml]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Format of the log messages written to stderr
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+
+    /// Change the gain even if gain lock is enabled on the device
+    #[arg(long, global = true)]
+    pub force: bool,
+
+    /// What to do when phantom power and low impedance mode would be enabled at the same time
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub phantom_lim: ConflictPolicy,
+
+    /// Turn on clipguard whenever the gain is set above this level, e.g. "50dB"
+    #[arg(long, value_name = "LEVEL", global = true)]
+    pub auto_clipguard_above: Option<Decibel<u16>>,
+
+    /// Turn clipguard off again once the gain is set back to or below the level of
+    /// --auto-clipguard-above, if it was turned on automatically
+    #[arg(long, global = true, requires = "auto_clipguard_above")]
+    pub auto_clipguard_release: bool,
+
+    /// Control the unit with this serial number instead of the first one found
+    #[arg(long, global = true)]
+    pub serial: Option<String>,
+
+    /// Control the unit at this position on the USB bus, as listed by `list-devices`
+    #[arg(long, value_name = "BUS:ADDRESS", global = true)]
+    pub bus_address: Option<BusAddress>,
+
+    /// Log every control transfer with a hexdump of its data
+    #[arg(long, global = true)]
+    pub trace_usb: bool,
+
+    /// Record every control transfer with its timing and outcome, for `replay-usb`
+    #[arg(long, value_name = "PATH", global = true)]
+    pub record_usb: Option<PathBuf>,
+
+    /// How long reading the settings may take before it fails
+    #[arg(long, value_name = "MS", default_value_t = 1000, global = true)]
+    pub read_timeout_ms: u64,
+
+    /// How long writing the settings may take before it fails
+    #[arg(long, value_name = "MS", default_value_t = 1000, global = true)]
+    pub write_timeout_ms: u64,
+
+    /// Try transfers that failed transiently, like by timing out, this many more times
+    #[arg(long, value_name = "N", default_value_t = 2, global = true)]
+    pub retries: u32,
+
+    /// Wait before retrying a failed transfer, doubled for every further retry
+    #[arg(long, value_name = "MS", default_value_t = 50, global = true)]
+    pub retry_backoff_ms: u64,
+
+    /// Talk to an emulated Wave XLR instead of a real one
+    ///
+    /// Its upcoming transfers fail in the order of the given faults: timeout, short-read,
+    /// invalid-bool, stall, ignore, disconnect, corrupt:OFFSET or random-disconnects:PERCENT.
+    #[cfg(feature = "mock")]
+    #[arg(
+        long,
+        value_name = "FAULTS",
+        num_args = 0..,
+        value_delimiter = ',',
+        require_equals = true,
+        global = true
+    )]
+    pub mock: Option<Vec<Fault>>,
+
+    /// Report colors as hex strings like "#ff8800" instead of byte arrays
+    #[arg(long, global = true)]
+    pub hex_colors: bool,
+
+    /// Without a subcommand, wait for the device to show up instead of failing right away, at
+    /// most SECS seconds if given as --wait=SECS
+    #[arg(long, value_name = "SECS", require_equals = true)]
+    pub wait: Option<Option<u64>>,
+
+    /// Without a subcommand or with `watch`, read the device every MS milliseconds instead of
+    /// every second, trading USB traffic for how soon changes on the device show up
+    ///
+    /// Without a subcommand, the device is still read every 100ms for a few seconds after a write
+    /// or a change on it.
+    #[arg(
+        long,
+        value_name = "MS",
+        value_parser = clap::value_parser!(u64).range(stdio::MIN_POLL_INTERVAL.as_millis() as u64..),
+    )]
+    pub poll_interval: Option<u64>,
+
+    /// Without a subcommand, apply lines to the settings read or written within the last MS
+    /// milliseconds instead of reading them again first, unless a line has "use_cached": false
+    ///
+    /// Halves the transfers for bursts of lines, but a change on the device within that time is
+    /// overwritten.
+    #[arg(long, value_name = "MS")]
+    pub max_cache_age_ms: Option<u64>,
+
+    /// Read the settings back after every write and fail, listing the ones the device didn't
+    /// take, if they differ
+    #[arg(long, global = true)]
+    pub verify: bool,
+
+    /// Without a subcommand, write the last known settings to the device again after the
+    /// system resumed from suspend
+    #[arg(long)]
+    pub restore_after_resume: bool,
+
+    /// Without a subcommand, add the configuration buffer as "raw" hex to state lines whenever it
+    /// changes
+    #[arg(long)]
+    pub report_raw: bool,
+
+    /// Without a subcommand or with replay, accept whole config buffers as {"raw": "<hex>"} and
+    /// write them as is, which can put the device into states this tool knows nothing about
+    #[arg(long, global = true)]
+    pub allow_raw_write: bool,
+
+    /// Without a subcommand or with replay, report and accept the fields whose meaning isn't
+    /// known yet under their provisional names
+    #[arg(long, global = true)]
+    pub experimental: bool,
+
+    /// Without a subcommand, merge settings lines arriving within MS milliseconds of the first one
+    /// into a single write with the final values, e.g. for a slider streaming updates
+    #[arg(long, value_name = "MS")]
+    pub coalesce_ms: Option<u64>,
+
+    /// Without a subcommand, write the settings read at startup back when exiting, so changes
+    /// made during the session don't outlive it
+    ///
+    /// Only the live settings are restored, stored ones stay as they were written.
+    #[arg(long)]
+    pub restore_on_exit: bool,
+
+    /// Without a subcommand, keep reporting changes of the device after stdin ends, until
+    /// SIGINT or SIGTERM
+    #[arg(long)]
+    pub monitor_after_eof: bool,
+
+    /// Without a subcommand, what to do with input lines that aren't valid
+    #[arg(long, value_enum, default_value_t)]
+    pub on_invalid_input: stdio::OnInvalidInput,
+
+    /// Record every applied line with its timing to a script, which can be played back with
+    /// `replay`
+    #[arg(long, value_name = "PATH")]
+    pub record_script: Option<PathBuf>,
+
+    /// Without a subcommand, control every connected unit instead of only the first one
+    ///
+    /// State lines are namespaced as {"device": SERIAL, "fields": {...}} and events carry a
+    /// "device" field. Input lines select their unit the same way, either namespaced or flat,
+    /// and apply to every unit without one.
+    #[arg(long, conflicts_with_all = ["wait", "serial", "bus_address"])]
+    pub all_devices: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RawTransfer {
+    /// Device-to-host transfer, reading LENGTH bytes
+    In {
+        #[arg(value_parser = parse_number::<u8>)]
+        request: u8,
+        #[arg(value_parser = parse_number::<u16>)]
+        value: u16,
+        #[arg(value_parser = parse_number::<u16>)]
+        index: u16,
+        #[arg(value_parser = parse_number::<u16>)]
+        length: u16,
+
+        #[arg(long, value_enum, default_value = "endpoint")]
+        recipient: probe::Recipient,
+    },
+    /// Host-to-device transfer sending the hex encoded DATA
+    Out {
+        #[arg(value_parser = parse_number::<u8>)]
+        request: u8,
+        #[arg(value_parser = parse_number::<u16>)]
+        value: u16,
+        #[arg(value_parser = parse_number::<u16>)]
+        index: u16,
+        #[arg(value_parser = raw::parse_hex)]
+        data: Vec<u8>,
+
+        #[arg(long, value_enum, default_value = "endpoint")]
+        recipient: probe::Recipient,
+    },
+}
+
+impl From<RawTransfer> for Transfer {
+    fn from(transfer: RawTransfer) -> Self {
+        match transfer {
+            RawTransfer::In {
+                request,
+                value,
+                index,
+                length,
+                recipient,
+            } => Transfer::In {
+                recipient,
+                request,
+                value,
+                index,
+                length,
+            },
+            RawTransfer::Out {
+                request,
+                value,
+                index,
+                data,
+                recipient,
+            } => Transfer::Out {
+                recipient,
+                request,
+                value,
+                index,
+                data,
+            },
+        }
+    }
+}
+
+/// Parse a decimal or `0x` prefixed hex number
+fn parse_number<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let n = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|err| format!("invalid number {s:?}: {err}"))?;
+    T::try_from(n).map_err(|_| format!("{s} is out of range"))
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Print the current settings as a JSON object, or the values of the given fields one per
+    /// line
+    Get {
+        fields: Vec<String>,
+
+        /// Print `field: value` lines instead, with color swatches on a terminal
+        #[arg(long)]
+        human: bool,
+    },
+
+    /// Change settings, given as pairs of field and value, e.g. `set gain 40 mute true`
+    ///
+    /// Bare numbers for gain and volume are in dB. Other values starting with `-` go after `--`,
+    /// like `set -- volume -10dB`, so they aren't taken for flags.
+    Set {
+        #[arg(required = true, allow_negative_numbers = true, value_names = ["FIELD", "VALUE"])]
+        settings: Vec<String>,
+
+        /// Make the changes persist across power cycles
+        #[arg(long)]
+        persistent: bool,
+    },
+
+    /// Flip boolean settings, e.g. `toggle mute`
+    Toggle {
+        #[arg(required = true)]
+        fields: Vec<String>,
+
+        /// Make the changes persist across power cycles
+        #[arg(long)]
+        persistent: bool,
+    },
+
+    /// Apply a single line of the JSON protocol and exit, e.g. `apply '{"mute":true}'`
+    Apply {
+        /// The line to apply [default: read one line from stdin]
+        line: Option<String>,
+    },
+
+    /// Switch all colors to a theme, or list the available themes
+    Theme {
+        name: Option<String>,
+
+        /// Make the colors persist across power cycles
+        #[arg(long)]
+        persistent: bool,
+    },
+
+    /// Print the current settings, then every change as JSON lines until interrupted
+    Watch,
+
+    /// Check the config encoder/decoder against known-good buffers
+    Selftest {
+        /// Additionally read the config from the device, write it back in temporary mode and
+        /// check that reading it again yields the same config
+        #[arg(long)]
+        live: bool,
+    },
+
+    /// Show which settings on the device differ from a profile
+    ///
+    /// Exits with 1 if there are differences, unless they were applied with --apply.
+    Diff {
+        /// TOML file with the settings to compare against
+        profile: PathBuf,
+
+        /// Write the profile to the device afterwards
+        #[arg(long)]
+        apply: bool,
+
+        /// Make the settings written by --apply persist across power cycles
+        #[arg(long, requires = "apply")]
+        persistent: bool,
+    },
+
+    /// Copy all settings from the unit with serial SOURCE to the units with serials TARGETS,
+    /// printing how each target differs first
+    Sync {
+        source: String,
+
+        #[arg(required = true)]
+        targets: Vec<String>,
+
+        /// Make the copied settings persist across power cycles
+        #[arg(long)]
+        persistent: bool,
+
+        /// Only print the differences
+        #[arg(long, conflicts_with = "persistent")]
+        dry_run: bool,
+    },
+
+    /// Print the current state as a single line and exit
+    ///
+    /// Suitable for Telegraf's exec input.
+    Metrics {
+        #[arg(long, value_enum, default_value = "influx")]
+        format: metrics::Format,
+    },
+
+    /// Check that the device answers and exit with 0/1/2 for ok/warning/critical
+    ///
+    /// Follows the Nagios plugin convention, so it can be used by most monitoring systems.
+    Health,
+
+    /// Flash the LEDs of the device for a few seconds, to tell several units apart
+    Identify,
+
+    /// Print the model, serial number and firmware version of the device as JSON, for bug
+    /// reports
+    Info,
+
+    /// List every connected Elgato device as JSON lines, with whether its interface can be
+    /// claimed
+    ListDevices,
+
+    /// Apply the lines of a script recorded with --record-script, keeping their timing
+    ///
+    /// Queries and raw transfers are sent again and their answers printed as JSON lines.
+    Replay { script: PathBuf },
+
+    /// Send a range of device-to-host control requests and print the responses as JSON lines
+    ///
+    /// Meant for reverse engineering: save the output, change a setting and run again with --diff
+    /// to see which requests report it.
+    #[command(visible_alias = "scan")]
+    Probe {
+        /// Type of the requests
+        #[arg(long, value_enum, default_value = "class")]
+        kind: probe::Kind,
+
+        #[arg(long, value_enum, default_value = "endpoint")]
+        recipient: probe::Recipient,
+
+        /// Request numbers to send, e.g. "0x80-0x8f"
+        #[arg(long, default_value = "0x80-0x8f")]
+        requests: Span,
+
+        /// wValue of the requests
+        #[arg(long, default_value = "0")]
+        values: Span,
+
+        /// wIndex of the requests
+        #[arg(long, default_value = "0x3300")]
+        indexes: Span,
+
+        /// Number of bytes to request
+        #[arg(long, default_value_t = 64)]
+        length: u16,
+
+        /// Milliseconds to wait between requests
+        #[arg(long, default_value_t = 20)]
+        interval_ms: u64,
+
+        /// Output of an earlier probe; only print the responses that differ from it
+        #[arg(long, value_name = "PATH")]
+        diff: Option<PathBuf>,
+
+        /// Allow --kind vendor, whose requests may change the device state even though they only
+        /// read
+        #[arg(long)]
+        allow_vendor: bool,
+    },
+
+    /// Send the control transfers recorded with --record-usb again, printing the ones whose
+    /// outcome differs
+    ReplayUsb { recording: PathBuf },
+
+    /// Send a single class control transfer and print the response as hex
+    ///
+    /// Unlike probe, this can send OUT transfers, which may change the device state in unknown
+    /// ways.
+    Raw {
+        #[command(subcommand)]
+        transfer: RawTransfer,
+    },
+
+    /// User-defined alias from the config
+    #[command(external_subcommand)]
+    Alias(Vec<String>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn is_a_valid_command() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn accepts_policy_flags_after_the_subcommand() {
+        let cli = Cli::try_parse_from(["tidal-wave", "set", "gain", "40dB", "--force"]).unwrap();
+        assert!(cli.force);
+        let Some(Command::Set { settings, .. }) = cli.command else {
+            panic!("not set: {:?}", cli.command);
+        };
+        assert_eq!(settings, ["gain", "40dB"]);
+
+        let cli = Cli::try_parse_from(["tidal-wave", "set", "volume", "-10", "--force"]).unwrap();
+        let Some(Command::Set { settings, .. }) = cli.command else {
+            panic!("not set: {:?}", cli.command);
+        };
+        assert_eq!(settings, ["volume", "-10"]);
+
+        let argv = ["tidal-wave", "toggle", "phantom", "--phantom-lim", "refuse"];
+        let cli = Cli::try_parse_from(argv).unwrap();
+        assert_eq!(cli.phantom_lim, ConflictPolicy::Refuse);
+
+        let argv = [
+            "tidal-wave",
+            "set",
+            "gain",
+            "60",
+            "--auto-clipguard-above",
+            "50dB",
+            "--auto-clipguard-release",
+        ];
+        let cli = Cli::try_parse_from(argv).unwrap();
+        assert!(cli.auto_clipguard_above.is_some());
+        assert!(cli.auto_clipguard_release);
+    }
+}
@@ -0,0 +1,733 @@
+//! Named colors, as defined by CSS Color Module Level 4
+//!
+//! These mostly agree with the X11 color names, except for a few like `gray` and `green`, where
+//! CSS chose the web colors.
+//!
+//! Also home to the conversions between [`Color`] and HSV/HSL, and to [`hex_colors`], which
+//! serializes colors as hex strings.
+
+use crate::usb_device::Color;
+use serde::{
+    Deserialize, Serialize, Serializer,
+    ser::{self, Error as _},
+};
+
+/// Sorted by name, for binary search
+const NAMED: &[(&str, [u8; 3])] = &[
+    ("aliceblue", [0xf0, 0xf8, 0xff]),
+    ("antiquewhite", [0xfa, 0xeb, 0xd7]),
+    ("aqua", [0x00, 0xff, 0xff]),
+    ("aquamarine", [0x7f, 0xff, 0xd4]),
+    ("azure", [0xf0, 0xff, 0xff]),
+    ("beige", [0xf5, 0xf5, 0xdc]),
+    ("bisque", [0xff, 0xe4, 0xc4]),
+    ("black", [0x00, 0x00, 0x00]),
+    ("blanchedalmond", [0xff, 0xeb, 0xcd]),
+    ("blue", [0x00, 0x00, 0xff]),
+    ("blueviolet", [0x8a, 0x2b, 0xe2]),
+    ("brown", [0xa5, 0x2a, 0x2a]),
+    ("burlywood", [0xde, 0xb8, 0x87]),
+    ("cadetblue", [0x5f, 0x9e, 0xa0]),
+    ("chartreuse", [0x7f, 0xff, 0x00]),
+    ("chocolate", [0xd2, 0x69, 0x1e]),
+    ("coral", [0xff, 0x7f, 0x50]),
+    ("cornflowerblue", [0x64, 0x95, 0xed]),
+    ("cornsilk", [0xff, 0xf8, 0xdc]),
+    ("crimson", [0xdc, 0x14, 0x3c]),
+    ("cyan", [0x00, 0xff, 0xff]),
+    ("darkblue", [0x00, 0x00, 0x8b]),
+    ("darkcyan", [0x00, 0x8b, 0x8b]),
+    ("darkgoldenrod", [0xb8, 0x86, 0x0b]),
+    ("darkgray", [0xa9, 0xa9, 0xa9]),
+    ("darkgreen", [0x00, 0x64, 0x00]),
+    ("darkgrey", [0xa9, 0xa9, 0xa9]),
+    ("darkkhaki", [0xbd, 0xb7, 0x6b]),
+    ("darkmagenta", [0x8b, 0x00, 0x8b]),
+    ("darkolivegreen", [0x55, 0x6b, 0x2f]),
+    ("darkorange", [0xff, 0x8c, 0x00]),
+    ("darkorchid", [0x99, 0x32, 0xcc]),
+    ("darkred", [0x8b, 0x00, 0x00]),
+    ("darksalmon", [0xe9, 0x96, 0x7a]),
+    ("darkseagreen", [0x8f, 0xbc, 0x8f]),
+    ("darkslateblue", [0x48, 0x3d, 0x8b]),
+    ("darkslategray", [0x2f, 0x4f, 0x4f]),
+    ("darkslategrey", [0x2f, 0x4f, 0x4f]),
+    ("darkturquoise", [0x00, 0xce, 0xd1]),
+    ("darkviolet", [0x94, 0x00, 0xd3]),
+    ("deeppink", [0xff, 0x14, 0x93]),
+    ("deepskyblue", [0x00, 0xbf, 0xff]),
+    ("dimgray", [0x69, 0x69, 0x69]),
+    ("dimgrey", [0x69, 0x69, 0x69]),
+    ("dodgerblue", [0x1e, 0x90, 0xff]),
+    ("firebrick", [0xb2, 0x22, 0x22]),
+    ("floralwhite", [0xff, 0xfa, 0xf0]),
+    ("forestgreen", [0x22, 0x8b, 0x22]),
+    ("fuchsia", [0xff, 0x00, 0xff]),
+    ("gainsboro", [0xdc, 0xdc, 0xdc]),
+    ("ghostwhite", [0xf8, 0xf8, 0xff]),
+    ("gold", [0xff, 0xd7, 0x00]),
+    ("goldenrod", [0xda, 0xa5, 0x20]),
+    ("gray", [0x80, 0x80, 0x80]),
+    ("green", [0x00, 0x80, 0x00]),
+    ("greenyellow", [0xad, 0xff, 0x2f]),
+    ("grey", [0x80, 0x80, 0x80]),
+    ("honeydew", [0xf0, 0xff, 0xf0]),
+    ("hotpink", [0xff, 0x69, 0xb4]),
+    ("indianred", [0xcd, 0x5c, 0x5c]),
+    ("indigo", [0x4b, 0x00, 0x82]),
+    ("ivory", [0xff, 0xff, 0xf0]),
+    ("khaki", [0xf0, 0xe6, 0x8c]),
+    ("lavender", [0xe6, 0xe6, 0xfa]),
+    ("lavenderblush", [0xff, 0xf0, 0xf5]),
+    ("lawngreen", [0x7c, 0xfc, 0x00]),
+    ("lemonchiffon", [0xff, 0xfa, 0xcd]),
+    ("lightblue", [0xad, 0xd8, 0xe6]),
+    ("lightcoral", [0xf0, 0x80, 0x80]),
+    ("lightcyan", [0xe0, 0xff, 0xff]),
+    ("lightgoldenrodyellow", [0xfa, 0xfa, 0xd2]),
+    ("lightgray", [0xd3, 0xd3, 0xd3]),
+    ("lightgreen", [0x90, 0xee, 0x90]),
+    ("lightgrey", [0xd3, 0xd3, 0xd3]),
+    ("lightpink", [0xff, 0xb6, 0xc1]),
+    ("lightsalmon", [0xff, 0xa0, 0x7a]),
+    ("lightseagreen", [0x20, 0xb2, 0xaa]),
+    ("lightskyblue", [0x87, 0xce, 0xfa]),
+    ("lightslategray", [0x77, 0x88, 0x99]),
+    ("lightslategrey", [0x77, 0x88, 0x99]),
+    ("lightsteelblue", [0xb0, 0xc4, 0xde]),
+    ("lightyellow", [0xff, 0xff, 0xe0]),
+    ("lime", [0x00, 0xff, 0x00]),
+    ("limegreen", [0x32, 0xcd, 0x32]),
+    ("linen", [0xfa, 0xf0, 0xe6]),
+    ("magenta", [0xff, 0x00, 0xff]),
+    ("maroon", [0x80, 0x00, 0x00]),
+    ("mediumaquamarine", [0x66, 0xcd, 0xaa]),
+    ("mediumblue", [0x00, 0x00, 0xcd]),
+    ("mediumorchid", [0xba, 0x55, 0xd3]),
+    ("mediumpurple", [0x93, 0x70, 0xdb]),
+    ("mediumseagreen", [0x3c, 0xb3, 0x71]),
+    ("mediumslateblue", [0x7b, 0x68, 0xee]),
+    ("mediumspringgreen", [0x00, 0xfa, 0x9a]),
+    ("mediumturquoise", [0x48, 0xd1, 0xcc]),
+    ("mediumvioletred", [0xc7, 0x15, 0x85]),
+    ("midnightblue", [0x19, 0x19, 0x70]),
+    ("mintcream", [0xf5, 0xff, 0xfa]),
+    ("mistyrose", [0xff, 0xe4, 0xe1]),
+    ("moccasin", [0xff, 0xe4, 0xb5]),
+    ("navajowhite", [0xff, 0xde, 0xad]),
+    ("navy", [0x00, 0x00, 0x80]),
+    ("oldlace", [0xfd, 0xf5, 0xe6]),
+    ("olive", [0x80, 0x80, 0x00]),
+    ("olivedrab", [0x6b, 0x8e, 0x23]),
+    ("orange", [0xff, 0xa5, 0x00]),
+    ("orangered", [0xff, 0x45, 0x00]),
+    ("orchid", [0xda, 0x70, 0xd6]),
+    ("palegoldenrod", [0xee, 0xe8, 0xaa]),
+    ("palegreen", [0x98, 0xfb, 0x98]),
+    ("paleturquoise", [0xaf, 0xee, 0xee]),
+    ("palevioletred", [0xdb, 0x70, 0x93]),
+    ("papayawhip", [0xff, 0xef, 0xd5]),
+    ("peachpuff", [0xff, 0xda, 0xb9]),
+    ("peru", [0xcd, 0x85, 0x3f]),
+    ("pink", [0xff, 0xc0, 0xcb]),
+    ("plum", [0xdd, 0xa0, 0xdd]),
+    ("powderblue", [0xb0, 0xe0, 0xe6]),
+    ("purple", [0x80, 0x00, 0x80]),
+    ("rebeccapurple", [0x66, 0x33, 0x99]),
+    ("red", [0xff, 0x00, 0x00]),
+    ("rosybrown", [0xbc, 0x8f, 0x8f]),
+    ("royalblue", [0x41, 0x69, 0xe1]),
+    ("saddlebrown", [0x8b, 0x45, 0x13]),
+    ("salmon", [0xfa, 0x80, 0x72]),
+    ("sandybrown", [0xf4, 0xa4, 0x60]),
+    ("seagreen", [0x2e, 0x8b, 0x57]),
+    ("seashell", [0xff, 0xf5, 0xee]),
+    ("sienna", [0xa0, 0x52, 0x2d]),
+    ("silver", [0xc0, 0xc0, 0xc0]),
+    ("skyblue", [0x87, 0xce, 0xeb]),
+    ("slateblue", [0x6a, 0x5a, 0xcd]),
+    ("slategray", [0x70, 0x80, 0x90]),
+    ("slategrey", [0x70, 0x80, 0x90]),
+    ("snow", [0xff, 0xfa, 0xfa]),
+    ("springgreen", [0x00, 0xff, 0x7f]),
+    ("steelblue", [0x46, 0x82, 0xb4]),
+    ("tan", [0xd2, 0xb4, 0x8c]),
+    ("teal", [0x00, 0x80, 0x80]),
+    ("thistle", [0xd8, 0xbf, 0xd8]),
+    ("tomato", [0xff, 0x63, 0x47]),
+    ("turquoise", [0x40, 0xe0, 0xd0]),
+    ("violet", [0xee, 0x82, 0xee]),
+    ("wheat", [0xf5, 0xde, 0xb3]),
+    ("white", [0xff, 0xff, 0xff]),
+    ("whitesmoke", [0xf5, 0xf5, 0xf5]),
+    ("yellow", [0xff, 0xff, 0x00]),
+    ("yellowgreen", [0x9a, 0xcd, 0x32]),
+];
+
+/// Look up a color by its CSS name, ignoring case
+pub fn named(name: &str) -> Option<Color> {
+    let name = name.to_ascii_lowercase();
+    NAMED
+        .binary_search_by_key(&name.as_str(), |&(name, _)| name)
+        .ok()
+        .map(|i| Color(NAMED[i].1))
+}
+
+/// A color given as hue, saturation and either value (HSV) or lightness (HSL)
+///
+/// `h` is in degrees and wraps around, the others range from 0 to 1.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Hsx {
+    h: f64,
+    s: f64,
+    v: Option<f64>,
+    l: Option<f64>,
+}
+
+impl TryFrom<Hsx> for Color {
+    type Error = String;
+
+    fn try_from(Hsx { h, s, v, l }: Hsx) -> Result<Self, Self::Error> {
+        for (name, value) in [("s", Some(s)), ("v", v), ("l", l)] {
+            if let Some(value) = value
+                && !(0.0..=1.0).contains(&value)
+            {
+                return Err(format!("{name} must be between 0 and 1, got {value}"));
+            }
+        }
+
+        match (v, l) {
+            (Some(v), None) => Ok(Color::from_hsv(h, s, v)),
+            (None, Some(l)) => Ok(Color::from_hsl(h, s, l)),
+            _ => Err("expected exactly one of v and l".to_owned()),
+        }
+    }
+}
+
+/// Brightness and gamma applied to every color written to the device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dimming {
+    /// Factor from 0 to 1
+    pub brightness: f64,
+    pub gamma: f64,
+}
+
+impl Default for Dimming {
+    fn default() -> Self {
+        Self {
+            brightness: 1.0,
+            gamma: 1.0,
+        }
+    }
+}
+
+impl Dimming {
+    pub fn apply(self, color: Color) -> Color {
+        if self == Self::default() {
+            return color;
+        }
+        Color(color.0.map(|c| {
+            let c = self.brightness * (f64::from(c) / 255.0).powf(self.gamma);
+            (c * 255.0).round().clamp(0.0, 255.0) as u8
+        }))
+    }
+}
+
+impl Color {
+    /// Convert from hue in degrees, saturation and value, the latter two from 0 to 1
+    pub fn from_hsv(h: f64, s: f64, v: f64) -> Self {
+        let chroma = v * s;
+        Self::from_hue_chroma(h, chroma, v - chroma)
+    }
+
+    /// Convert from hue in degrees, saturation and lightness, the latter two from 0 to 1
+    pub fn from_hsl(h: f64, s: f64, l: f64) -> Self {
+        let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+        Self::from_hue_chroma(h, chroma, l - chroma / 2.0)
+    }
+
+    fn from_hue_chroma(h: f64, chroma: f64, min: f64) -> Self {
+        let h = h.rem_euclid(360.0) / 60.0;
+        let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+        let (r, g, b) = match h as u8 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let channel = |c: f64| ((c + min) * 255.0).round().clamp(0.0, 255.0) as u8;
+        Color([channel(r), channel(g), channel(b)])
+    }
+
+    /// Hue in degrees, saturation and value, the latter two from 0 to 1
+    pub fn to_hsv(self) -> (f64, f64, f64) {
+        let [r, g, b] = self.0.map(|c| f64::from(c) / 255.0);
+        let max = r.max(g).max(b);
+        let chroma = max - r.min(g).min(b);
+
+        let h = if chroma == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / chroma).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / chroma + 2.0)
+        } else {
+            60.0 * ((r - g) / chroma + 4.0)
+        };
+        let s = if max == 0.0 { 0.0 } else { chroma / max };
+        (h, s, max)
+    }
+
+    /// Two spaces with this as background color, for truecolor terminals
+    pub fn swatch(self) -> String {
+        let Color([r, g, b]) = self;
+        format!("\x1b[48;2;{r};{g};{b}m  \x1b[0m")
+    }
+
+    /// The same color with every channel multiplied by `factor`
+    pub fn scaled(self, factor: f64) -> Self {
+        Color(
+            self.0
+                .map(|c| (f64::from(c) * factor).round().clamp(0.0, 255.0) as u8),
+        )
+    }
+
+    /// The same color with its hue rotated by `degrees`
+    pub fn rotate_hue(self, degrees: f64) -> Self {
+        let (h, s, v) = self.to_hsv();
+        Self::from_hsv(h + degrees, s, v)
+    }
+}
+
+/// Name under which [`Color`] serializes as a newtype, so [`HexColors`] can find it
+pub(crate) const NEWTYPE: &str = "Color";
+
+/// Serializes `value` with every [`Color`] in it as a hex string like `"#ff8800"` if `hex`
+///
+/// Colors are byte arrays otherwise, which is what existing consumers of the output expect.
+pub fn hex_colors<T: Serialize + ?Sized>(value: &T, hex: bool) -> HexColors<'_, T> {
+    HexColors { value, hex }
+}
+
+/// See [`hex_colors`]
+pub struct HexColors<'a, T: ?Sized> {
+    value: &'a T,
+    hex: bool,
+}
+
+impl<T: Serialize + ?Sized> Serialize for HexColors<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.hex {
+            true => self.value.serialize(HexSerializer(serializer)),
+            false => self.value.serialize(serializer),
+        }
+    }
+}
+
+/// Forwards everything to the wrapped serializer, except colors
+struct HexSerializer<S>(S);
+
+/// Forwards the parts of a compound value, so colors nested in them are caught too
+struct Compound<C>(C);
+
+/// Wrap a nested value, so it is serialized by a [`HexSerializer`] as well
+fn nested<T: Serialize + ?Sized>(value: &T) -> HexColors<'_, T> {
+    hex_colors(value, true)
+}
+
+macro_rules! forward {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method(self, $($arg: $ty),*) -> Result<S::Ok, S::Error> {
+                self.0.$method($($arg),*)
+            }
+        )*
+    };
+}
+
+impl<S: Serializer> Serializer for HexSerializer<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = Compound<S::SerializeSeq>;
+    type SerializeTuple = Compound<S::SerializeTuple>;
+    type SerializeTupleStruct = Compound<S::SerializeTupleStruct>;
+    type SerializeTupleVariant = Compound<S::SerializeTupleVariant>;
+    type SerializeMap = Compound<S::SerializeMap>;
+    type SerializeStruct = Compound<S::SerializeStruct>;
+    type SerializeStructVariant = Compound<S::SerializeStructVariant>;
+
+    forward! {
+        serialize_bool(v: bool);
+        serialize_i8(v: i8);
+        serialize_i16(v: i16);
+        serialize_i32(v: i32);
+        serialize_i64(v: i64);
+        serialize_i128(v: i128);
+        serialize_u8(v: u8);
+        serialize_u16(v: u16);
+        serialize_u32(v: u32);
+        serialize_u64(v: u64);
+        serialize_u128(v: u128);
+        serialize_f32(v: f32);
+        serialize_f64(v: f64);
+        serialize_char(v: char);
+        serialize_str(v: &str);
+        serialize_bytes(v: &[u8]);
+        serialize_none();
+        serialize_unit();
+        serialize_unit_struct(name: &'static str);
+        serialize_unit_variant(name: &'static str, index: u32, variant: &'static str);
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<S::Ok, S::Error> {
+        self.0.serialize_some(&nested(value))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        if name != NEWTYPE {
+            return self.0.serialize_newtype_struct(name, &nested(value));
+        }
+        let bytes = serde_json::to_value(value).and_then(serde_json::from_value);
+        let bytes = bytes.map_err(|err| S::Error::custom(format!("serializing color: {err}")))?;
+        self.0.collect_str(&Color(bytes))
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        self.0
+            .serialize_newtype_variant(name, index, variant, &nested(value))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, S::Error> {
+        self.0.serialize_seq(len).map(Compound)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, S::Error> {
+        self.0.serialize_tuple(len).map(Compound)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, S::Error> {
+        self.0.serialize_tuple_struct(name, len).map(Compound)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, S::Error> {
+        self.0
+            .serialize_tuple_variant(name, index, variant, len)
+            .map(Compound)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, S::Error> {
+        self.0.serialize_map(len).map(Compound)
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, S::Error> {
+        self.0.serialize_struct(name, len).map(Compound)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, S::Error> {
+        self.0
+            .serialize_struct_variant(name, index, variant, len)
+            .map(Compound)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.0.is_human_readable()
+    }
+}
+
+impl<C: ser::SerializeSeq> ser::SerializeSeq for Compound<C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        self.0.serialize_element(&nested(value))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.0.end()
+    }
+}
+
+impl<C: ser::SerializeTuple> ser::SerializeTuple for Compound<C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        self.0.serialize_element(&nested(value))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.0.end()
+    }
+}
+
+impl<C: ser::SerializeTupleStruct> ser::SerializeTupleStruct for Compound<C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        self.0.serialize_field(&nested(value))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.0.end()
+    }
+}
+
+impl<C: ser::SerializeTupleVariant> ser::SerializeTupleVariant for Compound<C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        self.0.serialize_field(&nested(value))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.0.end()
+    }
+}
+
+impl<C: ser::SerializeMap> ser::SerializeMap for Compound<C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), C::Error> {
+        self.0.serialize_key(&nested(key))
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        self.0.serialize_value(&nested(value))
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.0.end()
+    }
+}
+
+impl<C: ser::SerializeStruct> ser::SerializeStruct for Compound<C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), C::Error> {
+        self.0.serialize_field(key, &nested(value))
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), C::Error> {
+        self.0.skip_field(key)
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.0.end()
+    }
+}
+
+impl<C: ser::SerializeStructVariant> ser::SerializeStructVariant for Compound<C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), C::Error> {
+        self.0.serialize_field(key, &nested(value))
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), C::Error> {
+        self.0.skip_field(key)
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.0.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+    use std::collections::BTreeMap;
+
+    const ORANGE: Color = Color([0xff, 0x88, 0x00]);
+
+    #[derive(Serialize)]
+    struct Nested {
+        color: Color,
+        some: Option<Color>,
+        none: Option<Color>,
+        list: Vec<Color>,
+        map: BTreeMap<&'static str, Color>,
+        variant: Variant,
+        bytes: [u8; 3],
+        other: Other,
+    }
+
+    #[derive(Serialize)]
+    enum Variant {
+        Solid(Color),
+    }
+
+    #[derive(Serialize)]
+    struct Other(u8);
+
+    fn nested() -> Nested {
+        Nested {
+            color: ORANGE,
+            some: Some(ORANGE),
+            none: None,
+            list: vec![ORANGE, Color([0, 0, 0])],
+            map: BTreeMap::from([("mute", ORANGE)]),
+            variant: Variant::Solid(ORANGE),
+            bytes: [0xff, 0x88, 0x00],
+            other: Other(7),
+        }
+    }
+
+    fn to_value(hex: bool) -> Value {
+        serde_json::to_value(hex_colors(&nested(), hex)).unwrap()
+    }
+
+    #[test]
+    fn serializes_colors_as_byte_arrays_by_default() {
+        assert_eq!(
+            to_value(false),
+            json!({
+                "color": [255, 136, 0],
+                "some": [255, 136, 0],
+                "none": null,
+                "list": [[255, 136, 0], [0, 0, 0]],
+                "map": {"mute": [255, 136, 0]},
+                "variant": {"Solid": [255, 136, 0]},
+                "bytes": [255, 136, 0],
+                "other": 7,
+            })
+        );
+        assert_eq!(serde_json::to_value(nested()).unwrap(), to_value(false));
+    }
+
+    #[test]
+    fn serializes_only_colors_as_hex() {
+        assert_eq!(
+            to_value(true),
+            json!({
+                "color": "#ff8800",
+                "some": "#ff8800",
+                "none": null,
+                "list": ["#ff8800", "#000000"],
+                "map": {"mute": "#ff8800"},
+                "variant": {"Solid": "#ff8800"},
+                "bytes": [255, 136, 0],
+                "other": 7,
+            })
+        );
+        assert_eq!(
+            serde_json::to_string(&hex_colors(&ORANGE, true)).unwrap(),
+            r##""#ff8800""##
+        );
+    }
+
+    #[test]
+    fn reads_back_both_serializations() {
+        for hex in [false, true] {
+            let value = serde_json::to_value(hex_colors(&ORANGE, hex)).unwrap();
+            assert_eq!(serde_json::from_value::<Color>(value).unwrap(), ORANGE);
+        }
+    }
+
+    #[test]
+    fn deserializes_every_form() {
+        for (value, expected) in [
+            (json!([255, 136, 0]), ORANGE),
+            (json!("#ff8800"), ORANGE),
+            (json!("FF8800"), ORANGE),
+            (json!("darkorange"), Color([0xff, 0x8c, 0x00])),
+            (json!("RebeccaPurple"), Color([0x66, 0x33, 0x99])),
+            (json!({"h": 120, "s": 1.0, "v": 0.8}), Color([0, 204, 0])),
+            (json!({"h": -120, "s": 1.0, "l": 0.5}), Color([0, 0, 255])),
+        ] {
+            assert_eq!(
+                serde_json::from_value::<Color>(value.clone()).unwrap(),
+                expected,
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_colors() {
+        for value in [
+            json!("#ff880"),
+            json!("#ff88001"),
+            json!("ff88zz"),
+            json!("notacolor"),
+            json!([256, 0, 0]),
+            json!([255, 136]),
+            json!(16746496),
+            json!({"h": 0, "s": 2.0, "v": 1.0}),
+            json!({"h": 0, "s": 1.0}),
+            json!({"h": 0, "s": 1.0, "v": 1.0, "l": 0.5}),
+            json!({"h": 0, "s": 1.0, "v": 1.0, "a": 0.5}),
+        ] {
+            assert!(
+                serde_json::from_value::<Color>(value.clone()).is_err(),
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn converts_between_rgb_and_hsv() {
+        assert_eq!(Color::from_hsl(240.0, 1.0, 0.75), Color([128, 128, 255]));
+        assert_eq!(ORANGE.to_hsv(), (32.0, 1.0, 1.0));
+        for color in [
+            ORANGE,
+            Color([0, 0, 0]),
+            Color([0x66, 0x33, 0x99]),
+            Color([9, 9, 9]),
+        ] {
+            let (h, s, v) = color.to_hsv();
+            assert_eq!(Color::from_hsv(h, s, v), color);
+        }
+
+        let red = Color([255, 0, 0]);
+        assert_eq!(red.rotate_hue(120.0), Color([0, 255, 0]));
+        assert_eq!(red.rotate_hue(-120.0), Color([0, 0, 255]));
+        assert_eq!(red.rotate_hue(360.0), red);
+    }
+}
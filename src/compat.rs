@@ -0,0 +1,139 @@
+//! Compatibility shims for older versions of the stdin protocol
+//!
+//! Input lines are passed through [`migrate`] before being deserialized into a
+//! [`Line`](crate::ui_state::Line), so the `Line` itself only has to know the current names.
+
+use serde_json::Value;
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Display},
+    sync::Mutex,
+};
+
+/// Protocol fields that got renamed, as `(old, new)`
+const RENAMED_FIELDS: &[(&str, &str)] = &[("persistant", "persistent")];
+
+/// Fields that used to take raw device units and now take a level with a unit, as `(old, new)`
+///
+/// A bare number for one of them is rejected, as reading it either way would silently change
+/// what some client sets.
+const RAW_NUMBER_FIELDS: &[(&str, &str)] = &[("gain", "gain_raw"), ("volume", "volume_raw")];
+
+/// Rewrite deprecated fields of an input line to their current names
+///
+/// Logs a deprecation warning the first time each deprecated field is seen. If both the old and
+/// the new name of a renamed field are present, the new one wins. Fails on a bare number for a
+/// field that now takes a level.
+pub fn migrate(line: &mut Value) -> Result<(), BareNumber> {
+    let Value::Object(fields) = line else {
+        return Ok(());
+    };
+
+    for &(old, new) in RENAMED_FIELDS {
+        let Some(value) = fields.remove(old) else {
+            continue;
+        };
+
+        warn_once(old, || {
+            format!("the `{old}` field is deprecated, use `{new}` instead")
+        });
+        fields.entry(new).or_insert(value);
+    }
+
+    for &(field, raw_field) in RAW_NUMBER_FIELDS {
+        if let Some(Value::Number(number)) = fields.get(field) {
+            return Err(BareNumber {
+                field,
+                raw_field,
+                number: number.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A bare number for a field that takes a level like `"40dB"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BareNumber {
+    pub field: &'static str,
+    /// The field taking device units instead
+    pub raw_field: &'static str,
+    pub number: String,
+}
+
+impl Display for BareNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            field,
+            raw_field,
+            number,
+        } = self;
+        write!(
+            f,
+            "`{field}` takes a level like \"{number}dB\", not a bare number, use `{raw_field}` \
+             for device units"
+        )
+    }
+}
+
+impl std::error::Error for BareNumber {}
+
+fn warn_once(field: &'static str, message: impl FnOnce() -> String) {
+    static WARNED: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
+    if WARNED.lock().unwrap().insert(field) {
+        log::warn!("{}", message());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn migrated(mut line: Value) -> Result<Value, BareNumber> {
+        migrate(&mut line).map(|()| line)
+    }
+
+    #[test]
+    fn renames_persistant() {
+        assert_eq!(
+            migrated(json!({"mute": true, "persistant": true})),
+            Ok(json!({"mute": true, "persistent": true}))
+        );
+        // The current name wins over the old one
+        assert_eq!(
+            migrated(json!({"persistant": true, "persistent": false})),
+            Ok(json!({"persistent": false}))
+        );
+    }
+
+    #[test]
+    fn rejects_bare_numbers_for_levels() {
+        let err = migrated(json!({"gain": 40})).unwrap_err();
+        assert_eq!(
+            err,
+            BareNumber {
+                field: "gain",
+                raw_field: "gain_raw",
+                number: "40".to_owned(),
+            }
+        );
+        assert!(err.to_string().contains(r#""40dB""#), "{err}");
+
+        assert!(migrated(json!({"volume": -12})).is_err());
+        // Agreeing with the raw field doesn't make it any less ambiguous
+        assert!(migrated(json!({"gain": 2560, "gain_raw": 2560})).is_err());
+    }
+
+    #[test]
+    fn keeps_current_lines() {
+        for line in [
+            json!({"gain": "40dB", "volume_raw": -3072, "persistent": true}),
+            json!({}),
+            json!("not an object"),
+        ] {
+            assert_eq!(migrated(line.clone()), Ok(line));
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! The main configuration file, by default `$XDG_CONFIG_HOME/tidal-wave/config.toml`
+//!
+//! ```toml
+//! [vars]
+//! accent_color = "[255, 0, 128]"
+//!
+//! [host."studio-pc"]
+//! gain = "55dB"
+//! phantom = true
+//!
+//! [alias]
+//! stream = "diff streaming.toml --apply"
+//!
+//! [device_names]
+//! "A1B2C3D4" = "desk-mic"
+//!
+//! [theme.stream]
+//! color_gen = "#9146ff"
+//! color_mute = "red"
+//! color_gain_reduction = "orange"
+//! ```
+
+use crate::{pywal::Pywal, schedule::Night, themes::Theme};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Variables available as `${name}` in profiles
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+
+    /// Settings by hostname, which override the settings of every profile loaded on that host
+    #[serde(default)]
+    pub host: BTreeMap<String, Map<String, Value>>,
+
+    /// Command aliases, expanded by the CLI
+    #[serde(default)]
+    pub alias: BTreeMap<String, String>,
+
+    /// Friendly names by serial number, accepted and reported instead of the serial
+    #[serde(default)]
+    pub device_names: BTreeMap<String, String>,
+
+    /// LED color themes, in addition to the built-in ones
+    #[serde(default)]
+    pub theme: BTreeMap<String, Theme>,
+
+    /// Hours to switch the LEDs to a night setup, see [`schedule`](crate::schedule)
+    pub night: Option<Night>,
+
+    /// Follow the colors generated by pywal, see [`pywal`](crate::pywal)
+    pub pywal: Option<Pywal>,
+}
+
+impl Config {
+    /// Load the config from `path`, or from the default location if `None`
+    ///
+    /// A missing file at the default location is not an error and yields the default config.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let default_path = dirs::config_dir().map(|dir| dir.join("tidal-wave/config.toml"));
+        let (path, required) = match (path, &default_path) {
+            (Some(path), _) => (path, true),
+            (None, Some(path)) => (path.as_path(), false),
+            (None, None) => return Ok(Self::default()),
+        };
+
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default());
+            }
+            Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+        };
+        toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Overrides for the host this is running on
+    pub fn host_overrides(&self) -> Option<&Map<String, Value>> {
+        let hostname = hostname::get().ok()?;
+        self.host.get(hostname.to_str()?)
+    }
+
+    /// The serial number of a unit given by its name or serial number
+    pub fn resolve_device<'a>(&'a self, name: &'a str) -> &'a str {
+        self.device_names
+            .iter()
+            .find(|(_, other)| *other == name)
+            .map_or(name, |(serial, _)| serial)
+    }
+
+    /// Look up a variable, with the environment taking precedence over the config file
+    pub fn var(&self, name: &str) -> Option<String> {
+        std::env::var(name)
+            .ok()
+            .or_else(|| self.vars.get(name).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn parses_the_documented_example() {
+        let text = include_str!("config.rs")
+            .lines()
+            .skip_while(|line| *line != "//! ```toml")
+            .skip(1)
+            .take_while(|line| *line != "//! ```")
+            .map(|line| line.strip_prefix("//! ").unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let config: Config = toml::from_str(&text).unwrap();
+
+        assert_eq!(config.vars["accent_color"], "[255, 0, 128]");
+        assert_eq!(config.host["studio-pc"]["gain"], "55dB");
+        assert_eq!(config.alias["stream"], "diff streaming.toml --apply");
+        assert!(config.theme.contains_key("stream"));
+    }
+
+    #[test]
+    fn resolves_device_names_to_serials() {
+        let config: Config = toml::from_str("[device_names]\nA1B2C3D4 = \"desk-mic\"").unwrap();
+        assert_eq!(config.resolve_device("desk-mic"), "A1B2C3D4");
+        assert_eq!(config.resolve_device("A1B2C3D4"), "A1B2C3D4");
+        assert_eq!(config.resolve_device("other"), "other");
+    }
+
+    #[test]
+    fn requires_a_file_given_explicitly() {
+        let path = env::temp_dir().join(format!("tidal-wave-config-{}.toml", process::id()));
+        assert!(Config::load(Some(&path)).is_err());
+
+        fs::write(&path, "[alias]\nx = \"get\"\ntypo = 1\n").unwrap();
+        let err = Config::load(Some(&path)).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(format!("{err:#}").contains("parsing"), "{err:#}");
+    }
+}
@@ -0,0 +1,224 @@
+//! One-shot commands for scripts, built on the same protocol fields as the stdio mode
+
+use crate::{
+    color::hex_colors,
+    compat,
+    state_bus::StateBus,
+    stdio,
+    ui_state::{Decibel, Line, UiState},
+    usb_device::{Color, Device, DeviceConfiguration, DeviceOptions, Mode},
+};
+use anyhow::{Context, Result, bail};
+use serde_json::{Map, Value};
+use std::{
+    env,
+    io::{self, IsTerminal},
+    mem,
+    time::Duration,
+};
+use tokio::time::sleep;
+
+/// Fields where a bare number given on the command line means dB rather than device units
+const DECIBEL_FIELDS: &[&str] = &["gain", "volume"];
+
+/// Read the current settings as a map of protocol fields, with colors as hex strings if `hex`
+async fn current(device: &Device, hex: bool) -> Result<Map<String, Value>> {
+    let config = device.read_config(device.timeouts().read).await?;
+    let line = UiState::default().update_device_info(config);
+    match serde_json::to_value(hex_colors(&line, hex))? {
+        Value::Object(fields) => Ok(fields),
+        _ => unreachable!("Line serializes to an object"),
+    }
+}
+
+/// Print all settings as a JSON object, or the values of `fields` one per line
+///
+/// With `human`, prints `field: value` lines instead, with a swatch next to each color if stdout
+/// is a terminal. With `hex`, colors in JSON are hex strings.
+pub async fn get(options: &DeviceOptions, fields: &[String], human: bool, hex: bool) -> Result<()> {
+    let device = Device::try_initialize(options).await?;
+    let current = current(&device, hex).await?;
+
+    if human {
+        let swatches = io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none();
+        let fields: Vec<&String> = match fields {
+            [] => current.keys().collect(),
+            fields => fields.iter().collect(),
+        };
+        for field in fields {
+            let value = current
+                .get(field)
+                .with_context(|| format!("unknown field `{field}`"))?;
+            match (field.starts_with("color_"), value) {
+                (true, value) => {
+                    let color: Color = serde_json::from_value(value.clone())?;
+                    match swatches {
+                        true => println!("{field}: {color} {}", color.swatch()),
+                        false => println!("{field}: {color}"),
+                    }
+                }
+                (false, Value::String(s)) => println!("{field}: {s}"),
+                // Reported in device units
+                (false, Value::Number(n)) if DECIBEL_FIELDS.contains(&field.as_str()) => {
+                    let level = n.as_i64().and_then(|n| i32::try_from(n).ok());
+                    match level {
+                        Some(level) => println!("{field}: {}", Decibel(level)),
+                        None => println!("{field}: {n}"),
+                    }
+                }
+                (false, value) => println!("{field}: {value}"),
+            }
+        }
+        return Ok(());
+    }
+
+    if fields.is_empty() {
+        println!("{}", serde_json::to_string(&current)?);
+        return Ok(());
+    }
+    for field in fields {
+        match current
+            .get(field)
+            .with_context(|| format!("unknown field `{field}`"))?
+        {
+            Value::String(s) => println!("{s}"),
+            value => println!("{value}"),
+        }
+    }
+    Ok(())
+}
+
+/// Apply `settings`, given as alternating fields and values
+pub async fn set(
+    options: &DeviceOptions,
+    settings: &[String],
+    persistent: bool,
+    state: UiState,
+) -> Result<()> {
+    if !settings.len().is_multiple_of(2) {
+        bail!("missing value for `{}`", settings[settings.len() - 1]);
+    }
+
+    let mut fields = Map::new();
+    for pair in settings.chunks_exact(2) {
+        let [field, value] = pair else { unreachable!() };
+        let value = match serde_json::from_str(value) {
+            Ok(Value::Number(n)) if DECIBEL_FIELDS.contains(&field.as_str()) => {
+                Value::String(format!("{n}dB"))
+            }
+            Ok(value) => value,
+            // Anything that isn't valid JSON is taken as a plain string, like `80Hz`
+            Err(_) => Value::String(value.clone()),
+        };
+        fields.insert(field.clone(), value);
+    }
+
+    if persistent {
+        fields.insert("persistent".to_owned(), Value::Bool(true));
+    }
+
+    let device = Device::try_initialize(options).await?;
+    apply(&device, Value::Object(fields), state).await
+}
+
+/// Flip the boolean settings `fields`
+pub async fn toggle(
+    options: &DeviceOptions,
+    fields: &[String],
+    persistent: bool,
+    state: UiState,
+) -> Result<()> {
+    let mut line = Map::new();
+    line.insert("toggle".to_owned(), fields.into());
+    if persistent {
+        line.insert("persistent".to_owned(), Value::Bool(true));
+    }
+
+    let device = Device::try_initialize(options).await?;
+    apply(&device, Value::Object(line), state).await
+}
+
+/// Apply a single line of the stdio protocol
+pub async fn apply_json(options: &DeviceOptions, json: &str, state: UiState) -> Result<()> {
+    let line = serde_json::from_str(json).context("parsing line")?;
+    let device = Device::try_initialize(options).await?;
+    apply(&device, line, state).await
+}
+
+async fn apply(device: &Device, mut line: Value, state: UiState) -> Result<()> {
+    compat::migrate(&mut line)?;
+    let line: Line = serde_json::from_value(line)?;
+
+    let state = StateBus::spawn(state);
+    stdio::apply(device, &state, line).await?;
+    for event in state.with(|state| mem::take(&mut state.events)).await {
+        log::warn!("{}", serde_json::to_string(&event)?);
+    }
+    Ok(())
+}
+
+/// Flash the LEDs in a short-short-long pattern for a few seconds, then restore them
+///
+/// Tells several connected units apart, the one flashing is the one selected with `--serial` or
+/// `--bus-address`.
+pub async fn identify(options: &DeviceOptions) -> Result<()> {
+    const PATTERN: &[(bool, u64)] = &[
+        (true, 150),
+        (false, 150),
+        (true, 150),
+        (false, 150),
+        (true, 600),
+        (false, 400),
+    ];
+    const REPEAT: usize = 3;
+
+    let device = Device::try_initialize(options).await?;
+    let timeouts = device.timeouts();
+    let before = device.read_config(timeouts.read).await?;
+    println!("identifying {}", device.info()?.id());
+
+    let flash = async {
+        for &(on, ms) in PATTERN.iter().cycle().take(PATTERN.len() * REPEAT) {
+            // The mute color too, so it also shows while muted
+            let color = if on { Color([0xff; 3]) } else { Color([0; 3]) };
+            let config = DeviceConfiguration {
+                color_gen: color,
+                color_mute: color,
+                ..before
+            };
+            device
+                .write_config(&config, Mode::Temporary, timeouts.write)
+                .await?;
+            sleep(Duration::from_millis(ms)).await;
+        }
+        anyhow::Ok(())
+    };
+    let res = tokio::select! {
+        res = flash => res,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    device
+        .write_config(&before, Mode::Temporary, timeouts.write)
+        .await
+        .context("restoring the colors")?;
+    res
+}
+
+/// Print the settings once, then every change as JSON lines until interrupted
+///
+/// Polls every `interval`, [`stdio::POLL_INTERVAL`] if not given. With `hex`, colors are hex
+/// strings.
+pub async fn watch(options: &DeviceOptions, interval: Option<Duration>, hex: bool) -> Result<()> {
+    let device = Device::try_initialize(options).await?;
+    let mut state = UiState::default();
+
+    loop {
+        let config = device.read_config(device.timeouts().read).await?;
+        let line = state.update_device_info(config);
+        if !line.is_empty() {
+            println!("{}", serde_json::to_string(&hex_colors(&line, hex))?);
+        }
+        sleep(interval.unwrap_or(stdio::POLL_INTERVAL)).await;
+    }
+}
@@ -0,0 +1,134 @@
+use crate::capabilities::Capabilities;
+use crate::raw;
+use crate::usb_device::{DeviceConfiguration, DeviceInfo, ReservedByte, UndecodedChange};
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{self, Write};
+
+/// Out-of-band events written to the output stream alongside the state lines
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Summary of the device and options, emitted once before any state lines
+    ///
+    /// `config` is the full initial configuration, which later state lines are diffs against.
+    Startup {
+        device: DeviceInfo,
+        options: StartupOptions,
+        config: DeviceConfiguration,
+        revision: u64,
+    },
+
+    /// A requested change was applied, but is likely not what the user wants
+    Warning { message: String },
+
+    /// A policy rule changed more than what was requested
+    Policy { rule: &'static str, reason: String },
+
+    /// Answer to `{"get": "capabilities"}`
+    Capabilities(Capabilities),
+
+    /// Answer to `{"get": "info"}`
+    Info(DeviceInfo),
+
+    /// Answer to `{"raw": {...}}`, with the hex encoded response of an IN transfer, or to
+    /// `{"get": "raw"}`, with the last configuration buffer read
+    Raw {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+    },
+
+    /// A line with `if_revision` wasn't applied, because the settings changed since that revision
+    Conflict { expected: u64, revision: u64 },
+
+    /// Reading back a write found settings the device didn't take, see `--verify`
+    WriteRejected { fields: Vec<RejectedField> },
+
+    /// A line wasn't valid, see `--on-invalid-input echo-with-context`
+    ///
+    /// `offset` is where the line starts in the input, in bytes.
+    InvalidInput {
+        message: String,
+        input: String,
+        offset: u64,
+    },
+
+    /// A line with `"transaction": true` failed, and the settings from before were written again
+    /// unless `rolled_back` is false
+    TransactionFailed {
+        message: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        rejected: Vec<RejectedField>,
+        rolled_back: bool,
+    },
+
+    /// The device was unplugged or otherwise stopped answering
+    DeviceLost,
+
+    /// The device is back after [`Event::DeviceLost`]
+    DeviceFound { serial: Option<String> },
+
+    /// Bytes of the configuration that aren't decoded into any field changed
+    ///
+    /// Likely a feature of the firmware this tool doesn't model yet. `before` and `after` are hex.
+    UndecodedChange {
+        offset: usize,
+        before: String,
+        after: String,
+    },
+
+    /// The process is about to exit because of a panic or an unrecoverable error
+    Fatal {
+        code: &'static str,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        backtrace_hash: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct RejectedField {
+    pub field: String,
+    pub written: Value,
+    pub read: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StartupOptions {
+    pub poll_interval_ms: u128,
+    pub encoding: &'static str,
+}
+
+impl From<UndecodedChange> for Event {
+    fn from(change: UndecodedChange) -> Self {
+        Event::UndecodedChange {
+            offset: change.offset,
+            before: raw::hex(&change.before),
+            after: raw::hex(&change.after),
+        }
+    }
+}
+
+impl From<ReservedByte> for Event {
+    fn from(byte: ReservedByte) -> Self {
+        Event::Warning {
+            message: format!(
+                "reserved byte {} is {:#04x} instead of the usual {:#04x}",
+                byte.offset, byte.value, byte.usual
+            ),
+        }
+    }
+}
+
+impl Event {
+    /// Write the event as a single line to stdout, bypassing the async writer
+    ///
+    /// Used when the runtime is going away and the regular output task can't be relied upon.
+    pub fn emit_blocking(&self) {
+        let mut buf = serde_json::to_vec(self).expect("event serialization can't fail");
+        buf.push(b'\n');
+
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(&buf).and_then(|()| stdout.flush());
+    }
+}
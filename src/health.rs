@@ -0,0 +1,119 @@
+use crate::usb_device::{Device, DeviceOptions};
+use anyhow::Result;
+use std::{
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+/// Responses slower than this are reported as a warning
+const SLOW_RESPONSE: Duration = Duration::from_millis(500);
+
+/// Nagios plugin status, which doubles as the exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+}
+
+/// Check that the device answers a read and report in the Nagios plugin convention
+///
+/// A device whose interface is claimed by another process, usually the tidal-wave daemon, is
+/// reported as OK, as that's how it is in normal operation.
+pub async fn health(options: &DeviceOptions) -> ExitCode {
+    let (status, message) = match check(options).await {
+        Ok(res) => res,
+        Err(err) => {
+            let busy = err
+                .chain()
+                .filter_map(|err| err.downcast_ref::<nusb::Error>())
+                .any(|err| err.kind() == nusb::ErrorKind::Busy);
+            failed(&err, busy)
+        }
+    };
+
+    println!("{}", report(status, &message));
+    ExitCode::from(status as u8)
+}
+
+/// The status for a failed check, `busy` if the interface is claimed by another process
+fn failed(err: &anyhow::Error, busy: bool) -> (Status, String) {
+    match busy {
+        true => (
+            Status::Ok,
+            format!("device is in use by another process, like a running tidal-wave ({err:#})"),
+        ),
+        false => (Status::Critical, format!("{err:#}")),
+    }
+}
+
+/// The status line of a Nagios plugin
+fn report(status: Status, message: &str) -> String {
+    let label = match status {
+        Status::Ok => "OK",
+        Status::Warning => "WARNING",
+        Status::Critical => "CRITICAL",
+    };
+    format!("TIDAL-WAVE {label} - {message}")
+}
+
+/// Answers slower than [`SLOW_RESPONSE`] are a warning
+fn status_for(elapsed: Duration) -> Status {
+    match elapsed > SLOW_RESPONSE {
+        true => Status::Warning,
+        false => Status::Ok,
+    }
+}
+
+async fn check(options: &DeviceOptions) -> Result<(Status, String)> {
+    let device = Device::try_initialize(options).await?;
+    let info = device.info()?;
+
+    let start = Instant::now();
+    let config = device.read_config(device.timeouts().read).await?;
+    let elapsed = start.elapsed();
+
+    let name = match &info.serial {
+        Some(serial) => format!("{} {serial}", info.model),
+        None => info.model,
+    };
+    let status = status_for(elapsed);
+    let mute = match config.mute {
+        true => "muted",
+        false => "unmuted",
+    };
+    Ok((
+        status,
+        format!("{name} answered in {}ms, {mute}", elapsed.as_millis()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn reports_a_busy_device_as_ok() {
+        let err = anyhow!("claiming the interface failed");
+        let (status, message) = failed(&err, true);
+        assert_eq!(status, Status::Ok);
+        assert!(message.contains("in use"), "{message}");
+        assert_eq!(failed(&err, false).0, Status::Critical);
+    }
+
+    #[test]
+    fn warns_about_slow_answers() {
+        assert_eq!(status_for(Duration::from_millis(20)), Status::Ok);
+        assert_eq!(status_for(SLOW_RESPONSE * 2), Status::Warning);
+    }
+
+    #[test]
+    fn follows_the_nagios_status_line() {
+        assert_eq!(
+            report(Status::Critical, "missing device"),
+            "TIDAL-WAVE CRITICAL - missing device"
+        );
+        assert_eq!(Status::Warning as u8, 1);
+    }
+}
@@ -0,0 +1,98 @@
+//! Control an Elgato Wave XLR via USB
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use tidal_wave::{Device, DeviceOptions, Mode, Timeouts};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let options = DeviceOptions {
+//!     timeouts: Timeouts {
+//!         read: Duration::from_secs(1),
+//!         write: Duration::from_secs(3),
+//!     },
+//!     ..DeviceOptions::default()
+//! };
+//! let device = Device::try_initialize(&options).await?;
+//!
+//! let mut config = device.read_config(device.timeouts().read).await?;
+//! config.mute = true;
+//! device
+//!     .write_config(&config, Mode::Temporary, device.timeouts().write)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only the items re-exported at the top level are a stable API. The modules back the
+//! `tidal-wave` binary and may change between any two versions.
+
+pub use backend::DeviceBackend;
+pub use capabilities::Capabilities;
+pub use usb_device::{
+    BusAddress, Candidate, Color, Device, DeviceConfiguration, DeviceInfo, DeviceOptions,
+    LowcutFilter, Mode, Reserved, ReservedByte, RetryPolicy, Selector, Timeouts, UndecodedChange,
+};
+
+#[doc(hidden)]
+pub mod animation;
+#[cfg(feature = "pipewire")]
+#[doc(hidden)]
+pub mod audio;
+#[doc(hidden)]
+pub mod backend;
+#[doc(hidden)]
+pub mod capabilities;
+#[doc(hidden)]
+pub mod color;
+#[doc(hidden)]
+pub mod compat;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod control;
+#[doc(hidden)]
+pub mod event;
+#[doc(hidden)]
+pub mod health;
+#[doc(hidden)]
+pub mod logging;
+#[doc(hidden)]
+pub mod metrics;
+#[cfg(any(test, feature = "mock"))]
+#[doc(hidden)]
+pub mod mock;
+#[doc(hidden)]
+pub mod policy;
+#[doc(hidden)]
+pub mod probe;
+#[doc(hidden)]
+pub mod profile;
+#[doc(hidden)]
+pub mod pywal;
+#[doc(hidden)]
+pub mod quirks;
+#[doc(hidden)]
+pub mod raw;
+#[doc(hidden)]
+pub mod report;
+#[doc(hidden)]
+pub mod schedule;
+#[doc(hidden)]
+pub mod script;
+#[doc(hidden)]
+pub mod selftest;
+#[doc(hidden)]
+pub mod state_bus;
+#[doc(hidden)]
+pub mod stdio;
+#[doc(hidden)]
+pub mod sync;
+#[doc(hidden)]
+pub mod themes;
+#[doc(hidden)]
+pub mod ui_state;
+mod usb_device;
+#[doc(hidden)]
+pub mod usb_session;
+#[doc(hidden)]
+pub mod watchdog;
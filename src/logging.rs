@@ -0,0 +1,55 @@
+use clap::ValueEnum;
+use env_logger::{Builder, Env};
+use log::{
+    LevelFilter,
+    kv::{self, VisitSource},
+};
+use serde_json::{Map, Value, json};
+use std::io::Write;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line with level, target, message and fields
+    Json,
+}
+
+/// Set up logging to stderr, filtered by `RUST_LOG` and defaulting to warnings
+///
+/// `trace_usb` additionally logs every control transfer, see [`Device`](crate::Device).
+pub fn init(format: LogFormat, trace_usb: bool) {
+    let mut builder = Builder::from_env(Env::default().default_filter_or("warn"));
+    if trace_usb {
+        builder.filter(Some("usb"), LevelFilter::Trace);
+    }
+
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let mut fields = Fields(Map::new());
+            let _ = record.key_values().visit(&mut fields);
+
+            let line = json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+                "fields": fields.0,
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+
+    builder.init();
+}
+
+struct Fields(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0
+            .insert(key.to_string(), Value::String(value.to_string()));
+        Ok(())
+    }
+}
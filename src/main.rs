@@ -1,33 +1,355 @@
-use crate::{stdio::stdio, ui_state::UiState, usb_device::Device};
-use anyhow::{Context, Result};
-use std::{
-    io,
-    sync::{Arc, Mutex},
+use crate::cli::{Cli, Command};
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+use std::{env, io, process::ExitCode, sync::Arc, time::Duration};
+use tidal_wave::{
+    Device, DeviceOptions, RetryPolicy, Selector, Timeouts,
+    config::Config,
+    control, health, logging, metrics,
+    policy::{AutoClipguard, Policy},
+    probe, profile, pywal, raw, report, schedule, script, selftest,
+    state_bus::StateBus,
+    stdio::{self, stdio},
+    sync, themes,
+    ui_state::UiState,
+    usb_session,
 };
-use tokio::io::BufReader;
+use tokio::{io::BufReader, sync::Notify, task::JoinSet};
 
-mod stdio;
-mod ui_state;
-mod usb_device;
+mod alias;
+mod cli;
 
-fn main() {
-    match try_main().context(io::Error::last_os_error()) {
-        Ok(()) => (),
-        Err(res) => println!("{res:#?}"),
+fn main() -> ExitCode {
+    report::install_panic_hook();
+    let argv: Vec<String> = env::args().collect();
+    let cli = Cli::parse_from(&argv);
+    logging::init(cli.log_format, cli.trace_usb);
+
+    match try_main(cli, argv) {
+        Ok(code) => code,
+        Err(err) => {
+            report::report_error(&err);
+            ExitCode::from(report::exit_code(&err))
+        }
     }
 }
 
 #[tokio::main]
-async fn try_main() -> Result<()> {
-    let device = Device::try_initialize().await?;
-    let state = Arc::new(Mutex::new(UiState::default()));
+async fn try_main(cli: Cli, argv: Vec<String>) -> Result<ExitCode> {
+    run(cli, argv, 0).await
+}
+
+async fn run(cli: Cli, argv: Vec<String>, depth: usize) -> Result<ExitCode> {
+    let config = Config::load(cli.config.as_deref())?;
+    #[cfg(feature = "mock")]
+    if let Some(faults) = &cli.mock {
+        tidal_wave::mock::enable(faults);
+    }
+    if let Some(path) = &cli.record_usb {
+        usb_session::start(path)?;
+    }
+    let options = DeviceOptions {
+        selector: Selector {
+            serial: cli
+                .serial
+                .as_deref()
+                .map(|serial| config.resolve_device(serial).to_owned()),
+            bus_address: cli.bus_address.clone(),
+        },
+        retry: RetryPolicy {
+            retries: cli.retries,
+            backoff: Duration::from_millis(cli.retry_backoff_ms),
+        },
+        timeouts: Timeouts {
+            read: Duration::from_millis(cli.read_timeout_ms),
+            write: Duration::from_millis(cli.write_timeout_ms),
+        },
+    };
+    let poll_interval = cli.poll_interval.map(Duration::from_millis);
+    let make_state = || UiState {
+        policy: Policy {
+            override_gain_lock: cli.force,
+            phantom_lim: cli.phantom_lim,
+            auto_clipguard: cli
+                .auto_clipguard_above
+                .map(|threshold| AutoClipguard::new(threshold.0, cli.auto_clipguard_release)),
+        },
+        themes: config.theme.clone(),
+        poll_interval,
+        verify_writes: cli.verify,
+        max_cache_age: cli.max_cache_age_ms.map(Duration::from_millis),
+        ..UiState::default()
+    };
+    let state = make_state();
+
+    match cli.command {
+        None => {}
+        Some(Command::Get { fields, human }) => {
+            control::get(&options, &fields, human, cli.hex_colors).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Set {
+            settings,
+            persistent,
+        }) => {
+            control::set(&options, &settings, persistent, state).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Toggle { fields, persistent }) => {
+            control::toggle(&options, &fields, persistent, state).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Apply { line }) => {
+            let line = match line {
+                Some(line) => line,
+                None => {
+                    let mut line = String::new();
+                    io::stdin().read_line(&mut line).context("reading stdin")?;
+                    line
+                }
+            };
+            control::apply_json(&options, &line, state).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Theme { name: None, .. }) => {
+            for name in config.theme.keys() {
+                println!("{name}");
+            }
+            for (name, _) in themes::BUILTIN {
+                if !config.theme.contains_key(*name) {
+                    println!("{name} (built-in)");
+                }
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Theme {
+            name: Some(name),
+            persistent,
+        }) => {
+            control::set(&options, &["theme".to_owned(), name], persistent, state).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Watch) => {
+            control::watch(&options, poll_interval, cli.hex_colors).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Selftest { live }) => {
+            selftest::selftest(&options, live).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Diff {
+            profile,
+            apply,
+            persistent,
+        }) => {
+            return profile::diff_command(&options, &profile, &config, apply, persistent, state)
+                .await;
+        }
+        Some(Command::Sync {
+            source,
+            targets,
+            persistent,
+            dry_run,
+        }) => {
+            let targets: Vec<_> = targets
+                .iter()
+                .map(|target| config.resolve_device(target).to_owned())
+                .collect();
+            let source = config.resolve_device(&source);
+            sync::sync(&options, source, &targets, persistent, dry_run).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Health) => return Ok(health::health(&options).await),
+        Some(Command::Identify) => {
+            control::identify(&options).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Info) => {
+            let device = Device::try_initialize(&options).await?;
+            println!("{}", serde_json::to_string(&device.info()?)?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::ListDevices) => {
+            for candidate in Device::list().await? {
+                println!("{}", serde_json::to_string(&candidate)?);
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Metrics { format }) => {
+            metrics::metrics(&options, format, cli.hex_colors).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Probe {
+            kind,
+            recipient,
+            requests,
+            values,
+            indexes,
+            length,
+            interval_ms,
+            diff,
+            allow_vendor,
+        }) => {
+            let probe = probe::Probe {
+                kind,
+                recipient,
+                requests,
+                values,
+                indexes,
+                length,
+                interval: Duration::from_millis(interval_ms),
+                allow_vendor,
+            };
+            probe::probe(&options, probe, diff.as_deref()).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::ReplayUsb { recording }) => {
+            let device = Device::try_initialize(&options).await?;
+            usb_session::replay(&recording, &device).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Raw { transfer }) => {
+            let device = Device::try_initialize(&options).await?;
+            if let Some(data) = raw::transfer(&device, transfer.into()).await? {
+                println!("{data}");
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Replay { script }) => {
+            let device = Device::try_initialize(&options).await?;
+            let unit = stdio::Unit {
+                device,
+                state: StateBus::spawn(state),
+            };
+            let allowed = stdio::Allowed {
+                raw_write: cli.allow_raw_write,
+                experimental: cli.experimental,
+            };
+            script::replay(&script, &unit, allowed).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Alias(args)) => {
+            if depth >= alias::MAX_DEPTH {
+                bail!("aliases nested more than {} levels deep", alias::MAX_DEPTH);
+            }
+
+            for argv in alias::expand(&config, &argv, &args)? {
+                let cli = Cli::try_parse_from(&argv)?;
+                let code = Box::pin(run(cli, argv, depth + 1)).await?;
+                if code != ExitCode::SUCCESS {
+                    return Ok(code);
+                }
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+    }
+
+    let recorder = cli
+        .record_script
+        .as_deref()
+        .map(script::Recorder::create)
+        .transpose()?;
+    let devices = match cli.wait {
+        _ if cli.all_devices => Device::try_initialize_all(&options).await?,
+        Some(timeout) => vec![Device::wait_for(&options, timeout.map(Duration::from_secs)).await?],
+        None => vec![Device::try_initialize(&options).await?],
+    };
+    let units: Vec<_> = devices
+        .into_iter()
+        .map(|device| stdio::Unit {
+            device,
+            state: StateBus::spawn(make_state()),
+        })
+        .collect();
+
+    // Aborted when dropped, once the protocol ends
+    let mut tasks = JoinSet::new();
+    for unit in &units {
+        if let Some(night) = &config.night {
+            let (device, state) = (unit.device.clone(), unit.state.clone());
+            tasks.spawn(schedule::run(night.clone(), device, state));
+        }
+        if let Some(pywal) = &config.pywal {
+            let (device, state) = (unit.device.clone(), unit.state.clone());
+            tasks.spawn(pywal::run(pywal.clone(), device, state));
+        }
+    }
+    let shutdown = Arc::new(Notify::new());
+    tasks.spawn({
+        let shutdown = Arc::clone(&shutdown);
+        async move {
+            shutdown_signal().await;
+            shutdown.notify_one();
+        }
+    });
 
-    stdio(
-        device,
-        state,
+    let res = stdio(
+        units,
         BufReader::new(tokio::io::stdin()),
         tokio::io::stdout(),
+        stdio::Options {
+            recorder,
+            restore_after_resume: cli.restore_after_resume,
+            tag_device: cli.all_devices,
+            device_names: config.device_names.clone(),
+            report_raw: cli.report_raw,
+            allow_raw_write: cli.allow_raw_write,
+            experimental: cli.experimental,
+            coalesce: cli.coalesce_ms.map(Duration::from_millis),
+            on_invalid_input: cli.on_invalid_input,
+            shutdown: Some(shutdown),
+            restore_on_exit: cli.restore_on_exit,
+            monitor_after_eof: cli.monitor_after_eof,
+            hex_colors: cli.hex_colors,
+        },
     )
-    .await?;
-    Ok(())
+    .await;
+    drop(tasks);
+    res?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Resolves on SIGINT, or on SIGTERM where there is one
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(err) => log::warn!("listening for SIGTERM failed: {err}"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process};
+
+    #[tokio::test]
+    async fn stops_aliases_that_expand_forever() {
+        let path = env::temp_dir().join(format!("tidal-wave-alias-{}.toml", process::id()));
+        fs::write(&path, "[alias]\nping = \"pong\"\npong = \"ping\"\n").unwrap();
+        let argv: Vec<String> = ["tidal-wave", "--config", path.to_str().unwrap(), "ping"]
+            .map(str::to_owned)
+            .into();
+
+        let err = run(Cli::try_parse_from(&argv).unwrap(), argv, 0)
+            .await
+            .unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(
+            err.to_string()
+                .contains(&format!("nested more than {} levels", alias::MAX_DEPTH)),
+            "{err:#}"
+        );
+    }
 }
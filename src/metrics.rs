@@ -0,0 +1,127 @@
+use crate::{
+    color::hex_colors,
+    usb_device::{Color, Device, DeviceConfiguration, DeviceInfo, DeviceOptions},
+};
+use anyhow::Result;
+use clap::ValueEnum;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// InfluxDB line protocol, as expected by Telegraf's exec input
+    Influx,
+    /// A single JSON object with the full configuration
+    Json,
+}
+
+/// Print the current state of the device as a single line, JSON with colors as hex strings if
+/// `hex`
+pub async fn metrics(options: &DeviceOptions, format: Format, hex: bool) -> Result<()> {
+    let device = Device::try_initialize(options).await?;
+    let config = device.read_config(device.timeouts().read).await?;
+
+    match format {
+        Format::Influx => println!("{}", influx(&device.info()?, &config)),
+        Format::Json => println!("{}", serde_json::to_string(&hex_colors(&config, hex))?),
+    }
+    Ok(())
+}
+
+fn influx(info: &DeviceInfo, config: &DeviceConfiguration) -> String {
+    let DeviceConfiguration {
+        gain,
+        mute,
+        clipguard,
+        phantom,
+        lowcut,
+        volume,
+        mix,
+        color_mute,
+        color_gen,
+        gain_lock,
+        color_gain_reduction,
+        clipguard_indicator,
+        lim,
+    } = config;
+
+    let mut line = String::from("tidal_wave");
+    write!(line, ",model={}", escape_tag(&info.model)).unwrap();
+    if let Some(serial) = &info.serial {
+        write!(line, ",serial={}", escape_tag(serial)).unwrap();
+    }
+
+    // Levels are reported in dB, without the unit suffix used by the JSON protocol
+    let db = |raw: f64| raw / 256.0;
+    let color = |Color([r, g, b]): &Color| format!("\"{r:02x}{g:02x}{b:02x}\"");
+    write!(
+        line,
+        " gain={},gain_raw={gain}i,mute={mute},clipguard={clipguard},phantom={phantom},\
+         lowcut=\"{lowcut:?}\",volume={},volume_raw={volume}i,mix={mix}i,\
+         color_mute={},color_gen={},gain_lock={gain_lock},color_gain_reduction={},\
+         clipguard_indicator={clipguard_indicator},lim={lim}",
+        db(f64::from(*gain)),
+        db(f64::from(*volume)),
+        color(color_mute),
+        color(color_gen),
+        color(color_gain_reduction),
+    )
+    .unwrap();
+
+    line
+}
+
+/// Escape commas, equal signs and spaces in tag values
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockDevice, usb_device::LowcutFilter};
+
+    #[test]
+    fn escapes_tag_values() {
+        for (value, expected) in [
+            ("Wave XLR", r"Wave\ XLR"),
+            ("a,b=c", r"a\,b\=c"),
+            ("a, b", r"a\,\ b"),
+            ("MOCK", "MOCK"),
+            ("", ""),
+        ] {
+            assert_eq!(escape_tag(value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn writes_one_line_per_device() {
+        let mut info = MockDevice::new().info();
+        info.model = "Wave XLR".to_owned();
+        info.serial = Some("AB,12=3".to_owned());
+        let config = DeviceConfiguration {
+            gain: 40 * 256 + 128,
+            mute: true,
+            lowcut: LowcutFilter::Cutoff080Hz,
+            volume: -12 * 256,
+            mix: 50,
+            color_gen: Color([0x00, 0x80, 0xff]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            influx(&info, &config),
+            "tidal_wave,model=Wave\\ XLR,serial=AB\\,12\\=3 \
+             gain=40.5,gain_raw=10368i,mute=true,clipguard=false,phantom=false,\
+             lowcut=\"Cutoff080Hz\",volume=-12,volume_raw=-3072i,mix=50i,\
+             color_mute=\"000000\",color_gen=\"0080ff\",gain_lock=false,\
+             color_gain_reduction=\"000000\",clipguard_indicator=false,lim=false"
+        );
+    }
+}
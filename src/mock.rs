@@ -0,0 +1,433 @@
+//! An emulated Wave XLR, for developing and demoing without the hardware
+//!
+//! Enabled with `--mock` when built with the `mock` feature. It answers the configuration
+//! requests of [`quirks::WAVE_XLR`] from an in-memory buffer and stalls every other request, so
+//! everything built on [`Device`](crate::Device) works unchanged.
+//!
+//! [`MockDevice::inject`] makes upcoming transfers fail, to exercise the retry, recovery and
+//! error reporting paths. The same [`Fault`]s can be given on the command line, like
+//! `--mock=timeout,corrupt:4,random-disconnects:10`.
+//!
+//! An `Arc<MockDevice>` is also a [`DeviceBackend`] of its own, to run the protocol against the
+//! emulated buffer without a [`Device`](crate::Device) around it.
+
+use crate::{
+    backend::DeviceBackend,
+    capabilities::Capabilities,
+    quirks::{self, Quirks},
+    usb_device::{Color, DeviceConfiguration, DeviceInfo, LowcutFilter, Mode, ProtocolError},
+};
+use anyhow::{Result, bail};
+use nusb::transfer::{ControlIn, ControlOut, ControlType, Recipient, TransferError};
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{sync::Notify, time::sleep};
+
+/// The emulated device of this process, shared by every [`Device`](crate::Device) opening it
+static SHARED: OnceLock<Arc<MockDevice>> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct MockDevice {
+    quirks: &'static Quirks,
+    buf: Mutex<Vec<u8>>,
+    /// What `buf` is reset to on a power cycle
+    stored: Mutex<Vec<u8>>,
+    faults: Mutex<VecDeque<Fault>>,
+    /// Set by [`Fault::RandomDisconnects`]
+    disconnects: Mutex<Option<RandomDisconnects>>,
+    /// Configuration writes that went through
+    writes: AtomicUsize,
+    /// Answers the interrupt endpoint
+    changed: Notify,
+}
+
+/// A way for a single transfer to fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer nothing until the transfer times out
+    Timeout,
+    /// Answer a read with only half of the configuration
+    ShortRead,
+    /// Answer a read with a configuration whose mute byte is neither 0 nor 1
+    InvalidBool,
+    /// Stall the transfer, the next one goes through again
+    Stall,
+    /// Answer a read with every bit of the byte at `offset` flipped
+    Corrupt { offset: usize },
+    /// Fail the transfer like an unplugged device, it's back for the next one
+    Disconnect,
+    /// Fail this and every later transfer like an unplugged device with the given chance
+    ///
+    /// The transfers that fail are picked the same way on every run, so a run can be repeated.
+    RandomDisconnects { percent: u8 },
+    /// Acknowledge a write without applying it
+    Ignore,
+}
+
+impl Fault {
+    fn affects_reads(self) -> bool {
+        self != Fault::Ignore
+    }
+
+    fn affects_writes(self) -> bool {
+        match self {
+            Fault::Timeout
+            | Fault::Stall
+            | Fault::Ignore
+            | Fault::Disconnect
+            | Fault::RandomDisconnects { .. } => true,
+            Fault::ShortRead | Fault::InvalidBool | Fault::Corrupt { .. } => false,
+        }
+    }
+}
+
+/// Parses the kebab-case name of a fault, with its parameter after a colon, like `corrupt:4`
+impl FromStr for Fault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => (name, Some(param)),
+            None => (s, None),
+        };
+        let fault = match (name, param) {
+            ("timeout", None) => Fault::Timeout,
+            ("short-read", None) => Fault::ShortRead,
+            ("invalid-bool", None) => Fault::InvalidBool,
+            ("stall", None) => Fault::Stall,
+            ("ignore", None) => Fault::Ignore,
+            ("disconnect", None) => Fault::Disconnect,
+            ("corrupt", Some(offset)) => Fault::Corrupt {
+                offset: offset
+                    .parse()
+                    .map_err(|err| format!("invalid offset {offset:?}: {err}"))?,
+            },
+            ("random-disconnects", Some(percent)) => match percent.parse() {
+                Ok(percent @ 0..=100) => Fault::RandomDisconnects { percent },
+                _ => return Err(format!("{percent:?} is not a percentage from 0 to 100")),
+            },
+            _ => {
+                return Err(format!(
+                    "unknown fault {s:?}, expected timeout, short-read, invalid-bool, stall, \
+                     ignore, disconnect, corrupt:OFFSET or random-disconnects:PERCENT"
+                ));
+            }
+        };
+        Ok(fault)
+    }
+}
+
+/// Picks the transfers failed by [`Fault::RandomDisconnects`]
+#[derive(Debug)]
+struct RandomDisconnects {
+    percent: u8,
+    /// xorshift64 state, never 0
+    state: u64,
+}
+
+impl RandomDisconnects {
+    fn new(percent: u8) -> Self {
+        Self {
+            percent,
+            state: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    fn next(&mut self) -> bool {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state % 100 < u64::from(self.percent)
+    }
+}
+
+/// Use the emulated device instead of looking for a real one from now on
+///
+/// The `faults` are injected when the device is created, so only once per process.
+pub fn enable(faults: &[Fault]) {
+    SHARED.get_or_init(|| {
+        let mock = MockDevice::new();
+        for &fault in faults {
+            mock.inject(fault);
+        }
+        Arc::new(mock)
+    });
+}
+
+/// The emulated device, if [`enable`]d
+pub(crate) fn shared() -> Option<Arc<MockDevice>> {
+    SHARED.get().cloned()
+}
+
+impl MockDevice {
+    /// A device with plausible settings, rather than all zeros
+    pub fn new() -> Self {
+        let quirks = &quirks::WAVE_XLR;
+        let config = DeviceConfiguration {
+            gain: 40 * 256,
+            mute: false,
+            clipguard: true,
+            phantom: false,
+            lowcut: LowcutFilter::Off,
+            volume: -12 * 256,
+            mix: 50,
+            color_mute: Color([0xff, 0x00, 0x00]),
+            color_gen: Color([0x00, 0x80, 0xff]),
+            gain_lock: false,
+            color_gain_reduction: Color([0xff, 0xa5, 0x00]),
+            clipguard_indicator: true,
+            lim: false,
+        };
+        let layout = &quirks.layouts[0];
+        let mut buf = vec![0; layout.length];
+        config.write(&mut buf, layout);
+
+        Self {
+            quirks,
+            stored: Mutex::new(buf.clone()),
+            buf: Mutex::new(buf),
+            faults: Mutex::default(),
+            disconnects: Mutex::default(),
+            writes: AtomicUsize::new(0),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Fail an upcoming transfer
+    ///
+    /// Faults are used up in the order they were injected, each by the next transfer it can
+    /// affect. Reads can fail in every way but [`Fault::Ignore`], writes only by
+    /// [`Fault::Timeout`], [`Fault::Stall`], [`Fault::Ignore`] and the disconnects.
+    pub fn inject(&self, fault: Fault) {
+        self.faults.lock().unwrap().push_back(fault);
+    }
+
+    /// The configuration as the device would answer it, without any faults
+    pub fn config(&self) -> Vec<u8> {
+        self.buf.lock().unwrap().clone()
+    }
+
+    /// The configuration the device would come back with after a power cycle
+    pub fn stored(&self) -> Vec<u8> {
+        self.stored.lock().unwrap().clone()
+    }
+
+    /// How many configuration writes went through so far
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    /// Change the configuration behind the back of the host, like the knob on the device does
+    ///
+    /// Whether the host finds out before its next poll is up to [`MockDevice::send_interrupt`].
+    pub fn set_config(&self, buf: &[u8]) {
+        self.buf.lock().unwrap().copy_from_slice(buf);
+    }
+
+    /// Send an (empty) report on the interrupt endpoint, to the current or the next waiter
+    pub fn send_interrupt(&self) {
+        self.changed.notify_one();
+    }
+
+    fn take_fault(&self, write: bool) -> Option<Fault> {
+        let mut faults = self.faults.lock().unwrap();
+        let fault = match faults.front() {
+            Some(fault) if write && fault.affects_writes() => faults.pop_front(),
+            Some(fault) if !write && fault.affects_reads() => faults.pop_front(),
+            _ => None,
+        };
+        let mut disconnects = self.disconnects.lock().unwrap();
+        if let Some(Fault::RandomDisconnects { percent }) = fault {
+            *disconnects = Some(RandomDisconnects::new(percent));
+        }
+        match fault {
+            None | Some(Fault::RandomDisconnects { .. }) => disconnects
+                .as_mut()
+                .is_some_and(RandomDisconnects::next)
+                .then_some(Fault::Disconnect),
+            fault => fault,
+        }
+    }
+
+    pub(crate) fn quirks(&self) -> &'static Quirks {
+        self.quirks
+    }
+
+    pub(crate) fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            model: format!("{} (mock)", self.quirks.capabilities.model),
+            manufacturer: Some("tidal-wave".to_owned()),
+            serial: Some("MOCK".to_owned()),
+            vendor_id: format!("{:04x}", self.quirks.vendor_id),
+            product_id: format!("{:04x}", self.quirks.product_id),
+            usb_version: "2.0.0".to_owned(),
+            firmware: "0.0.0".to_owned(),
+            bus_id: "mock".to_owned(),
+            address: 0,
+            interface: 0,
+        }
+    }
+
+    pub(crate) async fn control_in(
+        &self,
+        control: ControlIn,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, TransferError> {
+        if !self.is_config_request(control.control_type, control.request, control.index, true) {
+            return Err(TransferError::Stall);
+        }
+        let mut buf = self.config();
+        buf.truncate(control.length.into());
+        match self.take_fault(false) {
+            Some(Fault::Timeout) => {
+                sleep(timeout).await;
+                return Err(TransferError::Cancelled);
+            }
+            Some(Fault::ShortRead) => buf.truncate(buf.len() / 2),
+            Some(Fault::InvalidBool) => {
+                let layout = &self.quirks.layouts[0];
+                if let Some(byte) = buf.get_mut(layout.mute) {
+                    *byte = 0x02;
+                }
+            }
+            Some(Fault::Stall) => return Err(TransferError::Stall),
+            Some(Fault::Corrupt { offset }) => {
+                if let Some(byte) = buf.get_mut(offset) {
+                    *byte ^= 0xff;
+                }
+            }
+            Some(Fault::Disconnect) => return Err(TransferError::Disconnected),
+            Some(Fault::Ignore | Fault::RandomDisconnects { .. }) | None => {}
+        }
+        Ok(buf)
+    }
+
+    /// Writes the whole buffer, and also stores it for persistent writes
+    pub(crate) async fn control_out(
+        &self,
+        control: ControlOut<'_>,
+        timeout: Duration,
+    ) -> Result<(), TransferError> {
+        if !self.is_config_request(control.control_type, control.request, control.index, false)
+            || control.data.len() != self.buf.lock().unwrap().len()
+        {
+            return Err(TransferError::Stall);
+        }
+        match self.take_fault(true) {
+            Some(Fault::Timeout) => {
+                sleep(timeout).await;
+                return Err(TransferError::Cancelled);
+            }
+            Some(Fault::Stall) => return Err(TransferError::Stall),
+            Some(Fault::Ignore) => return Ok(()),
+            Some(Fault::Disconnect) => return Err(TransferError::Disconnected),
+            _ => {}
+        }
+        self.set_config(control.data);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        if control.value == Mode::Persistant as u16 {
+            self.stored.lock().unwrap().copy_from_slice(control.data);
+        }
+        Ok(())
+    }
+
+    /// Wait for the next [`MockDevice::send_interrupt`]
+    pub(crate) async fn interrupt(&self) -> Vec<u8> {
+        self.changed.notified().await;
+        Vec::new()
+    }
+
+    fn is_config_request(
+        &self,
+        control_type: ControlType,
+        request: u8,
+        index: u16,
+        read: bool,
+    ) -> bool {
+        let expected = match read {
+            true => self.quirks.read_request,
+            false => self.quirks.write_request,
+        };
+        control_type == ControlType::Class && request == expected && index == self.quirks.index
+    }
+}
+
+impl Default for MockDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Transfers see the same faults as through a [`Device`](crate::Device), but aren't retried
+impl DeviceBackend for Arc<MockDevice> {
+    async fn read_config(&self, timeout: Duration) -> Result<DeviceConfiguration> {
+        let layout = &self.quirks.layouts[0];
+        let control = ControlIn {
+            control_type: ControlType::Class,
+            recipient: Recipient::Endpoint,
+            request: self.quirks.read_request,
+            value: 0x0000,
+            index: self.quirks.index,
+            length: layout.length as u16,
+        };
+        let buf = MockDevice::control_in(self, control, timeout).await?;
+        layout
+            .check(&buf, 0)
+            .and_then(|()| DeviceConfiguration::read(&buf, layout))
+            .map_err(|err| ProtocolError(format!("{err:#}")).into())
+    }
+
+    async fn write_config(
+        &self,
+        config: &DeviceConfiguration,
+        mode: Mode,
+        timeout: Duration,
+    ) -> Result<()> {
+        let mut buf = self.config();
+        config.write(&mut buf, &self.quirks.layouts[0]);
+        self.write_buffer(&buf, mode, timeout).await
+    }
+
+    async fn write_buffer(&self, buf: &[u8], mode: Mode, timeout: Duration) -> Result<()> {
+        let length = self.quirks.layouts[0].length;
+        if buf.len() != length {
+            bail!("config buffer must be {length} bytes, got {}", buf.len());
+        }
+        let control = ControlOut {
+            control_type: ControlType::Class,
+            recipient: Recipient::Endpoint,
+            request: self.quirks.write_request,
+            value: mode as _,
+            index: self.quirks.index,
+            data: buf,
+        };
+        Ok(MockDevice::control_out(self, control, timeout).await?)
+    }
+
+    fn info(&self) -> Result<DeviceInfo> {
+        Ok(MockDevice::info(self))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.quirks.capabilities.clone()
+    }
+
+    fn last_buffer(&self) -> Option<Vec<u8>> {
+        Some(self.config())
+    }
+
+    async fn control_in(&self, control: ControlIn, timeout: Duration) -> Result<Vec<u8>> {
+        Ok(MockDevice::control_in(self, control, timeout).await?)
+    }
+
+    async fn control_out(&self, control: ControlOut<'_>, timeout: Duration) -> Result<()> {
+        Ok(MockDevice::control_out(self, control, timeout).await?)
+    }
+}
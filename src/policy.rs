@@ -0,0 +1,281 @@
+use crate::{
+    event::Event,
+    ui_state::{Decibel, Line},
+    usb_device::DeviceConfiguration,
+};
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+
+/// Rules applied to every change requested on stdin before it is written to the device
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// Change the gain even if gain lock is enabled on the device
+    pub override_gain_lock: bool,
+
+    /// What to do when phantom power and low impedance mode would be enabled at the same time
+    pub phantom_lim: ConflictPolicy,
+
+    /// Turn on clipguard when the gain is set above a threshold
+    pub auto_clipguard: Option<AutoClipguard>,
+}
+
+#[derive(Debug)]
+pub struct AutoClipguard {
+    /// Gain in device units above which clipguard gets enabled
+    threshold: u16,
+    /// Turn clipguard off again once the gain is set back to or below the threshold
+    release: bool,
+    /// Whether clipguard is currently on because of this rule, so it's only ever released when
+    /// it was enabled automatically
+    engaged: bool,
+}
+
+impl AutoClipguard {
+    pub fn new(threshold: u16, release: bool) -> Self {
+        Self {
+            threshold,
+            release,
+            engaged: false,
+        }
+    }
+
+    fn apply(
+        &mut self,
+        current: &DeviceConfiguration,
+        new: &mut DeviceConfiguration,
+        line: &Line,
+    ) -> Option<Event> {
+        // Explicitly setting clipguard always takes precedence
+        if line.clipguard.is_some() {
+            self.engaged = false;
+            return None;
+        }
+        if new.gain == current.gain {
+            return None;
+        }
+
+        let gain = Decibel(new.gain);
+        let threshold = Decibel(self.threshold);
+        if new.gain > self.threshold && !new.clipguard {
+            new.clipguard = true;
+            self.engaged = true;
+            Some(Event::Policy {
+                rule: "auto_clipguard",
+                reason: format!("enabled clipguard because gain {gain} is above {threshold}"),
+            })
+        } else if new.gain <= self.threshold && self.release && self.engaged && new.clipguard {
+            new.clipguard = false;
+            self.engaged = false;
+            Some(Event::Policy {
+                rule: "auto_clipguard",
+                reason: format!(
+                    "disabled clipguard because gain {gain} is back at or below {threshold}"
+                ),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Allow the change silently
+    Off,
+    /// Allow the change, but emit a warning event
+    #[default]
+    Warn,
+    /// Reject the change
+    Refuse,
+}
+
+impl Policy {
+    /// Check whether going from `current` to `new` is allowed and apply automatic adjustments
+    ///
+    /// Returns events for changes that are allowed, but should be brought to the user's attention,
+    /// and for changes made by the rules themselves.
+    pub fn apply(
+        &mut self,
+        current: &DeviceConfiguration,
+        new: &mut DeviceConfiguration,
+        line: &Line,
+    ) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+
+        // Unlocking and changing the gain in the same line is fine
+        let locked = current.gain_lock && new.gain_lock;
+        let override_lock = self.override_gain_lock || line.override_lock.unwrap_or(false);
+        if locked && current.gain != new.gain && !override_lock {
+            bail!(
+                "gain is locked on the device, unlock it with \"gain_lock\": false or set \"override_lock\": true"
+            );
+        }
+
+        // Only complain when the change introduces the combination, so a device already in that
+        // state can still be changed otherwise
+        if new.phantom && new.lim && !(current.phantom && current.lim) {
+            let message = "phantom power and low impedance mode enabled at the same time";
+            match self.phantom_lim {
+                ConflictPolicy::Off => {}
+                ConflictPolicy::Warn => events.push(Event::Warning {
+                    message: message.to_owned(),
+                }),
+                ConflictPolicy::Refuse => bail!("refusing to enable {message}"),
+            }
+        }
+
+        if let Some(auto_clipguard) = &mut self.auto_clipguard {
+            events.extend(auto_clipguard.apply(current, new, line));
+        }
+
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DB: u16 = 256;
+
+    fn apply(
+        policy: &mut Policy,
+        current: &DeviceConfiguration,
+        change: impl FnOnce(&mut DeviceConfiguration),
+        line: &Line,
+    ) -> Result<(DeviceConfiguration, Vec<Event>)> {
+        let mut new = *current;
+        change(&mut new);
+        let events = policy.apply(current, &mut new, line)?;
+        Ok((new, events))
+    }
+
+    #[test]
+    fn refuses_gain_changes_while_locked() {
+        let locked = DeviceConfiguration {
+            gain: 10 * DB,
+            gain_lock: true,
+            ..Default::default()
+        };
+        let raise = |new: &mut DeviceConfiguration| new.gain = 20 * DB;
+        let line = Line::default();
+
+        let err = apply(&mut Policy::default(), &locked, raise, &line).unwrap_err();
+        assert!(err.to_string().contains("gain is locked"), "{err:#}");
+
+        let override_line = Line {
+            override_lock: Some(true),
+            ..Default::default()
+        };
+        assert!(apply(&mut Policy::default(), &locked, raise, &override_line).is_ok());
+        let mut overriding = Policy {
+            override_gain_lock: true,
+            ..Default::default()
+        };
+        assert!(apply(&mut overriding, &locked, raise, &line).is_ok());
+
+        let unlock = |new: &mut DeviceConfiguration| {
+            new.gain_lock = false;
+            new.gain = 20 * DB;
+        };
+        assert!(apply(&mut Policy::default(), &locked, unlock, &line).is_ok());
+        let mix = |new: &mut DeviceConfiguration| new.mix = 80;
+        assert!(apply(&mut Policy::default(), &locked, mix, &line).is_ok());
+    }
+
+    #[test]
+    fn handles_phantom_power_with_low_impedance_as_configured() {
+        let lim = DeviceConfiguration {
+            lim: true,
+            ..Default::default()
+        };
+        let phantom = |new: &mut DeviceConfiguration| new.phantom = true;
+        let line = Line::default();
+        let policy = |phantom_lim| Policy {
+            phantom_lim,
+            ..Default::default()
+        };
+
+        let (_, events) = apply(&mut policy(ConflictPolicy::Off), &lim, phantom, &line).unwrap();
+        assert!(events.is_empty());
+        let (new, events) = apply(&mut policy(ConflictPolicy::Warn), &lim, phantom, &line).unwrap();
+        assert!(new.phantom);
+        assert!(matches!(events[..], [Event::Warning { .. }]), "{events:?}");
+        assert!(apply(&mut policy(ConflictPolicy::Refuse), &lim, phantom, &line).is_err());
+
+        // A device already in that state can still be changed otherwise
+        let both = DeviceConfiguration {
+            phantom: true,
+            ..lim
+        };
+        let mute = |new: &mut DeviceConfiguration| new.mute = true;
+        let (_, events) = apply(&mut policy(ConflictPolicy::Refuse), &both, mute, &line).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn enables_clipguard_above_the_threshold() {
+        let mut policy = Policy {
+            auto_clipguard: Some(AutoClipguard::new(50 * DB, true)),
+            ..Default::default()
+        };
+        let line = Line::default();
+        let gain = |gain| move |new: &mut DeviceConfiguration| new.gain = gain;
+
+        let quiet = DeviceConfiguration {
+            gain: 40 * DB,
+            ..Default::default()
+        };
+        let (loud, events) = apply(&mut policy, &quiet, gain(60 * DB), &line).unwrap();
+        assert!(loud.clipguard);
+        assert!(
+            matches!(
+                events[..],
+                [Event::Policy {
+                    rule: "auto_clipguard",
+                    ..
+                }]
+            ),
+            "{events:?}"
+        );
+
+        let (new, events) = apply(&mut policy, &loud, gain(50 * DB), &line).unwrap();
+        assert!(!new.clipguard);
+        assert_eq!(events.len(), 1);
+
+        // Clipguard turned on by hand is left alone
+        let manual = DeviceConfiguration {
+            clipguard: true,
+            ..loud
+        };
+        let (new, events) = apply(&mut policy, &manual, gain(40 * DB), &line).unwrap();
+        assert!(new.clipguard && events.is_empty());
+
+        // As is an explicit clipguard in the same line
+        let explicit = Line {
+            clipguard: Some(false),
+            ..Default::default()
+        };
+        let (new, events) = apply(&mut policy, &quiet, gain(60 * DB), &explicit).unwrap();
+        assert!(!new.clipguard && events.is_empty());
+    }
+
+    #[test]
+    fn keeps_clipguard_on_without_release() {
+        let mut policy = Policy {
+            auto_clipguard: Some(AutoClipguard::new(50 * DB, false)),
+            ..Default::default()
+        };
+        let line = Line::default();
+        let (loud, _) = apply(
+            &mut policy,
+            &DeviceConfiguration::default(),
+            |new| new.gain = 60 * DB,
+            &line,
+        )
+        .unwrap();
+
+        let (new, events) = apply(&mut policy, &loud, |new| new.gain = 0, &line).unwrap();
+        assert!(new.clipguard && events.is_empty());
+    }
+}
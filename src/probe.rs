@@ -0,0 +1,351 @@
+//! Probing control requests to discover more of the protocol
+//!
+//! Only device-to-host requests of the class and vendor types are ever sent. That they answer with
+//! data doesn't make them harmless: the USB spec only defines what standard requests do, a class or
+//! vendor request may change the device state however its firmware likes. Class requests next to
+//! the ones reading the configuration are what probing is for. Vendor requests are where firmwares
+//! tend to put updates and factory resets, so probing them has to be allowed explicitly.
+//!
+//! Comparing the responses before and after changing a setting on the device (or in Wave Link)
+//! shows which requests report it.
+
+use crate::{
+    raw,
+    usb_device::{self, Device, DeviceOptions},
+};
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use nusb::transfer::{ControlIn, ControlType};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, fs, path::Path, str::FromStr, time::Duration};
+
+/// Maximum number of requests a single probe may send
+const MAX_REQUESTS: usize = 4096;
+
+/// Timeout of each request, short since most of them are expected to stall
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// Only in USB session recordings, never probed
+    #[value(skip)]
+    Standard,
+    Class,
+    Vendor,
+}
+
+impl From<Kind> for ControlType {
+    fn from(kind: Kind) -> Self {
+        match kind {
+            Kind::Standard => Self::Standard,
+            Kind::Class => Self::Class,
+            Kind::Vendor => Self::Vendor,
+        }
+    }
+}
+
+impl From<ControlType> for Kind {
+    fn from(control_type: ControlType) -> Self {
+        match control_type {
+            ControlType::Standard => Self::Standard,
+            ControlType::Class => Self::Class,
+            ControlType::Vendor => Self::Vendor,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recipient {
+    Device,
+    Interface,
+    /// Like the configuration requests
+    #[default]
+    Endpoint,
+    Other,
+}
+
+impl From<Recipient> for nusb::transfer::Recipient {
+    fn from(recipient: Recipient) -> Self {
+        match recipient {
+            Recipient::Device => Self::Device,
+            Recipient::Interface => Self::Interface,
+            Recipient::Endpoint => Self::Endpoint,
+            Recipient::Other => Self::Other,
+        }
+    }
+}
+
+impl From<nusb::transfer::Recipient> for Recipient {
+    fn from(recipient: nusb::transfer::Recipient) -> Self {
+        match recipient {
+            nusb::transfer::Recipient::Device => Self::Device,
+            nusb::transfer::Recipient::Interface => Self::Interface,
+            nusb::transfer::Recipient::Endpoint => Self::Endpoint,
+            nusb::transfer::Recipient::Other => Self::Other,
+        }
+    }
+}
+
+/// Inclusive range of numbers like `0x80-0x8f`, or a single number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    start: u16,
+    end: u16,
+}
+
+impl FromStr for Span {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn number(s: &str) -> Result<u16, String> {
+            let s = s.trim();
+            match s.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => s.parse(),
+            }
+            .map_err(|err| format!("invalid number {s:?}: {err}"))
+        }
+
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (number(start)?, number(end)?),
+            None => (number(s)?, number(s)?),
+        };
+        if start > end {
+            return Err(format!("empty range {s:?}"));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl Span {
+    fn len(self) -> usize {
+        usize::from(self.end - self.start) + 1
+    }
+}
+
+/// Parameters of the requests to send
+pub struct Probe {
+    pub kind: Kind,
+    pub recipient: Recipient,
+    pub requests: Span,
+    pub values: Span,
+    pub indexes: Span,
+    pub length: u16,
+    /// Pause between requests, so a slow firmware isn't flooded
+    pub interval: Duration,
+    /// Whether requests of [`Kind::Vendor`] may be sent
+    pub allow_vendor: bool,
+}
+
+impl Probe {
+    /// The first and last request number, unless the probe is refused
+    fn check(&self) -> Result<(u8, u8)> {
+        if self.kind == Kind::Vendor && !self.allow_vendor {
+            bail!(
+                "vendor requests may change the device state in unknown ways, \
+                 pass --allow-vendor to probe them anyway"
+            );
+        }
+        let count = self.requests.len() * self.values.len() * self.indexes.len();
+        if count > MAX_REQUESTS {
+            bail!("refusing to send {count} requests, narrow the ranges to at most {MAX_REQUESTS}");
+        }
+        u8::try_from(self.requests.start)
+            .ok()
+            .zip(u8::try_from(self.requests.end).ok())
+            .context("requests must be at most 0xff")
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Response {
+    request: u8,
+    value: u16,
+    index: u16,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    /// Hex encoded response data
+    Data(String),
+    Error(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Data(data) if data.is_empty() => f.write_str("(empty)"),
+            Outcome::Data(data) => f.write_str(data),
+            Outcome::Error(err) => write!(f, "error: {err}"),
+        }
+    }
+}
+
+/// Send every combination of request, value and index and print the responses as JSON lines
+///
+/// With `diff`, only the responses that differ from those of an earlier probe are printed.
+pub async fn probe(options: &DeviceOptions, probe: Probe, diff: Option<&Path>) -> Result<()> {
+    let requests = probe.check()?;
+    let Probe {
+        kind,
+        recipient,
+        values,
+        indexes,
+        length,
+        interval,
+        ..
+    } = probe;
+
+    let old = match diff {
+        Some(path) => Some(load(path)?),
+        None => None,
+    };
+
+    let device = Device::try_initialize(options).await?;
+    for request in requests.0..=requests.1 {
+        for value in values.start..=values.end {
+            for index in indexes.start..=indexes.end {
+                let control = ControlIn {
+                    control_type: kind.into(),
+                    recipient: recipient.into(),
+                    request,
+                    value,
+                    index,
+                    length,
+                };
+                let outcome = match device.control_in(control, TIMEOUT).await {
+                    Ok(data) => Outcome::Data(raw::hex(&data)),
+                    Err(err) if usb_device::is_disconnected(&err) => {
+                        return Err(err.context(format!(
+                            "device went away at request={request:#04x} value={value:#06x} \
+                             index={index:#06x}"
+                        )));
+                    }
+                    Err(err) => Outcome::Error(format!("{err:#}")),
+                };
+                tokio::time::sleep(interval).await;
+                let response = Response {
+                    request,
+                    value,
+                    index,
+                    outcome,
+                };
+
+                match &old {
+                    None => println!("{}", serde_json::to_string(&response)?),
+                    Some(old) => match old.get(&(request, value, index)) {
+                        Some(before) if *before == response.outcome => {}
+                        before => println!(
+                            "request={request:#04x} value={value:#06x} index={index:#06x}: {} -> {}",
+                            before.map_or("(not probed)".to_owned(), ToString::to_string),
+                            response.outcome,
+                        ),
+                    },
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn load(path: &Path) -> Result<BTreeMap<(u8, u16, u16), Outcome>> {
+    let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let Response {
+                request,
+                value,
+                index,
+                outcome,
+            } = serde_json::from_str(line)
+                .with_context(|| format!("{}:{}", path.display(), i + 1))?;
+            Ok(((request, value, index), outcome))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: u16, end: u16) -> Span {
+        Span { start, end }
+    }
+
+    fn probe(kind: Kind, requests: Span, allow_vendor: bool) -> Probe {
+        Probe {
+            kind,
+            recipient: Recipient::Endpoint,
+            requests,
+            values: span(0, 0),
+            indexes: span(0x3300, 0x3300),
+            length: 64,
+            interval: Duration::ZERO,
+            allow_vendor,
+        }
+    }
+
+    #[test]
+    fn parses_spans() {
+        for (s, expected) in [
+            ("0x80-0x8f", span(0x80, 0x8f)),
+            ("128-143", span(128, 143)),
+            ("0x3300", span(0x3300, 0x3300)),
+            (" 0x80 - 0x81 ", span(0x80, 0x81)),
+            ("0-0xffff", span(0, 0xffff)),
+        ] {
+            assert_eq!(s.parse::<Span>(), Ok(expected), "{s}");
+        }
+        assert_eq!(span(0, 0xffff).len(), 0x10000);
+    }
+
+    #[test]
+    fn rejects_malformed_spans() {
+        for s in [
+            "",
+            "0x",
+            "0x8f-0x80",
+            "0x80-",
+            "-1",
+            "0x10000",
+            "0x80-0x8f-0x90",
+            "eighty",
+        ] {
+            assert!(s.parse::<Span>().is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn refuses_vendor_requests_unless_allowed() {
+        let requests = span(0x80, 0x8f);
+        assert_eq!(
+            probe(Kind::Class, requests, false).check().unwrap(),
+            (0x80, 0x8f)
+        );
+        assert!(probe(Kind::Vendor, requests, false).check().is_err());
+        assert_eq!(
+            probe(Kind::Vendor, requests, true).check().unwrap(),
+            (0x80, 0x8f)
+        );
+    }
+
+    #[test]
+    fn refuses_too_many_or_too_large_requests() {
+        let mut wide = probe(Kind::Class, span(0, 0xff), false);
+        wide.values = span(0, 0xff);
+        assert!(wide.check().is_err());
+        assert!(
+            probe(Kind::Class, span(0xff, 0x100), false)
+                .check()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Profiles are TOML files containing a subset of the fields of a [`Line`]
+//!
+//! ```toml
+//! gain = "45dB"
+//! phantom = true
+//! color_gen = ${accent_color}
+//! ```
+//!
+//! `${name}` is replaced with the variable `name` from the environment or the `[vars]` of the
+//! [`Config`] before parsing, `$$` with a literal `$`. Settings in the `[host."<hostname>"]` table
+//! of the [`Config`] for the current host take precedence over the profile.
+
+use crate::{
+    compat,
+    config::Config,
+    state_bus::StateBus,
+    stdio,
+    ui_state::{Line, UiState},
+    usb_device::{Device, DeviceConfiguration, DeviceOptions, Mode},
+    watchdog,
+};
+use anyhow::{Context, Result, bail};
+use serde_json::Value;
+use std::{fs, path::Path, process::ExitCode};
+
+/// Fields setting the same value in different representations
+const EQUIVALENT_FIELDS: &[&[&str]] = &[
+    &["gain", "gain_raw"],
+    &["volume", "volume_raw"],
+    &["mix", "balance"],
+];
+
+pub fn load(path: &Path, config: &Config) -> Result<Line> {
+    let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let text =
+        substitute(&text, config).with_context(|| format!("expanding {}", path.display()))?;
+    let mut line: Value =
+        toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+    compat::migrate(&mut line).with_context(|| format!("reading {}", path.display()))?;
+
+    if let (Value::Object(fields), Some(overrides)) = (&mut line, config.host_overrides()) {
+        let mut overrides = Value::Object(overrides.clone());
+        compat::migrate(&mut overrides).context("reading the host overrides")?;
+        if let Value::Object(overrides) = overrides {
+            for group in EQUIVALENT_FIELDS {
+                if group.iter().any(|field| overrides.contains_key(*field)) {
+                    for field in *group {
+                        fields.remove(*field);
+                    }
+                }
+            }
+            fields.extend(overrides);
+        }
+    }
+
+    serde_json::from_value(line).with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Replace `${name}` with the value of the variable `name`
+fn substitute(text: &str, config: &Config) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').context("unterminated `${`")?;
+            let name = &after[..end];
+            let value = config
+                .var(name)
+                .with_context(|| format!("undefined variable `{name}`"))?;
+            out.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Fields that differ between two configurations, as `(field, a, b)`
+pub fn diff(
+    a: &DeviceConfiguration,
+    b: &DeviceConfiguration,
+) -> Result<Vec<(String, Value, Value)>> {
+    let (Value::Object(a), Value::Object(mut b)) =
+        (serde_json::to_value(a)?, serde_json::to_value(b)?)
+    else {
+        unreachable!("DeviceConfiguration serializes to an object");
+    };
+
+    Ok(a.into_iter()
+        .filter_map(|(field, a)| {
+            let b = b.remove(&field)?;
+            (a != b).then_some((field, a, b))
+        })
+        .collect())
+}
+
+/// Print the fields on the device that differ from the profile and optionally apply it
+///
+/// Exits with 1 if there are differences that weren't applied, like `diff(1)`. Applying reads the
+/// config back and fails if the device didn't take all of the profile.
+pub async fn diff_command(
+    options: &DeviceOptions,
+    path: &Path,
+    config: &Config,
+    apply: bool,
+    persistent: bool,
+    mut state: UiState,
+) -> Result<ExitCode> {
+    let profile = load(path, config)?;
+
+    let device = Device::try_initialize(options).await?;
+    let timeout = device.timeouts().read;
+    let config = watchdog::guard(&device, "read_config", timeout, || {
+        device.read_config(timeout)
+    })
+    .await?;
+    state.set_cached(state.regular(config));
+
+    let current = state.cached;
+    let target = state.update_state(profile)?;
+    for event in state.events.drain(..) {
+        log::warn!("{}", serde_json::to_string(&event)?);
+    }
+
+    let diff = diff(&current, &target)?;
+    for (field, device, profile) in &diff {
+        println!("{field}: {device} -> {profile}");
+    }
+
+    if diff.is_empty() {
+        return Ok(ExitCode::SUCCESS);
+    }
+    if !apply {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let mode = match persistent {
+        true => Mode::Persistant,
+        false => Mode::Temporary,
+    };
+    // The same way every other write goes, with dimming applied to the colors, stuck transfers
+    // recovered and the result read back
+    let target = state.outgoing(target);
+    let state = StateBus::spawn(state);
+    let rejected = stdio::write_verified(&device, &state, &target, mode, true).await?;
+    if !rejected.is_empty() {
+        bail!("the device didn't take {}", stdio::names(&rejected));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        for (name, value) in [
+            ("tidal_wave_test_color", "\"#ff8800\""),
+            ("tidal_wave_test_gain", "45dB"),
+        ] {
+            config.vars.insert(name.to_owned(), value.to_owned());
+        }
+        config
+    }
+
+    #[test]
+    fn substitutes_variables() {
+        let config = config();
+        for (text, expected) in [
+            (
+                "color_gen = ${tidal_wave_test_color}",
+                "color_gen = \"#ff8800\"",
+            ),
+            ("gain = \"${tidal_wave_test_gain}\"", "gain = \"45dB\""),
+            ("no variables", "no variables"),
+            // `$$` escapes, a lone `$` stays as it is
+            ("$${tidal_wave_test_gain}", "${tidal_wave_test_gain}"),
+            ("cost = \"5$\"", "cost = \"5$\""),
+            ("$$$", "$$"),
+        ] {
+            assert_eq!(substitute(text, &config).unwrap(), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn rejects_undefined_and_malformed_variables() {
+        let config = config();
+        for text in [
+            "${tidal_wave_test_missing}",
+            "${tidal_wave_test_gain",
+            // Variables don't nest, the inner `${` is part of the name
+            "${tidal_wave_${tidal_wave_test_gain}}",
+        ] {
+            assert!(substitute(text, &config).is_err(), "{text}");
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Following the colors generated by pywal
+//!
+//! ```toml
+//! [pywal]
+//! color = "color4"
+//! ```
+//!
+//! Watches pywal's `colors.json` and sets `color_gen` to the chosen color whenever it changes.
+//! Only active in the stdio mode.
+
+use crate::{
+    backend::DeviceBackend, state_bus::StateBus, stdio, ui_state::Line, usb_device::Color,
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::time::sleep;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pywal {
+    /// Path of the colors file [default: ~/.cache/wal/colors.json]
+    pub path: Option<PathBuf>,
+    /// Which color to use, `color0` to `color15` or one of the special colors like `foreground`
+    #[serde(default = "default_color")]
+    pub color: String,
+}
+
+fn default_color() -> String {
+    "color1".to_owned()
+}
+
+/// The parts of pywal's `colors.json` that are of interest
+#[derive(Debug, Deserialize)]
+struct Colors {
+    special: BTreeMap<String, Color>,
+    colors: BTreeMap<String, Color>,
+}
+
+impl Pywal {
+    fn read(&self, path: &Path) -> Result<Color> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let Colors { special, colors } =
+            serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+        colors
+            .get(&self.color)
+            .or_else(|| special.get(&self.color))
+            .copied()
+            .with_context(|| format!("{} has no color `{}`", path.display(), self.color))
+    }
+}
+
+/// Set `color_gen` from the colors file every time it changes
+pub async fn run<D: DeviceBackend>(pywal: Pywal, device: D, state: StateBus) {
+    let Some(path) = pywal
+        .path
+        .clone()
+        .or_else(|| dirs::cache_dir().map(|dir| dir.join("wal/colors.json")))
+    else {
+        log::warn!("pywal: no cache directory, not following its colors");
+        return;
+    };
+    let mut last_modified: Option<SystemTime> = None;
+
+    loop {
+        // Not existing yet is fine, pywal might just not have run so far
+        if let Ok(modified) = fs::metadata(&path).and_then(|meta| meta.modified())
+            && last_modified != Some(modified)
+        {
+            last_modified = Some(modified);
+
+            let res = async {
+                let color = pywal.read(&path)?;
+                log::info!("pywal: switching to {color}");
+                let line = Line {
+                    color_gen: Some(color),
+                    ..Line::default()
+                };
+                stdio::apply(&device, &state, line).await
+            }
+            .await;
+            if let Err(err) = res {
+                let err = format!("{err:#}");
+                state.update(|state| state.io.err = Some(err));
+            }
+        }
+
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn reads_colors_and_special_colors() {
+        let path = env::temp_dir().join(format!("tidal-wave-pywal-{}.json", process::id()));
+        fs::write(
+            &path,
+            r##"{
+                "wallpaper": "/home/user/wall.png",
+                "special": {"background": "#0b0c10", "foreground": "#c5c6c7"},
+                "colors": {"color0": "#0b0c10", "color4": "#66fcf1"}
+            }"##,
+        )
+        .unwrap();
+        let pywal = |color: &str| Pywal {
+            path: None,
+            color: color.to_owned(),
+        };
+
+        let color4 = pywal("color4").read(&path);
+        let foreground = pywal("foreground").read(&path);
+        let missing = pywal("color9").read(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(color4.unwrap(), Color([0x66, 0xfc, 0xf1]));
+        assert_eq!(foreground.unwrap(), Color([0xc5, 0xc6, 0xc7]));
+        let err = missing.unwrap_err();
+        assert!(err.to_string().contains("no color `color9`"), "{err:#}");
+        assert!(pywal("color4").read(&path).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! Everything that differs between supported hardware
+//!
+//! Adding a hardware revision that speaks the same protocol is a new entry in [`ALL`], not a
+//! change to [`DeviceConfiguration::read`](crate::DeviceConfiguration) and friends.
+
+use crate::capabilities::Capabilities;
+use anyhow::{Result, bail};
+use std::ops::{Range, RangeInclusive};
+
+/// How to find, talk to and decode one kind of device
+#[derive(Debug)]
+pub struct Quirks {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Class, subclass and protocol of the vendor interface
+    pub interface: [u8; 3],
+    /// Class request reading the configuration
+    pub read_request: u8,
+    /// Class request writing the configuration, with the [`Mode`](crate::Mode) as value
+    pub write_request: u8,
+    pub index: u16,
+    /// Layouts of the configuration buffer, the first one matching the firmware is used
+    pub layouts: &'static [Layout],
+    pub capabilities: Capabilities,
+}
+
+/// Byte offsets of the fields in the configuration buffer
+///
+/// Offsets are named after the field of [`DeviceConfiguration`](crate::DeviceConfiguration) they
+/// hold, which is how reading and writing find them. Multi-byte values are little endian.
+#[derive(Debug)]
+pub struct Layout {
+    /// Firmware versions, from `bcdDevice`, using this layout, `None` for any
+    ///
+    /// Should a firmware update change the layout, the old one gets the range of versions it was
+    /// seen with and the new one is added in front of it.
+    pub firmware: Option<RangeInclusive<u16>>,
+    /// Length of the configuration buffer
+    pub length: usize,
+    pub gain: usize,
+    pub mute: usize,
+    pub clipguard: usize,
+    pub phantom: usize,
+    pub lowcut: usize,
+    pub volume: usize,
+    pub mix: usize,
+    /// A flag derived from the mix, which the device ignores when reading
+    pub mix_flag: usize,
+    pub color_mute: usize,
+    /// The general color is repeated at each of these offsets, it's read from the first
+    pub color_gen: &'static [usize],
+    pub gain_lock: usize,
+    pub color_gain_reduction: usize,
+    pub clipguard_indicator: usize,
+    pub lim: usize,
+    /// Fields whose meaning isn't known yet, see [`UnknownField`]
+    pub unknown: &'static [UnknownField],
+    /// Byte ranges not decoded into any field, which are watched for changes
+    pub undecoded: &'static [Range<usize>],
+}
+
+/// Bytes of the configuration buffer that clearly encode something, but nobody knows what yet
+///
+/// They are reported and can be set under their provisional name with `--experimental`. Once the
+/// meaning of one is confirmed, it becomes a proper field of
+/// [`DeviceConfiguration`](crate::DeviceConfiguration).
+#[derive(Debug)]
+pub struct UnknownField {
+    pub name: &'static str,
+    pub offset: usize,
+    pub length: usize,
+    /// What is written unless set otherwise, `None` if derived from other fields
+    pub default: Option<&'static [u8]>,
+}
+
+impl UnknownField {
+    pub fn read<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        &buf[self.offset..][..self.length]
+    }
+}
+
+/// Layout of the Wave XLR, as documented in `usb_elgato_wave_xlr.lua`
+pub const WAVE_XLR_LAYOUT: Layout = Layout {
+    firmware: None,
+    length: 34,
+    gain: 0,
+    mute: 4,
+    clipguard: 5,
+    phantom: 6,
+    lowcut: 7,
+    volume: 9,
+    mix: 13,
+    mix_flag: 12,
+    color_mute: 15,
+    color_gen: &[18, 21, 24],
+    gain_lock: 28,
+    color_gain_reduction: 29,
+    clipguard_indicator: 32,
+    lim: 33,
+    unknown: &[
+        UnknownField {
+            name: "word_2",
+            offset: 2,
+            length: 2,
+            default: Some(&[0x00, 0xec]),
+        },
+        UnknownField {
+            name: "byte_11",
+            offset: 11,
+            length: 1,
+            default: Some(&[0x00]),
+        },
+        UnknownField {
+            name: "mix_flag",
+            offset: 12,
+            length: 1,
+            default: None,
+        },
+        UnknownField {
+            name: "byte_14",
+            offset: 14,
+            length: 1,
+            default: Some(&[0x01]),
+        },
+        UnknownField {
+            name: "byte_27",
+            offset: 27,
+            length: 1,
+            default: Some(&[0x01]),
+        },
+    ],
+    // 12 is derived from the mix, but reading ignores it, so changes to it would go
+    // unnoticed otherwise. 21-26 repeat the general color and change along with it, so they
+    // aren't included.
+    undecoded: &[2..4, 11..13, 14..15, 27..28],
+};
+
+pub const WAVE_XLR: Quirks = Quirks {
+    vendor_id: 0x0FD9,
+    product_id: 0x007D,
+    interface: [0xFF, 0xF0, 0x00],
+    read_request: 0x85,
+    write_request: 0x05,
+    index: 0x3300,
+    layouts: &[WAVE_XLR_LAYOUT],
+    capabilities: Capabilities::WAVE_XLR,
+};
+
+/// Every supported kind of device
+pub const ALL: &[Quirks] = &[WAVE_XLR];
+
+impl Quirks {
+    /// The layout used by the given firmware version
+    pub fn layout(&self, firmware: u16) -> Result<&Layout> {
+        match self.layouts.iter().find(|layout| {
+            layout
+                .firmware
+                .as_ref()
+                .is_none_or(|range| range.contains(&firmware))
+        }) {
+            Some(layout) => Ok(layout),
+            None => bail!("unknown config layout for firmware {firmware:#06x}"),
+        }
+    }
+}
+
+impl Layout {
+    /// Fail on a buffer of the wrong length rather than misparsing it
+    pub fn check(&self, buf: &[u8], firmware: u16) -> Result<()> {
+        if buf.len() != self.length {
+            bail!(
+                "unknown config layout, got {} bytes instead of {} from firmware {firmware:#06x}",
+                buf.len(),
+                self.length
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The quirks of a device found by enumeration, `None` if it isn't supported
+pub fn lookup(dev: &nusb::DeviceInfo) -> Option<&'static Quirks> {
+    ALL.iter()
+        .find(|quirks| quirks.vendor_id == dev.vendor_id() && quirks.product_id == dev.product_id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The bytes each decoded field occupies, by name
+    fn fields(layout: &Layout) -> Vec<(&'static str, Range<usize>)> {
+        let mut fields = vec![
+            ("gain", layout.gain..layout.gain + 2),
+            ("mute", layout.mute..layout.mute + 1),
+            ("clipguard", layout.clipguard..layout.clipguard + 1),
+            ("phantom", layout.phantom..layout.phantom + 1),
+            ("lowcut", layout.lowcut..layout.lowcut + 2),
+            ("volume", layout.volume..layout.volume + 2),
+            ("mix", layout.mix..layout.mix + 1),
+            ("color_mute", layout.color_mute..layout.color_mute + 3),
+            ("gain_lock", layout.gain_lock..layout.gain_lock + 1),
+            (
+                "color_gain_reduction",
+                layout.color_gain_reduction..layout.color_gain_reduction + 3,
+            ),
+            (
+                "clipguard_indicator",
+                layout.clipguard_indicator..layout.clipguard_indicator + 1,
+            ),
+            ("lim", layout.lim..layout.lim + 1),
+        ];
+        fields.extend(
+            layout
+                .color_gen
+                .iter()
+                .map(|&offset| ("color_gen", offset..offset + 3)),
+        );
+        fields.extend(
+            layout
+                .unknown
+                .iter()
+                .map(|field| (field.name, field.offset..field.offset + field.length)),
+        );
+        fields
+    }
+
+    #[test]
+    fn fields_fit_the_buffer_without_overlapping() {
+        for quirks in ALL {
+            for layout in quirks.layouts {
+                let mut owner = vec![None; layout.length];
+                for (name, bytes) in fields(layout) {
+                    assert!(bytes.end <= layout.length, "{name} ends at {}", bytes.end);
+                    for byte in bytes {
+                        if let Some(other) = owner[byte].replace(name) {
+                            assert_eq!(other, name, "byte {byte}");
+                        }
+                    }
+                }
+                assert_eq!(layout.mix_flag, layout.mix - 1);
+                assert_eq!(owner[layout.mix_flag], Some("mix_flag"));
+            }
+        }
+    }
+
+    #[test]
+    fn watches_only_unknown_bytes() {
+        for layout in ALL.iter().flat_map(|quirks| quirks.layouts) {
+            for byte in layout.undecoded.iter().cloned().flatten() {
+                assert!(
+                    layout.unknown.iter().any(|field| {
+                        (field.offset..field.offset + field.length).contains(&byte)
+                    }),
+                    "byte {byte} is watched, but isn't part of an unknown field"
+                );
+            }
+            for field in layout.unknown {
+                if let Some(default) = field.default {
+                    assert_eq!(default.len(), field.length, "{}", field.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn picks_the_layout_of_the_firmware() {
+        const OLD: Layout = Layout {
+            firmware: Some(0x0100..=0x01ff),
+            length: 32,
+            ..WAVE_XLR_LAYOUT
+        };
+        let quirks = Quirks {
+            layouts: &[OLD, WAVE_XLR_LAYOUT],
+            ..WAVE_XLR
+        };
+        assert_eq!(quirks.layout(0x0150).unwrap().length, 32);
+        assert_eq!(quirks.layout(0x0200).unwrap().length, 34);
+
+        let only_old = Quirks {
+            layouts: &[OLD],
+            ..WAVE_XLR
+        };
+        assert!(only_old.layout(0x0200).is_err());
+    }
+
+    #[test]
+    fn rejects_buffers_of_the_wrong_length() {
+        assert!(WAVE_XLR_LAYOUT.check(&[0; 34], 0x0100).is_ok());
+        for length in [0, 33, 35, 64] {
+            let err = WAVE_XLR_LAYOUT.check(&vec![0; length], 0x0100).unwrap_err();
+            assert!(err.to_string().contains("0x0100"), "{err:#}");
+        }
+    }
+}
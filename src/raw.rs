@@ -0,0 +1,172 @@
+//! Arbitrary class control transfers through the claimed interface, for reverse engineering
+//!
+//! Unlike [`probe`](crate::probe), this can send host-to-device requests, which may change the
+//! device state in unknown ways.
+
+use crate::{backend::DeviceBackend, probe::Recipient};
+use anyhow::{Result, bail};
+use nusb::transfer::{ControlIn, ControlOut, ControlType};
+use serde::{Deserialize, Deserializer, de};
+use std::fmt::Write;
+
+/// What a line asks for with `{"raw": ...}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged, expecting = "a hex encoded config buffer or a transfer")]
+pub enum Request {
+    /// A whole config buffer as hex, written as is, see `--allow-raw-write`
+    Config(String),
+    Transfer(Transfer),
+}
+
+/// A single class control transfer, as sent with `{"raw": {...}}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "direction", rename_all = "snake_case")]
+pub enum Transfer {
+    /// Device-to-host, answered with the received bytes
+    In {
+        #[serde(default)]
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    },
+    /// Host-to-device
+    Out {
+        #[serde(default)]
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+        /// Hex encoded payload
+        #[serde(deserialize_with = "deserialize_hex")]
+        data: Vec<u8>,
+    },
+}
+
+/// Send the transfer, returning the hex encoded response of an IN transfer
+pub async fn transfer<D: DeviceBackend>(device: &D, transfer: Transfer) -> Result<Option<String>> {
+    let timeouts = device.timeouts();
+    match transfer {
+        Transfer::In {
+            recipient,
+            request,
+            value,
+            index,
+            length,
+        } => {
+            let control = ControlIn {
+                control_type: ControlType::Class,
+                recipient: recipient.into(),
+                request,
+                value,
+                index,
+                length,
+            };
+            let data = device.control_in(control, timeouts.read).await?;
+            Ok(Some(hex(&data)))
+        }
+        Transfer::Out {
+            recipient,
+            request,
+            value,
+            index,
+            data,
+        } => {
+            if data.len() > usize::from(u16::MAX) {
+                bail!(
+                    "payload of {} bytes is longer than a control transfer can carry",
+                    data.len()
+                );
+            }
+            let control = ControlOut {
+                control_type: ControlType::Class,
+                recipient: recipient.into(),
+                request,
+                value,
+                index,
+                data: &data,
+            };
+            device.control_out(control, timeouts.write).await?;
+            Ok(None)
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+/// Parse hex like `"0028 00ec"`, ignoring whitespace
+pub fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let digits: String = s.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+        bail!("invalid hex digit {c:?} in {s:?}");
+    }
+    if !digits.len().is_multiple_of(2) {
+        bail!("odd number of hex digits in {s:?}");
+    }
+    let byte = |i| u8::from_str_radix(&digits[i..i + 2], 16).expect("checked above");
+    Ok((0..digits.len()).step_by(2).map(byte).collect())
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_hex(&s).map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+    use std::sync::Arc;
+
+    #[test]
+    fn parses_hex() {
+        for (s, expected) in [
+            ("0028 00ec", &[0x00, 0x28, 0x00, 0xec][..]),
+            ("FF\n0a", &[0xff, 0x0a]),
+            ("f f", &[0xff]),
+            ("", &[]),
+        ] {
+            assert_eq!(parse_hex(s).unwrap(), expected, "{s:?}");
+        }
+        assert_eq!(parse_hex(&hex(&[0x12, 0xab])).unwrap(), [0x12, 0xab]);
+    }
+
+    #[test]
+    fn rejects_malformed_hex() {
+        for s in [
+            "0", "abc", "0028 00e", "zz", "0g", "+f", "-1", "0x28", "éé", "0é0",
+        ] {
+            assert!(parse_hex(s).is_err(), "{s:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_payloads_longer_than_a_transfer() {
+        let mock = Arc::new(MockDevice::new());
+        let transfer = |data| Transfer::Out {
+            recipient: Recipient::Endpoint,
+            request: 0x05,
+            value: 0,
+            index: 0x3300,
+            data,
+        };
+
+        let err = super::transfer(&mock, transfer(vec![0; 0x10000]))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("65536 bytes"), "{err:#}");
+        // Only the length differs from a config write, which the device then refuses
+        assert!(
+            super::transfer(&mock, transfer(vec![0; 0x100]))
+                .await
+                .is_err()
+        );
+        assert_eq!(mock.writes(), 0);
+    }
+}
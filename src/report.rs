@@ -0,0 +1,165 @@
+use crate::{event::Event, usb_device};
+use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    io, panic, process,
+};
+
+/// Exit code used when the process dies from a panic, matching the default Rust panic exit code
+const PANIC_EXIT_CODE: i32 = 101;
+
+/// Exit code used when `try_main` returns an error that none of the others fit
+pub const ERROR_EXIT_CODE: u8 = 1;
+
+/// Exit code used when no device is connected, matching `EX_UNAVAILABLE` of sysexits.h
+pub const MISSING_DEVICE_EXIT_CODE: u8 = 69;
+
+/// Exit code used when the output can't be written anymore, matching `EX_IOERR` of sysexits.h
+pub const IO_ERROR_EXIT_CODE: u8 = 74;
+
+/// Exit code used when the device or the input didn't follow the protocol, matching
+/// `EX_PROTOCOL` of sysexits.h
+pub const PROTOCOL_EXIT_CODE: u8 = 76;
+
+/// Exit code used when the device can't be opened for lack of permissions, matching `EX_NOPERM`
+/// of sysexits.h
+pub const PERMISSION_DENIED_EXIT_CODE: u8 = 77;
+
+/// Report panics as a final [`Event::Fatal`] line and exit
+///
+/// A panic inside one of the worker tasks would otherwise only kill that task, leaving a
+/// half-working process behind.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(msg) => msg.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(msg) => msg.clone(),
+                None => "Box<dyn Any>".to_owned(),
+            },
+        };
+        let message = match info.location() {
+            Some(location) => format!("{message} at {location}"),
+            None => message,
+        };
+
+        Event::Fatal {
+            code: "panic",
+            message,
+            backtrace_hash: backtrace_hash(&Backtrace::force_capture()),
+        }
+        .emit_blocking();
+
+        process::exit(PANIC_EXIT_CODE);
+    }));
+}
+
+/// Report the error that ended `try_main` as a final [`Event::Fatal`] line
+pub fn report_error(err: &anyhow::Error) {
+    log::error!("{err:?}");
+
+    Event::Fatal {
+        code: "error",
+        message: format!("{err:#}"),
+        backtrace_hash: backtrace_hash(err.backtrace()),
+    }
+    .emit_blocking();
+}
+
+/// The exit code for the error that ended `try_main`
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    let closed = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+    });
+    if closed {
+        IO_ERROR_EXIT_CODE
+    } else if usb_device::is_missing(err) {
+        MISSING_DEVICE_EXIT_CODE
+    } else if usb_device::is_permission_denied(err) {
+        PERMISSION_DENIED_EXIT_CODE
+    } else if usb_device::is_protocol_error(err) {
+        PROTOCOL_EXIT_CODE
+    } else {
+        ERROR_EXIT_CODE
+    }
+}
+
+/// Short stable identifier for a backtrace, so bug reports of the same crash can be grouped
+fn backtrace_hash(backtrace: &Backtrace) -> Option<String> {
+    if backtrace.status() != BacktraceStatus::Captured {
+        return None;
+    }
+    Some(symbols_hash(&backtrace.to_string()))
+}
+
+/// Hash of the symbol names in a formatted backtrace
+///
+/// Addresses, file paths and line numbers differ between builds, machines and runs, so they are
+/// left out, as are the hashes rustc appends to symbol names. FNV-1a rather than the std hasher,
+/// whose output may change with the toolchain.
+fn symbols_hash(backtrace: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for symbol in backtrace.lines().filter_map(symbol) {
+        for byte in symbol.bytes().chain([b'\n']) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    format!("{hash:016x}")
+}
+
+/// The symbol name of a frame line like `  3: tidal_wave::main::h0123456789abcdef`, `None` for
+/// the `at path:line:column` lines in between
+///
+/// Frames printed with their address, like `0x55d3c2a1b2c3 - main`, keep only the name, and
+/// frames without a name only their position.
+fn symbol(line: &str) -> Option<&str> {
+    let (index, symbol) = line.trim_start().split_once(": ")?;
+    if !index.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let mut symbol = symbol.trim();
+    if symbol.starts_with("0x") {
+        symbol = symbol
+            .split_once(" - ")
+            .map_or("<unknown>", |(_, name)| name);
+    }
+    Some(match symbol.rsplit_once("::h") {
+        Some((name, hash))
+            if hash.len() == 16 && hash.bytes().all(|byte| byte.is_ascii_hexdigit()) =>
+        {
+            name
+        }
+        _ => symbol,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_only_symbol_names() {
+        let one = "   0: tidal_wave::stdio::poll::h0123456789abcdef
+             at /home/alice/tidal-wave/src/stdio.rs:412:9
+   1: 0x55d3c2a1b2c3 - std::rt::lang_start
+   2: main
+             at ./src/main.rs:22:5
+";
+        let other = "   0: tidal_wave::stdio::poll::hfedcba9876543210
+             at /build/src/stdio.rs:420:13
+   1: 0x7f00aa00bb00 - std::rt::lang_start
+   2: main
+             at /tmp/src/main.rs:22:5
+";
+        assert_eq!(symbol("   7: 0x7f00aa00bb00"), Some("<unknown>"));
+        assert_eq!(symbols_hash(one), symbols_hash(other));
+
+        let different = one.replace("poll", "flush");
+        assert_ne!(symbols_hash(one), symbols_hash(&different));
+    }
+}
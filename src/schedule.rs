@@ -0,0 +1,149 @@
+//! Switching the LEDs to a night setup during configurable hours
+//!
+//! ```toml
+//! [night]
+//! start = "22:00"
+//! end = "07:00"
+//! theme = "night"
+//! brightness = 0.3
+//! ```
+//!
+//! Once the night is over, `day_theme` is applied if given, otherwise the colors and brightness
+//! from before the night are restored. Only active in the stdio mode.
+
+use crate::{
+    backend::DeviceBackend, state_bus::StateBus, stdio, ui_state::Line, usb_device::Color,
+};
+use jiff::{Zoned, civil::Time};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::sleep;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Night {
+    /// Local time the night starts at
+    pub start: Time,
+    /// Local time the night ends at, may be before `start` to span midnight
+    pub end: Time,
+    pub theme: Option<String>,
+    pub brightness: Option<f64>,
+    pub day_theme: Option<String>,
+}
+
+impl Night {
+    fn contains(&self, now: Time) -> bool {
+        match self.start <= self.end {
+            true => self.start <= now && now < self.end,
+            false => now >= self.start || now < self.end,
+        }
+    }
+}
+
+/// Colors and brightness from before the night
+struct Day {
+    color_gen: Color,
+    color_mute: Color,
+    color_gain_reduction: Color,
+    brightness: f64,
+}
+
+/// Apply the night setup whenever the night starts and undo it when it ends
+pub async fn run<D: DeviceBackend>(night: Night, device: D, state: StateBus) {
+    let mut day: Option<Day> = None;
+
+    loop {
+        let line = match (night.contains(Zoned::now().time()), &day) {
+            (true, None) => {
+                day = state
+                    .with(|state| {
+                        Some(Day {
+                            color_gen: state.cached.color_gen,
+                            color_mute: state.cached.color_mute,
+                            color_gain_reduction: state.cached.color_gain_reduction,
+                            brightness: state.dimming.brightness,
+                        })
+                    })
+                    .await;
+                log::info!("night starts, switching the LEDs");
+                Some(Line {
+                    theme: night.theme.clone(),
+                    brightness: night.brightness,
+                    ..Line::default()
+                })
+            }
+            (false, Some(Day { brightness, .. })) if night.day_theme.is_some() => {
+                log::info!("night ends, switching the LEDs back");
+                let line = Line {
+                    theme: night.day_theme.clone(),
+                    brightness: Some(*brightness),
+                    ..Line::default()
+                };
+                day = None;
+                Some(line)
+            }
+            (false, Some(_)) => {
+                log::info!("night ends, switching the LEDs back");
+                day.take().map(|day| Line {
+                    color_gen: Some(day.color_gen),
+                    color_mute: Some(day.color_mute),
+                    color_gain_reduction: Some(day.color_gain_reduction),
+                    brightness: Some(day.brightness),
+                    ..Line::default()
+                })
+            }
+            _ => None,
+        };
+
+        if let Some(line) = line
+            && let Err(err) = stdio::apply(&device, &state, line).await
+        {
+            let err = err.to_string();
+            state.update(|state| state.io.err = Some(err));
+        }
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn night(start: &str, end: &str) -> Night {
+        Night {
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            theme: None,
+            brightness: None,
+            day_theme: None,
+        }
+    }
+
+    #[test]
+    fn contains_the_hours_between_start_and_end() {
+        for (night, inside, outside) in [
+            (
+                night("22:00", "07:00"),
+                &["22:00", "23:59:59", "00:00", "06:59:59"][..],
+                &["07:00", "12:00", "21:59:59"][..],
+            ),
+            (
+                night("01:00", "05:30"),
+                &["01:00", "03:00", "05:29:59"],
+                &["00:59:59", "05:30", "23:00"],
+            ),
+        ] {
+            for time in inside {
+                assert!(night.contains(time.parse().unwrap()), "{time} in {night:?}");
+            }
+            for time in outside {
+                assert!(
+                    !night.contains(time.parse().unwrap()),
+                    "{time} in {night:?}"
+                );
+            }
+        }
+    }
+}
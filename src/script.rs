@@ -0,0 +1,137 @@
+//! Recording sessions as scripts and replaying them
+//!
+//! A script has one JSON object per line, holding an input [`Line`] as it was applied and the
+//! time since the previous one:
+//!
+//! ```json
+//! {"delay_ms":0,"line":{"phantom":true}}
+//! {"delay_ms":1500,"line":{"gain":"45dB","mute":false}}
+//! ```
+
+use crate::{
+    backend::DeviceBackend,
+    compat,
+    stdio::{self, Allowed, Unit},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs::{self, File},
+    io::Write,
+    mem,
+    path::Path,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    delay_ms: u64,
+    line: Value,
+}
+
+/// Appends every applied line to a script file
+#[derive(Debug)]
+pub struct Recorder {
+    file: File,
+    last: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        Ok(Self {
+            file,
+            last: Instant::now(),
+        })
+    }
+
+    /// Record a line that was just applied
+    pub fn record(&mut self, line: &Value) -> Result<()> {
+        let now = Instant::now();
+        let entry = Entry {
+            delay_ms: (now - self.last).as_millis() as u64,
+            line: line.clone(),
+        };
+        self.last = now;
+
+        let mut buf = serde_json::to_vec(&entry)?;
+        buf.push(b'\n');
+        // Written in one go, so an interrupted session leaves a usable script behind
+        self.file.write_all(&buf).context("writing script")?;
+        Ok(())
+    }
+}
+
+/// Handle the lines of a script like input lines, waiting the recorded delay before each of them
+///
+/// Answers to queries and raw transfers and other events are printed as JSON lines.
+pub async fn replay<D: DeviceBackend>(path: &Path, unit: &Unit<D>, allowed: Allowed) -> Result<()> {
+    let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+    for (i, text) in text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        let context = || format!("{}:{}", path.display(), i + 1);
+        let Entry { delay_ms, mut line } = serde_json::from_str(text).with_context(context)?;
+        compat::migrate(&mut line).with_context(context)?;
+
+        sleep(Duration::from_millis(delay_ms)).await;
+        stdio::handle_line(unit, &line, allowed)
+            .await
+            .with_context(context)?;
+
+        let events = unit.state.with(|state| mem::take(&mut state.events)).await;
+        for event in events {
+            println!("{}", serde_json::to_string(&event)?);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Device, mock::MockDevice, quirks, state_bus::StateBus, ui_state::UiState,
+        usb_device::DeviceConfiguration,
+    };
+    use std::{env, process, sync::Arc};
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_every_kind_of_line() {
+        let mock = Arc::new(MockDevice::new());
+        let unit = Unit {
+            device: Device::mock(Arc::clone(&mock)),
+            state: StateBus::spawn(UiState::default()),
+        };
+        let path = env::temp_dir().join(format!("tidal-wave-script-{}.jsonl", process::id()));
+        let script = |lines: &[&str]| {
+            let text: String = lines.iter().map(|line| format!("{line}\n")).collect();
+            fs::write(&path, text).unwrap();
+        };
+
+        script(&[
+            r#"{"delay_ms":0,"line":{"mute":true}}"#,
+            r#"{"delay_ms":10,"line":{"get":"info"}}"#,
+        ]);
+        replay(&path, &unit, Allowed::default()).await.unwrap();
+        let layout = &quirks::WAVE_XLR_LAYOUT;
+        assert!(
+            DeviceConfiguration::read(&mock.config(), layout)
+                .unwrap()
+                .mute
+        );
+
+        // Refused like on stdin, naming the line instead of skipping it
+        let raw = format!(r#"{{"delay_ms":0,"line":{{"raw":"{}"}}}}"#, "00".repeat(34));
+        script(&[r#"{"delay_ms":0,"line":{"mix":80}}"#, &raw]);
+        let err = replay(&path, &unit, Allowed::default()).await.unwrap_err();
+        assert!(err.to_string().ends_with(":2"), "{err:#}");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use crate::quirks;
+use crate::usb_device::{Color, Device, DeviceConfiguration, DeviceOptions, LowcutFilter, Mode};
+use anyhow::{Result, bail};
+
+struct Fixture {
+    name: &'static str,
+    buf: [u8; 34],
+    config: DeviceConfiguration,
+}
+
+/// Reference buffers following the layout documented in `usb_elgato_wave_xlr.lua`
+const FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "40dB gain, clipguard, -12dB monitor",
+        #[rustfmt::skip]
+        buf: [
+            0x00, 0x28, // gain
+            0x00, 0xec,
+            0x00, // mute
+            0x01, // clipguard
+            0x00, // phantom
+            0x00, 0x00, // lowcut
+            0x00, 0xf4, // volume
+            0x00,
+            0x00, // mix quirk
+            50, // mix
+            0x01,
+            0xff, 0x00, 0x00, // color_mute
+            0x00, 0x80, 0xff, 0x00, 0x80, 0xff, 0x00, 0x80, 0xff, // color_gen
+            0x01,
+            0x00, // gain_lock
+            0xff, 0xa5, 0x00, // color_gain_reduction
+            0x01, // clipguard_indicator
+            0x00, // lim
+        ],
+        config: DeviceConfiguration {
+            gain: 40 * 256,
+            mute: false,
+            clipguard: true,
+            phantom: false,
+            lowcut: LowcutFilter::Off,
+            volume: -12 * 256,
+            mix: 50,
+            color_mute: Color([0xff, 0x00, 0x00]),
+            color_gen: Color([0x00, 0x80, 0xff]),
+            gain_lock: false,
+            color_gain_reduction: Color([0xff, 0xa5, 0x00]),
+            clipguard_indicator: true,
+            lim: false,
+        },
+    },
+    Fixture {
+        name: "muted, phantom power, gain lock, mix quirk",
+        #[rustfmt::skip]
+        buf: [
+            0x80, 0x0c, // gain
+            0x00, 0xec,
+            0x01, // mute
+            0x00, // clipguard
+            0x01, // phantom
+            0x00, 0x00, // lowcut
+            0x00, 0x00, // volume
+            0x00,
+            0x01, // mix quirk
+            41, // mix
+            0x01,
+            0x00, 0x00, 0xff, // color_mute
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // color_gen
+            0x01,
+            0x01, // gain_lock
+            0x00, 0xff, 0x00, // color_gain_reduction
+            0x00, // clipguard_indicator
+            0x01, // lim
+        ],
+        config: DeviceConfiguration {
+            gain: 0x0c80,
+            mute: true,
+            clipguard: false,
+            phantom: true,
+            lowcut: LowcutFilter::Off,
+            volume: 0,
+            mix: 41,
+            color_mute: Color([0x00, 0x00, 0xff]),
+            color_gen: Color([0xff, 0xff, 0xff]),
+            gain_lock: true,
+            color_gain_reduction: Color([0x00, 0xff, 0x00]),
+            clipguard_indicator: false,
+            lim: true,
+        },
+    },
+];
+
+/// Run the encoder/decoder against [`FIXTURES`] and optionally a live read-write-read cycle
+///
+/// Prints one line per check and fails if any of them failed.
+pub async fn selftest(options: &DeviceOptions, live: bool) -> Result<()> {
+    let mut failed = 0;
+    let mut report = |name: &str, res: Result<()>| match res {
+        Ok(()) => println!("ok   {name}"),
+        Err(err) => {
+            failed += 1;
+            println!("FAIL {name}: {err:#}");
+        }
+    };
+
+    for fixture in FIXTURES {
+        report(&format!("decode {}", fixture.name), decode(fixture));
+        report(&format!("encode {}", fixture.name), encode(fixture));
+    }
+
+    if live {
+        report("live read-write-read", live_roundtrip(options).await);
+    }
+
+    if failed != 0 {
+        bail!("{failed} selftest check(s) failed");
+    }
+    Ok(())
+}
+
+fn decode(fixture: &Fixture) -> Result<()> {
+    let config = DeviceConfiguration::read(&fixture.buf, &quirks::WAVE_XLR_LAYOUT)?;
+    if config != fixture.config {
+        bail!("expected {:?}, got {config:?}", fixture.config);
+    }
+    Ok(())
+}
+
+fn encode(fixture: &Fixture) -> Result<()> {
+    let mut buf = [0; 34];
+    fixture.config.write(&mut buf, &quirks::WAVE_XLR_LAYOUT);
+    if let Some(offset) = (0..buf.len()).find(|&i| buf[i] != fixture.buf[i]) {
+        bail!(
+            "byte {offset}: expected {:#04x}, got {:#04x}",
+            fixture.buf[offset],
+            buf[offset]
+        );
+    }
+    Ok(())
+}
+
+async fn live_roundtrip(options: &DeviceOptions) -> Result<()> {
+    let device = Device::try_initialize(options).await?;
+    let timeouts = device.timeouts();
+
+    let before = device.read_config(timeouts.read).await?;
+    device
+        .write_config(&before, Mode::Temporary, timeouts.write)
+        .await?;
+    let after = device.read_config(timeouts.read).await?;
+
+    if before != after {
+        bail!("config changed by writing it back: before {before:?}, after {after:?}");
+    }
+    Ok(())
+}
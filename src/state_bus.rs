@@ -0,0 +1,81 @@
+//! The [`UiState`] of a unit, owned by a single task
+//!
+//! Frontends don't share the state behind a lock, they send closures to the task owning it, which
+//! runs them one after the other. Whenever one leaves events or the outcome of a line to be
+//! written, the owner publishes that on a watch channel, which any number of frontends can
+//! subscribe to.
+
+use crate::ui_state::UiState;
+use tokio::sync::{mpsc, oneshot, watch};
+
+type Command = Box<dyn FnOnce(&mut UiState) + Send>;
+
+/// Handle to the task owning a [`UiState`], which ends once every handle is dropped
+#[derive(Clone)]
+pub struct StateBus {
+    commands: mpsc::UnboundedSender<Command>,
+    changed: watch::Receiver<()>,
+}
+
+impl StateBus {
+    /// Hand `state` over to a new owner task
+    pub fn spawn(mut state: UiState) -> Self {
+        let (commands, mut rx) = mpsc::unbounded_channel::<Command>();
+        let (publish, changed) = watch::channel(());
+        tokio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                command(&mut state);
+                if state.has_output() {
+                    publish.send_replace(());
+                }
+            }
+        });
+        Self { commands, changed }
+    }
+
+    /// Run `f` on the state and wait for what it returns
+    pub async fn with<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut UiState) -> R + Send + 'static,
+    ) -> R {
+        let (tx, rx) = oneshot::channel();
+        self.update(move |state| {
+            let _ = tx.send(f(state));
+        });
+        rx.await.expect("the task owning the state panicked")
+    }
+
+    /// Run `f` on the state without waiting for it, commands still run in the order they're sent
+    pub fn update(&self, f: impl FnOnce(&mut UiState) + Send + 'static) {
+        // Only fails once the owner panicked, which the next `with` reports
+        let _ = self.commands.send(Box::new(f));
+    }
+
+    /// Notified whenever there are events or the outcome of a line waiting to be written
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        let mut rx = self.changed.clone();
+        rx.mark_unchanged();
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+
+    #[tokio::test]
+    async fn publishes_only_what_is_left_to_write() {
+        let state = StateBus::spawn(UiState::default());
+        let mut changed = state.subscribe();
+
+        state.with(|state| state.revision += 1).await;
+        assert!(!changed.has_changed().unwrap());
+
+        state.update(|state| state.events.push(Event::DeviceLost));
+        changed.changed().await.unwrap();
+        let events = state.with(|state| std::mem::take(&mut state.events)).await;
+        assert_eq!(events.len(), 1);
+        assert!(!changed.has_changed().unwrap());
+    }
+}
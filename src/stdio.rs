@@ -1,113 +1,1782 @@
 use crate::{
+    animation,
+    backend::DeviceBackend,
+    capabilities::Query,
+    color::hex_colors,
+    compat,
+    event::{Event, RejectedField, StartupOptions},
+    profile, raw,
+    script::Recorder,
+    state_bus::StateBus,
     ui_state::{Line, UiState},
-    usb_device::{Device, Mode},
+    usb_device::{self, Device, DeviceConfiguration, MissingDevice, Mode, ProtocolError},
+    watchdog::{self, ResumeDetector},
 };
-use anyhow::Result;
-use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::future;
+use std::mem;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::time::sleep;
+use tokio::sync::{Mutex as AsyncMutex, Notify, watch};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep_until, timeout_at};
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shortest poll interval accepted, as every poll is a control transfer
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Poll interval right after a write or a change on the device, so its outcome shows up quickly
+pub const FAST_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long polling stays fast after the last write or change
+pub const FAST_POLL_WINDOW: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Records every applied line
+    pub recorder: Option<Recorder>,
+    /// Write the last known config again after the system resumed from suspend
+    pub restore_after_resume: bool,
+    /// Tag output with the unit's [`id`](crate::usb_device::DeviceInfo::id), by namespacing
+    /// state lines and adding a `device` field to events
+    pub tag_device: bool,
+    /// Names to report and accept instead of serial numbers
+    pub device_names: BTreeMap<String, String>,
+    /// Add the configuration buffer as `raw` hex to state lines whenever it changes
+    pub report_raw: bool,
+    /// Accept whole config buffers as `{"raw": "<hex>"}` and write them as is
+    pub allow_raw_write: bool,
+    /// Report and accept the fields whose meaning isn't known yet as `unknown`
+    pub experimental: bool,
+    /// Merge settings lines arriving within this long of each other into a single write
+    pub coalesce: Option<Duration>,
+    /// What to do with lines that aren't valid JSON or not a valid line of the protocol
+    pub on_invalid_input: OnInvalidInput,
+    /// Stop reading input and end cleanly once notified, e.g. on SIGTERM
+    pub shutdown: Option<Arc<Notify>>,
+    /// Write the configuration read at startup back to every unit when the protocol ends
+    pub restore_on_exit: bool,
+    /// Keep reporting changes of the device once the input ends, until shut down
+    pub monitor_after_eof: bool,
+    /// Report colors as hex strings instead of byte arrays
+    pub hex_colors: bool,
+}
+
+/// How the lines of a unit are written
+#[derive(Debug, Clone)]
+struct Format {
+    /// Tag lines with the unit's id, see [`Options::tag_device`]
+    id: Option<String>,
+    hex_colors: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnInvalidInput {
+    /// Report the error in the `err` field of the next state line and carry on
+    #[default]
+    Skip,
+    /// End with an error naming the line and where it starts in the input
+    Abort,
+    /// Report an `invalid_input` event with the line and where it starts in the input instead
+    EchoWithContext,
+}
+
+/// Input lines that are rejected unless explicitly allowed
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Allowed {
+    /// Whole config buffers, see [`Options::allow_raw_write`]
+    pub raw_write: bool,
+    /// Unknown fields, see [`Options::experimental`]
+    pub experimental: bool,
+}
+
+/// A device together with the state of its side of the protocol
+#[derive(Clone)]
+pub struct Unit<D = Device> {
+    pub device: D,
+    pub state: StateBus,
+}
 
 pub async fn stdio<
+    D: DeviceBackend,
     R: AsyncBufRead + Unpin + Send + 'static,
     W: AsyncWrite + Unpin + Send + 'static,
 >(
-    device: Device,
-    state: Arc<Mutex<UiState>>,
+    units: Vec<Unit<D>>,
     reader: R,
     writer: W,
+    options: Options,
 ) -> Result<()> {
+    let Options {
+        mut recorder,
+        restore_after_resume,
+        tag_device,
+        device_names,
+        report_raw,
+        allow_raw_write,
+        experimental,
+        coalesce,
+        on_invalid_input,
+        shutdown,
+        restore_on_exit,
+        monitor_after_eof,
+        hex_colors,
+    } = options;
+    let allowed = Allowed {
+        raw_write: allow_raw_write,
+        experimental,
+    };
+    if units.is_empty() {
+        return Err(MissingDevice(None).into());
+    }
+
+    let mut ids = Vec::with_capacity(units.len());
+    let mut serials = Vec::with_capacity(units.len());
+    let mut startups = Vec::with_capacity(units.len());
+    let mut initial = Vec::with_capacity(units.len());
+    for Unit { device, state } in &units {
+        let timeout = device.timeouts().read;
+        let config = watchdog::guard(device, "read_config", timeout, || {
+            device.read_config(timeout)
+        })
+        .await?;
+        // Establishes the baseline, so the first poll only reports actual changes
+        let (poll_interval, revision) = state
+            .with(move |state| {
+                state.update_device_info(config);
+                (state.poll_interval.unwrap_or(POLL_INTERVAL), state.revision)
+            })
+            .await;
+
+        initial.push(config);
+        let info = device.info()?;
+        let id = info.id();
+        ids.push(device_names.get(&id).cloned().unwrap_or_else(|| id.clone()));
+        serials.push(id);
+        startups.push(Event::Startup {
+            device: info,
+            options: StartupOptions {
+                poll_interval_ms: poll_interval.as_millis(),
+                encoding: "json",
+            },
+            config,
+            revision,
+        });
+    }
+
     let stdin = tokio::spawn({
-        let device = device.clone();
-        let state = Arc::clone(&state);
+        let units = units.clone();
+        let ids = ids.clone();
         async move {
             let mut stdin = reader;
             let mut buf = Vec::new();
+            // Bytes of input before the current line
+            let mut offset = 0;
+            // Settings line collecting the ones that follow until its deadline, see `coalesce`
+            let mut pending: Option<(Value, Instant)> = None;
 
-            loop {
+            let mut process = async |value: Value| {
                 let res = async {
-                    buf.clear();
-                    stdin.read_until(b'\n', &mut buf).await?;
-                    let line: Line = serde_json::from_slice(&buf)?;
-
-                    let persistent = line.persistent;
-                    let use_cached = line.use_cached;
-
-                    let config = {
-                        let config = if !use_cached.unwrap_or(false) {
-                            Some(device.read_config(Duration::from_secs(1)).await?)
-                        } else {
-                            None
-                        };
-
-                        let mut state = state.lock().unwrap();
-                        if let Some(config) = config {
-                            state.cached = config;
-                        }
+                    let line: Line = serde_json::from_value(value.clone())?;
 
-                        state.update_state(line)
+                    // Without a "device", the line is broadcast to every unit
+                    let targets: Vec<&Unit<D>> = match &line.device {
+                        Some(id) => vec![
+                            (0..units.len())
+                                .find(|&i| ids[i] == *id || serials[i] == *id)
+                                .map(|i| &units[i])
+                                .with_context(|| format!("no device {id}"))?,
+                        ],
+                        None => units.iter().collect(),
                     };
+                    anyhow::Ok(targets)
+                }
+                .await;
+                let targets = match res {
+                    Ok(targets) => targets,
+                    Err(err) => {
+                        fail(&units[0], err);
+                        return;
+                    }
+                };
 
-                    device
-                        .write_config(
-                            &config,
-                            match persistent.unwrap_or(false) {
-                                true => Mode::Persistant,
-                                false => Mode::Temporary,
-                            },
-                            Duration::from_secs(1),
-                        )
-                        .await?;
-                    anyhow::Ok(())
+                let mut failed = false;
+                for unit in targets {
+                    if let Err(err) = handle_line(unit, &value, allowed).await {
+                        fail(unit, err);
+                        failed = true;
+                    }
+                }
+                if !failed && let Some(recorder) = &mut recorder {
+                    let res = recorder.record(&value);
+                    if let Err(err) = res {
+                        fail(&units[0], err);
+                    }
+                }
+            };
+
+            loop {
+                let read = match &pending {
+                    // Reading keeps what it got so far in `buf` when the deadline cuts it short
+                    Some((_, deadline)) => {
+                        match timeout_at(*deadline, stdin.read_until(b'\n', &mut buf)).await {
+                            Ok(read) => read,
+                            Err(_) => {
+                                let (value, _) = pending.take().unwrap();
+                                process(value).await;
+                                continue;
+                            }
+                        }
+                    }
+                    None => stdin.read_until(b'\n', &mut buf).await,
+                };
+                let line = mem::take(&mut buf);
+                let start = offset;
+                offset += line.len() as u64;
+                match read {
+                    // End of input, which ends the protocol
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(err) => {
+                        fail(&units[0], err.into());
+                        continue;
+                    }
+                }
+
+                let res = async {
+                    let mut value = serde_json::from_slice(&line)?;
+                    flatten_fields(&mut value);
+                    compat::migrate(&mut value)?;
+                    // Rejects invalid lines right away, rather than after coalescing
+                    serde_json::from_value::<Line>(value.clone())?;
+                    anyhow::Ok(value)
                 }
                 .await;
+                let value = match res {
+                    Ok(value) => value,
+                    Err(err) => {
+                        let input = String::from_utf8_lossy(&line).trim_end().to_owned();
+                        match on_invalid_input {
+                            OnInvalidInput::Skip => fail(&units[0], err),
+                            OnInvalidInput::Abort => {
+                                if let Some((value, _)) = pending.take() {
+                                    process(value).await;
+                                }
+                                let context = format!("invalid input at byte {start}: {input}");
+                                return Err(err.context(ProtocolError(context)));
+                            }
+                            OnInvalidInput::EchoWithContext => {
+                                let event = Event::InvalidInput {
+                                    message: err.to_string(),
+                                    input,
+                                    offset: start,
+                                };
+                                units[0].state.update(|state| state.events.push(event));
+                            }
+                        }
+                        continue;
+                    }
+                };
 
-                match res {
-                    Ok(()) => {}
-                    Err(err) => state.lock().unwrap().io.err = Some(err.to_string()),
+                if let Some((merged, _)) = &mut pending
+                    && coalesce_into(merged, &value)
+                {
+                    continue;
+                }
+                if let Some((value, _)) = pending.take() {
+                    process(value).await;
                 }
+                match coalesce {
+                    Some(window) if is_coalescable(&value) => {
+                        pending = Some((value, Instant::now() + window));
+                    }
+                    _ => process(value).await,
+                }
+            }
+            if let Some((value, _)) = pending {
+                process(value).await;
             }
+            if monitor_after_eof {
+                log::info!("input ended, only reporting changes from now on");
+                future::pending::<()>().await;
+            }
+            Ok(())
         }
     });
 
-    let stdout = tokio::spawn({
-        let device = device.clone();
-        let state = Arc::clone(&state);
-        async move {
-            let mut stdout = writer;
-            let mut buf = Vec::new();
+    let writer = Arc::new(AsyncMutex::new(writer));
+    let formats: Vec<_> = ids
+        .iter()
+        .map(|id| Format {
+            id: tag_device.then(|| id.clone()),
+            hex_colors,
+        })
+        .collect();
+    // Aborted when dropped, so nothing outlives the protocol
+    let mut tasks = JoinSet::new();
+    let mut polls = JoinSet::new();
+    for ((unit, format), startup) in units.iter().cloned().zip(formats.clone()).zip(startups) {
+        tasks.spawn(watchdog::watch_state(unit.state.clone()));
+        tasks.spawn(animation::animate(unit.device.clone(), unit.state.clone()));
+        polls.spawn(poll(
+            unit,
+            format,
+            startup,
+            Arc::clone(&writer),
+            restore_after_resume,
+            report_raw,
+            experimental,
+        ));
+    }
 
-            loop {
-                let res: Result<()> = async {
-                    let config = device.read_config(Duration::from_secs(1)).await?;
-                    let line = state.lock().unwrap().update_device_info(config);
+    // Ends with the input, with the first unit that can't write its output anymore or when told
+    // to shut down
+    let mut stdin = stdin;
+    let shutdown = async {
+        match &shutdown {
+            Some(shutdown) => shutdown.notified().await,
+            None => future::pending().await,
+        }
+    };
+    let res = tokio::select! {
+        res = &mut stdin => res.map_err(anyhow::Error::from).and_then(|res| res),
+        Some(res) = polls.join_next() => res.map_err(anyhow::Error::from).and_then(|res| res),
+        () = shutdown => {
+            log::info!("shutting down");
+            Ok(())
+        }
+    };
+    stdin.abort();
+    // Nothing may write to the device or the output anymore once this goes on
+    polls.shutdown().await;
+    tasks.shutdown().await;
 
-                    if !line.is_empty() {
-                        buf.clear();
+    for (unit, format) in units.iter().zip(&formats) {
+        if let Err(err) = flush(unit, format, &writer).await {
+            log::warn!("writing the last output failed: {err:#}");
+        }
+    }
+    if restore_on_exit {
+        for (Unit { device, .. }, config) in units.iter().zip(initial) {
+            let timeout = device.timeouts().write;
+            let res = watchdog::guard(device, "write_config", timeout, || {
+                device.write_config(&config, Mode::Temporary, timeout)
+            })
+            .await;
+            if let Err(err) = res {
+                log::warn!("restoring the configuration from startup failed: {err:#}");
+            }
+        }
+    }
+    res
+}
+
+/// Write the events and the outcome of the last line that no poll picked up anymore
+async fn flush<D, W: AsyncWrite + Unpin>(
+    unit: &Unit<D>,
+    format: &Format,
+    stdout: &AsyncMutex<W>,
+) -> Result<()> {
+    let (events, line) = unit
+        .state
+        .with(|state| {
+            let line = Line {
+                err: state.io.err.take(),
+                noop: state.io.noop.take(),
+                ..Line::default()
+            };
+            (mem::take(&mut state.events), line)
+        })
+        .await;
 
-                        serde_json::to_writer(&mut buf, &line)?;
-                        buf.push(b'\n');
+    let mut buf = Vec::new();
+    for event in &events {
+        push_line(&mut buf, event, format)?;
+    }
+    if !line.is_empty() {
+        push_state(&mut buf, &line, format)?;
+    }
+    if !buf.is_empty() {
+        write_output(stdout, &buf).await?;
+    }
+    Ok(())
+}
 
-                        stdout.write_all(&buf).await?;
-                        stdout.flush().await?;
-                    }
+/// Report the error of a line in the next state line of `unit`, which is written right away
+fn fail<D>(unit: &Unit<D>, err: anyhow::Error) {
+    let err = err.to_string();
+    unit.state.update(|state| state.io.err = Some(err));
+}
+
+/// Fields of lines that don't change any settings
+const ONLY_EVENTS: &[&str] = &["get", "raw", "device"];
+
+/// Settings that a later line simply replaces, grouped with the other forms of the same setting
+///
+/// Lines with nothing else can be merged without changing what they do together.
+const COALESCABLE: &[&[&str]] = &[
+    &["gain", "gain_raw"],
+    &["volume", "volume_raw"],
+    &["mix", "balance"],
+    &["mute"],
+    &["clipguard"],
+    &["phantom"],
+    &["lowcut"],
+    &["color_mute"],
+    &["color_gen"],
+    &["gain_lock"],
+    &["color_gain_reduction"],
+    &["clipguard_indicator"],
+    &["lim"],
+    &["brightness"],
+    &["gamma"],
+];
+
+fn coalescable_group(key: &str) -> Option<&'static [&'static str]> {
+    COALESCABLE
+        .iter()
+        .copied()
+        .find(|group| group.contains(&key))
+}
 
-                    Ok(())
+/// Whether a line only has settings in [`COALESCABLE`], besides its `device`
+fn is_coalescable(value: &Value) -> bool {
+    value.as_object().is_some_and(|map| {
+        map.keys()
+            .all(|key| key == "device" || coalescable_group(key).is_some())
+    })
+}
+
+/// Merge `value` into the earlier line `merged` if both can be, with the settings of `value`
+/// replacing any form of the same setting in `merged`
+fn coalesce_into(merged: &mut Value, value: &Value) -> bool {
+    if !is_coalescable(value) || merged.get("device") != value.get("device") {
+        return false;
+    }
+    let (Value::Object(merged), Value::Object(value)) = (merged, value) else {
+        return false;
+    };
+    for (key, setting) in value {
+        for other in coalescable_group(key).unwrap_or_default() {
+            merged.remove(*other);
+        }
+        merged.insert(key.clone(), setting.clone());
+    }
+    true
+}
+
+/// Answer the query of a line, send its raw transfer or buffer and apply its settings to one unit
+///
+/// A raw buffer is written instead of any settings of the line. Answers and other events are
+/// left in the [`UiState::events`] of the unit.
+pub async fn handle_line<D: DeviceBackend>(
+    unit: &Unit<D>,
+    value: &Value,
+    allowed: Allowed,
+) -> Result<()> {
+    let mut line: Line = serde_json::from_value(value.clone())?;
+    if let Some(query) = line.get.take() {
+        let event = match query {
+            Query::Capabilities => Event::Capabilities(unit.device.capabilities()),
+            Query::Info => Event::Info(unit.device.info()?),
+            Query::Raw => Event::Raw {
+                data: unit.device.last_buffer().map(|buf| raw::hex(&buf)),
+            },
+        };
+        unit.state.update(|state| state.events.push(event));
+    }
+    match line.raw.take() {
+        Some(raw::Request::Transfer(transfer)) => {
+            let data = raw::transfer(&unit.device, transfer).await?;
+            let event = Event::Raw { data };
+            unit.state.update(|state| state.events.push(event));
+        }
+        Some(raw::Request::Config(hex)) => {
+            if !allowed.raw_write {
+                bail!("writing a raw config buffer needs --allow-raw-write");
+            }
+            let buf = raw::parse_hex(&hex)?;
+            let mode = match line.persistent.unwrap_or(false) {
+                true => Mode::Persistant,
+                false => Mode::Temporary,
+            };
+            let device = &unit.device;
+            let timeout = device.timeouts().write;
+            watchdog::guard(device, "write_buffer", timeout, || {
+                device.write_buffer(&buf, mode, timeout)
+            })
+            .await?;
+            let now = Instant::now();
+            unit.state
+                .update(move |state| state.last_activity = Some(now));
+            return Ok(());
+        }
+        None => {}
+    }
+    if let Some(unknown) = &line.unknown {
+        if !allowed.experimental {
+            bail!("setting unknown fields needs --experimental");
+        }
+        for (name, hex) in unknown {
+            unit.device.set_unknown(name, raw::parse_hex(hex)?)?;
+        }
+    }
+
+    // Nothing to write to the device if the line only asks something or sends a transfer
+    let map = value.as_object();
+    if map.is_some_and(|map| map.keys().all(|key| ONLY_EVENTS.contains(&key.as_str()))) {
+        return Ok(());
+    }
+
+    apply(&unit.device, &unit.state, line).await
+}
+
+/// Serialize a state line into `buf`, as `{"device": ..., "fields": {...}}` when tagged
+fn push_state(buf: &mut Vec<u8>, line: &Line, format: &Format) -> Result<()> {
+    let line = hex_colors(line, format.hex_colors);
+    match &format.id {
+        Some(id) => serde_json::to_writer(&mut *buf, &json!({"device": id, "fields": line}))?,
+        None => serde_json::to_writer(&mut *buf, &line)?,
+    }
+    buf.push(b'\n');
+    Ok(())
+}
+
+/// Merge the `fields` of a namespaced input line into the line itself
+///
+/// Both `{"device": ..., "fields": {"gain": ...}}` and `{"device": ..., "gain": ...}` are
+/// accepted.
+fn flatten_fields(value: &mut Value) {
+    let Value::Object(map) = value else {
+        return;
+    };
+    if let Some(Value::Object(fields)) = map.remove("fields") {
+        map.extend(fields);
+    }
+}
+
+/// Serialize an event into `buf`, tagged with the device it's about
+fn push_line(buf: &mut Vec<u8>, value: &impl Serialize, format: &Format) -> Result<()> {
+    let value = hex_colors(value, format.hex_colors);
+    match &format.id {
+        Some(id) => {
+            let mut value = serde_json::to_value(value)?;
+            if let Value::Object(map) = &mut value {
+                map.insert("device".to_owned(), id.as_str().into());
+            }
+            serde_json::to_writer(&mut *buf, &value)?;
+        }
+        None => serde_json::to_writer(&mut *buf, &value)?,
+    }
+    buf.push(b'\n');
+    Ok(())
+}
+
+/// Write `buf` to the shared output in one go
+async fn write_output<W: AsyncWrite + Unpin>(stdout: &AsyncMutex<W>, buf: &[u8]) -> Result<()> {
+    let mut stdout = stdout.lock().await;
+    stdout.write_all(buf).await.context("writing output")?;
+    stdout.flush().await.context("writing output")?;
+    Ok(())
+}
+
+/// Write the startup event, then poll the device and write changes and events until aborted
+///
+/// Only fails once the output can't be written anymore, errors of the device are reported in the
+/// next state line instead.
+///
+/// Events and the outcome of lines are written as soon as the [`StateBus`] publishes them, without
+/// waiting for the next read.
+async fn poll<D: DeviceBackend, W: AsyncWrite + Unpin>(
+    unit: Unit<D>,
+    format: Format,
+    startup: Event,
+    stdout: Arc<AsyncMutex<W>>,
+    restore_after_resume: bool,
+    report_raw: bool,
+    experimental: bool,
+) -> Result<()> {
+    let Unit { device, state } = unit;
+    let mut buf = Vec::new();
+
+    push_line(&mut buf, &startup, &format)?;
+    write_output(&stdout, &buf).await?;
+
+    let mut changed = state.subscribe();
+    let mut reports = device.interrupts();
+    let mut connected = true;
+    let mut resume = ResumeDetector::start();
+    // Whether this round reads the device, rather than only writing what was published
+    let mut read = true;
+    let mut next_read = Instant::now();
+    loop {
+        // The claimed interface doesn't survive a suspend
+        if resume.resumed() {
+            log::warn!("system resumed from suspend, reopening device");
+            connected = match device.reopen().await {
+                Ok(()) => true,
+                Err(err) => {
+                    log::warn!("reopening device after resume failed: {err:#}");
+                    false
+                }
+            };
+            if connected && restore_after_resume {
+                let config = state.with(|state| state.outgoing(state.cached)).await;
+                let timeout = device.timeouts().write;
+                let res = device.write_config(&config, Mode::Temporary, timeout).await;
+                if let Err(err) = res {
+                    let err = err.to_string();
+                    state.update(|state| state.io.err = Some(err));
                 }
+            }
+        }
+
+        if !connected && device.reopen().await.is_ok() {
+            connected = true;
+            let serial = device.info().ok().and_then(|info| info.serial);
+            log::info!("device found again");
+            let event = Event::DeviceFound { serial };
+            state.update(|state| state.events.push(event));
+        }
+
+        buf.clear();
+        let res: Result<()> = async {
+            let timeout = device.timeouts().read;
+            let config = match connected && read {
+                true => match watchdog::guard(&device, "read_config", timeout, || {
+                    device.read_config(timeout)
+                })
+                .await
+                {
+                    Ok(config) => Some(config),
+                    Err(err) if usb_device::is_disconnected(&err) => {
+                        log::warn!("device lost: {err:#}");
+                        connected = false;
+                        state.update(|state| state.events.push(Event::DeviceLost));
+                        None
+                    }
+                    Err(err) => return Err(err),
+                },
+                false => None,
+            };
+            let undecoded = device.take_undecoded_changes().into_iter().map(Event::from);
+            let reserved = device
+                .take_unexpected_reserved()
+                .into_iter()
+                .map(Event::from);
+            let found: Vec<_> = undecoded.chain(reserved).collect();
+            let raw = (report_raw && config.is_some())
+                .then(|| device.last_buffer().map(|buf| raw::hex(&buf)));
+            let unknown = (experimental && config.is_some()).then(|| {
+                device.unknown_fields().map(|fields| {
+                    let fields = fields.into_iter();
+                    fields
+                        .map(|(name, value)| (name.to_owned(), raw::hex(&value)))
+                        .collect()
+                })
+            });
+            let (events, line) = state
+                .with(move |state| {
+                    let mut events = mem::take(&mut state.events);
+                    events.extend(found);
+                    let mut line = match config {
+                        Some(config) => {
+                            let config = state.regular(config);
+                            state.update_device_info(config)
+                        }
+                        None => Line {
+                            err: state.io.err.take(),
+                            noop: state.io.noop.take(),
+                            ..Line::default()
+                        },
+                    };
+                    if !line.is_empty() {
+                        state.last_activity = Some(Instant::now());
+                        line.revision = Some(state.revision);
+                    }
+                    if let Some(raw) = raw
+                        && raw != state.io.raw_buffer
+                    {
+                        state.io.raw_buffer.clone_from(&raw);
+                        line.raw_buffer = raw;
+                    }
+                    if let Some(unknown) = unknown
+                        && unknown != state.io.unknown
+                    {
+                        state.io.unknown.clone_from(&unknown);
+                        line.unknown = unknown;
+                    }
+                    (events, line)
+                })
                 .await;
 
-                match res {
-                    Ok(()) => {}
-                    Err(err) => state.lock().unwrap().io.err = Some(err.to_string()),
-                }
-                sleep(Duration::from_secs(1)).await
+            for event in &events {
+                push_line(&mut buf, event, &format)?;
             }
+            if !line.is_empty() {
+                push_state(&mut buf, &line, &format)?;
+            }
+            Ok(())
         }
-    });
+        .await;
+
+        if let Err(err) = res {
+            let err = err.to_string();
+            state.update(|state| state.io.err = Some(err));
+        }
+        if !buf.is_empty() {
+            write_output(&stdout, &buf).await?;
+        }
+        if read {
+            next_read = Instant::now() + state.with(|state| poll_delay(state)).await;
+        }
+        // A report on the interrupt endpoint brings the next read forward
+        read = tokio::select! {
+            () = sleep_until(next_read) => true,
+            () = next_report(&mut reports) => true,
+            _ = changed.changed() => false,
+        };
+    }
+}
+
+/// Wait for the next interrupt report, forever once the reader stopped so polling takes over
+async fn next_report(reports: &mut Option<watch::Receiver<()>>) {
+    if let Some(rx) = reports
+        && rx.changed().await.is_ok()
+    {
+        return;
+    }
+    *reports = None;
+    future::pending().await
+}
+
+/// How long to wait before the next read, quicker for a while after the last activity
+fn poll_delay(state: &UiState) -> Duration {
+    let interval = state.poll_interval.unwrap_or(POLL_INTERVAL);
+    match state.last_activity {
+        Some(at) if at.elapsed() < FAST_POLL_WINDOW => interval.min(FAST_POLL_INTERVAL),
+        _ => interval,
+    }
+}
+
+/// Apply a single input line to the device
+///
+/// Reads the current config first unless the line asks to `use_cached` or the cache is younger
+/// than [`UiState::max_cache_age`], then writes the merged config. Skips the write and reports
+/// `noop` if that is what the device already has, unless the line is persistent or sets unknown
+/// fields, which only take effect by writing. With [`UiState::verify_writes`], the config is read
+/// back and the write fails if it differs.
+///
+/// A `transaction` is always read back, and the config from before written again if the write
+/// fails or the device didn't take it.
+pub async fn apply<D: DeviceBackend>(device: &D, state: &StateBus, line: Line) -> Result<()> {
+    let persistent = line.persistent;
+    let use_cached = line.use_cached;
+    let forced = persistent.unwrap_or(false) || line.unknown.is_some();
+    let transaction = line.transaction.unwrap_or(false);
+    let (verify, fresh) = state
+        .with(|state| {
+            let fresh = match (state.max_cache_age, state.synced_at) {
+                (Some(age), Some(at)) => at.elapsed() <= age,
+                _ => false,
+            };
+            (state.verify_writes, fresh)
+        })
+        .await;
+
+    // An explicit `"use_cached": false` reads even if the cache is fresh
+    let read = if !use_cached.unwrap_or(fresh) {
+        let timeout = device.timeouts().read;
+        Some(
+            watchdog::guard(device, "read_config", timeout, || {
+                device.read_config(timeout)
+            })
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    let merged = state
+        .with(move |state| {
+            if let Some(config) = read {
+                let config = state.regular(config);
+                state.set_cached(config);
+                state.synced_at = Some(Instant::now());
+            }
+            state.check_revision(&line)?;
+
+            let previous = state.cached;
+            let before = state.outgoing(previous);
+            let config = state.update_state(line)?;
+            let config = state.outgoing(config);
+            if config == before && !forced {
+                state.io.noop = Some(true);
+                return Ok(None);
+            }
+            anyhow::Ok(Some((config, before, previous)))
+        })
+        .await?;
+    let Some((config, before, previous)) = merged else {
+        return Ok(());
+    };
 
-    let (stdin, stdout) = tokio::join!(stdin, stdout);
-    stdin?;
-    stdout?;
+    let mode = match persistent.unwrap_or(false) {
+        true => Mode::Persistant,
+        false => Mode::Temporary,
+    };
+    let res = write_verified(device, state, &config, mode, verify || transaction).await;
+    if transaction {
+        let (err, rejected) = match res {
+            Ok(rejected) if rejected.is_empty() => return Ok(()),
+            Ok(rejected) => (
+                anyhow!("the device didn't take {}", names(&rejected)),
+                rejected,
+            ),
+            Err(err) => (err, Vec::new()),
+        };
+        let previous = (previous, before);
+        return Err(roll_back(device, state, previous, mode, err, rejected).await);
+    }
 
+    let rejected = res?;
+    if !rejected.is_empty() {
+        let names = names(&rejected);
+        let event = Event::WriteRejected { fields: rejected };
+        state.update(|state| state.events.push(event));
+        bail!("the device didn't take {names}");
+    }
     Ok(())
 }
+
+/// Write `config`, and read it back if asked to `verify`, returning the fields that differ
+pub(crate) async fn write_verified<D: DeviceBackend>(
+    device: &D,
+    state: &StateBus,
+    config: &DeviceConfiguration,
+    mode: Mode,
+    verify: bool,
+) -> Result<Vec<RejectedField>> {
+    let timeout = device.timeouts().write;
+    watchdog::guard(device, "write_config", timeout, || {
+        device.write_config(config, mode, timeout)
+    })
+    .await?;
+    let now = Instant::now();
+    state.update(move |state| {
+        state.last_activity = Some(now);
+        state.synced_at = Some(now);
+    });
+    if !verify {
+        return Ok(Vec::new());
+    }
+
+    let timeout = device.timeouts().read;
+    let read = watchdog::guard(device, "read_config", timeout, || {
+        device.read_config(timeout)
+    })
+    .await?;
+    Ok(profile::diff(config, &read)?
+        .into_iter()
+        .map(|(field, written, read)| RejectedField {
+            field,
+            written,
+            read,
+        })
+        .collect())
+}
+
+/// Write the config from before a failed transaction again and report the failure as one event
+///
+/// `previous` is the config before the transaction as cached and as written to the device.
+async fn roll_back<D: DeviceBackend>(
+    device: &D,
+    state: &StateBus,
+    previous: (DeviceConfiguration, DeviceConfiguration),
+    mode: Mode,
+    err: anyhow::Error,
+    rejected: Vec<RejectedField>,
+) -> anyhow::Error {
+    let (cached, outgoing) = previous;
+    let timeout = device.timeouts().write;
+    let res = watchdog::guard(device, "write_config", timeout, || {
+        device.write_config(&outgoing, mode, timeout)
+    })
+    .await;
+
+    let outcome = match &res {
+        Ok(()) => "rolled back",
+        Err(err) => {
+            log::warn!("rolling back the transaction failed: {err:#}");
+            "rolling back failed too"
+        }
+    };
+    let rolled_back = res.is_ok();
+    let event = Event::TransactionFailed {
+        message: format!("{err:#}"),
+        rejected,
+        rolled_back,
+    };
+    state.update(move |state| {
+        if rolled_back {
+            state.set_cached(cached);
+        }
+        state.events.push(event);
+    });
+    anyhow!("transaction failed, {outcome}: {err:#}")
+}
+
+pub(crate) fn names(fields: &[RejectedField]) -> String {
+    let names: Vec<_> = fields.iter().map(|field| field.field.as_str()).collect();
+    names.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::{Fault, MockDevice},
+        quirks, report,
+        usb_device::RetryPolicy,
+    };
+    use tokio::io::{BufReader, DuplexStream, Lines, duplex};
+    use tokio::task::JoinHandle;
+    use tokio::time::{sleep, timeout};
+
+    /// `stdio` running against a mock device, fed and read through in-memory pipes
+    ///
+    /// Time is paused in these tests, so polls happen as soon as everything else is idle.
+    struct Harness {
+        mock: Arc<MockDevice>,
+        input: DuplexStream,
+        output: Lines<BufReader<DuplexStream>>,
+        task: JoinHandle<Result<()>>,
+    }
+
+    impl Harness {
+        async fn start() -> Self {
+            Self::start_with(Options::default()).await
+        }
+
+        /// Start `stdio` and consume the startup event
+        async fn start_with(options: Options) -> Self {
+            Self::start_retrying(options, RetryPolicy::default()).await
+        }
+
+        async fn start_retrying(options: Options, retry: RetryPolicy) -> Self {
+            Self::start_on(options, |mock| Device::mock(mock).with_retry(retry)).await
+        }
+
+        /// Start `stdio` against whatever backend `backend` makes of the mock device
+        async fn start_on<D: DeviceBackend>(
+            options: Options,
+            backend: impl FnOnce(Arc<MockDevice>) -> D,
+        ) -> Self {
+            let mock = Arc::new(MockDevice::new());
+            let unit = Unit {
+                device: backend(Arc::clone(&mock)),
+                state: StateBus::spawn(UiState::default()),
+            };
+            let (input, reader) = duplex(4096);
+            let (writer, output) = duplex(4096);
+            let task = tokio::spawn(stdio(vec![unit], BufReader::new(reader), writer, options));
+
+            let mut harness = Self {
+                mock,
+                input,
+                output: BufReader::new(output).lines(),
+                task,
+            };
+            let startup = harness.next().await;
+            assert_eq!(startup["event"], "startup");
+            // Let the first poll establish its baseline before anything changes
+            sleep(Duration::from_millis(10)).await;
+            harness
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.input.write_all(line.as_bytes()).await.unwrap();
+            self.input.write_all(b"\n").await.unwrap();
+        }
+
+        /// The next output line, without the revision most tests don't care about
+        async fn next(&mut self) -> Value {
+            let mut value = self.next_raw().await;
+            if let Value::Object(map) = &mut value {
+                map.remove("revision");
+            }
+            value
+        }
+
+        async fn next_raw(&mut self) -> Value {
+            let line = self
+                .output
+                .next_line()
+                .await
+                .unwrap()
+                .expect("output ended");
+            serde_json::from_str(&line).unwrap()
+        }
+
+        /// The configuration the mock device holds right now
+        fn config(&self) -> DeviceConfiguration {
+            read(&self.mock.config())
+        }
+    }
+
+    fn read(buf: &[u8]) -> DeviceConfiguration {
+        DeviceConfiguration::read(buf, &quirks::WAVE_XLR_LAYOUT).unwrap()
+    }
+
+    fn write(config: &DeviceConfiguration) -> Vec<u8> {
+        let mut buf = vec![0; quirks::WAVE_XLR_LAYOUT.length];
+        config.write(&mut buf, &quirks::WAVE_XLR_LAYOUT);
+        buf
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn applies_input_lines() {
+        let mut harness = Harness::start().await;
+
+        harness.send(r#"{"gain": "30dB", "mute": true}"#).await;
+        assert_eq!(
+            harness.next().await,
+            json!({"gain": 30 * 256, "gain_raw": 30 * 256, "mute": true}),
+        );
+
+        let config = harness.config();
+        assert_eq!(config.gain, 30 * 256);
+        assert!(config.mute);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn runs_against_any_backend() {
+        async fn check<D: DeviceBackend>(backend: impl FnOnce(Arc<MockDevice>) -> D) {
+            let mut harness = Harness::start_on(Options::default(), backend).await;
+
+            harness.send(r#"{"mute": true}"#).await;
+            assert_eq!(harness.next().await, json!({"mute": true}));
+            assert!(harness.config().mute);
+
+            let mut config = harness.config();
+            config.mix = 80;
+            harness.mock.set_config(&write(&config));
+            assert_eq!(harness.next().await, json!({"mix": 80, "balance": 30}));
+
+            harness.mock.inject(Fault::ShortRead);
+            harness.send(r#"{"mute": false}"#).await;
+            let line = harness.next().await;
+            assert!(line["err"].is_string(), "{line}");
+            assert!(harness.config().mute);
+        }
+
+        check(|mock| Device::mock(mock).with_retry(RetryPolicy::NONE)).await;
+        check(|mock| mock).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_device_side_changes() {
+        let mut harness = Harness::start().await;
+
+        let mut config = harness.config();
+        config.mute = true;
+        config.mix = 80;
+        harness.mock.set_config(&write(&config));
+
+        assert_eq!(
+            harness.next().await,
+            json!({"mute": true, "mix": 80, "balance": 30})
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_device_side_changes_before_the_next_poll() {
+        let mut harness = Harness::start().await;
+
+        let mut config = harness.config();
+        config.mute = true;
+        harness.mock.set_config(&write(&config));
+        harness.mock.send_interrupt();
+
+        let line = timeout(POLL_INTERVAL / 10, harness.next()).await;
+        assert_eq!(line.expect("waited for the poll"), json!({"mute": true}));
+
+        // The same reader picks up the next report
+        config.mute = false;
+        harness.mock.set_config(&write(&config));
+        harness.mock.send_interrupt();
+
+        let line = timeout(POLL_INTERVAL / 10, harness.next()).await;
+        assert_eq!(line.expect("waited for the poll"), json!({"mute": false}));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_events_and_errors_without_waiting_for_the_poll() {
+        let mut harness = Harness::start().await;
+
+        harness.send(r#"{"get": "info"}"#).await;
+        let line = timeout(FAST_POLL_INTERVAL / 2, harness.next()).await;
+        assert_eq!(line.expect("waited for the poll")["event"], "info");
+
+        harness.send("not json").await;
+        let line = timeout(FAST_POLL_INTERVAL / 2, harness.next()).await;
+        let line = line.expect("waited for the poll");
+        assert!(line["err"].is_string(), "{line}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_at_the_requested_interval() {
+        let mut harness = Harness::start().await;
+
+        harness.send(r#"{"poll_interval_ms": 10}"#).await;
+        let line = harness.next().await;
+        assert!(line["err"].is_string(), "{line}");
+
+        harness.send(r#"{"poll_interval_ms": 100}"#).await;
+        assert_eq!(harness.next().await, json!({"noop": true}));
+        // Let the current poll interval run out, so the next one is the requested one
+        sleep(POLL_INTERVAL).await;
+        let mut config = harness.config();
+        config.mute = true;
+        harness.mock.set_config(&write(&config));
+
+        let line = timeout(Duration::from_millis(150), harness.next()).await;
+        assert_eq!(line.expect("waited too long"), json!({"mute": true}));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_quickly_only_for_a_while_after_writes() {
+        let mut harness = Harness::start().await;
+
+        harness.send(r#"{"mute": true}"#).await;
+        harness.next().await;
+        let mut config = harness.config();
+        config.mix = 80;
+        harness.mock.set_config(&write(&config));
+        let line = timeout(FAST_POLL_INTERVAL * 2, harness.next()).await;
+        assert_eq!(
+            line.expect("polled slowly"),
+            json!({"mix": 80, "balance": 30})
+        );
+
+        sleep(FAST_POLL_WINDOW + FAST_POLL_INTERVAL * 2).await;
+        config.mix = 20;
+        harness.mock.set_config(&write(&config));
+        let line = timeout(FAST_POLL_INTERVAL * 2, harness.next()).await;
+        assert!(line.is_err(), "polled quickly: {line:?}");
+        assert_eq!(harness.next().await, json!({"mix": 20, "balance": -30}));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn warns_about_unexpected_reserved_bytes() {
+        let mut harness = Harness::start().await;
+
+        let mut buf = harness.mock.config();
+        buf[3] = 0xed;
+        harness.mock.set_config(&buf);
+        assert_eq!(harness.next().await["event"], "undecoded_change");
+        let event = harness.next().await;
+        assert_eq!(event["event"], "warning");
+        assert!(
+            event["message"].as_str().unwrap().contains("byte 3"),
+            "{event}"
+        );
+
+        // Not again while the byte keeps its value
+        buf[quirks::WAVE_XLR_LAYOUT.mix] = 10;
+        harness.mock.set_config(&buf);
+        assert_eq!(harness.next().await, json!({"mix": 10, "balance": -40}));
+
+        // Nor is it overwritten by changing something else
+        harness.send(r#"{"mute": true}"#).await;
+        assert_eq!(harness.next().await, json!({"mute": true}));
+        assert_eq!(harness.mock.config()[3], 0xed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_before_writing_unless_use_cached() {
+        let mut harness = Harness::start().await;
+        let mut config = harness.config();
+        config.mute = true;
+
+        // The device-side mute is read and kept
+        harness.mock.set_config(&write(&config));
+        harness.send(r#"{"mix": 70}"#).await;
+        assert_eq!(
+            harness.next().await,
+            json!({"mute": true, "mix": 70, "balance": 20})
+        );
+        assert!(harness.config().mute);
+
+        // The cached config without the device-side change is written back
+        config.mute = false;
+        harness.mock.set_config(&write(&config));
+        harness.send(r#"{"mix": 60, "use_cached": true}"#).await;
+        assert_eq!(harness.next().await, json!({"mix": 60, "balance": 10}));
+        assert!(harness.config().mute);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skips_writes_that_change_nothing() {
+        let mut harness = Harness::start().await;
+        let mut config = harness.config();
+
+        harness
+            .send(&json!({"mute": config.mute, "mix": config.mix}).to_string())
+            .await;
+        assert_eq!(harness.next().await, json!({"noop": true}));
+
+        // Persistent lines are written anyway, to store the settings
+        config.mix = 80;
+        harness.mock.set_config(&write(&config));
+        harness.send(r#"{"persistent": true}"#).await;
+        assert_eq!(harness.next().await, json!({"mix": 80, "balance": 30}));
+        assert_eq!(harness.mock.stored(), harness.mock.config());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesces_settings_lines_within_the_window() {
+        let mut harness = Harness::start_with(Options {
+            coalesce: Some(Duration::from_millis(50)),
+            ..Options::default()
+        })
+        .await;
+
+        harness.send(r#"{"gain": "30dB"}"#).await;
+        harness.send(r#"{"mute": true}"#).await;
+        harness.send(r#"{"gain_raw": 8960}"#).await;
+        assert_eq!(
+            harness.next().await,
+            json!({"gain": 8960, "gain_raw": 8960, "mute": true}),
+        );
+        assert_eq!(harness.mock.writes(), 1);
+
+        // Anything else is applied right away, after the settings before it
+        harness.send(r#"{"mute": false}"#).await;
+        harness.send(r#"{"toggle": ["mute"]}"#).await;
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(harness.mock.writes(), 3);
+        assert!(harness.config().mute);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn verifies_writes_when_asked() {
+        let mock = Arc::new(MockDevice::new());
+        let device = Device::mock(Arc::clone(&mock));
+        let state = StateBus::spawn(UiState {
+            verify_writes: true,
+            ..UiState::default()
+        });
+        let line = |json: &str| serde_json::from_str::<Line>(json).unwrap();
+
+        apply(&device, &state, line(r#"{"mute": true}"#))
+            .await
+            .unwrap();
+
+        mock.inject(Fault::Ignore);
+        let err = apply(&device, &state, line(r#"{"mix": 80}"#))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "the device didn't take mix");
+        let events = state.with(|state| mem::take(&mut state.events)).await;
+        assert_eq!(
+            serde_json::to_value(&events).unwrap(),
+            json!([{
+                "event": "write_rejected",
+                "fields": [{"field": "mix", "written": 80, "read": 50}],
+            }]),
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rolls_back_failed_transactions() {
+        let mock = Arc::new(MockDevice::new());
+        let device = Device::mock(Arc::clone(&mock));
+        let state = StateBus::spawn(UiState::default());
+        let line = |json: &str| serde_json::from_str::<Line>(json).unwrap();
+
+        apply(&device, &state, line(r#"{"mute": true}"#))
+            .await
+            .unwrap();
+        let before = state.with(|state| state.cached).await;
+
+        mock.inject(Fault::Ignore);
+        let json = r#"{"mix": 80, "mute": false, "transaction": true}"#;
+        let err = apply(&device, &state, line(json)).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "transaction failed, rolled back: the device didn't take mix, mute",
+        );
+        assert_eq!(read(&mock.config()), before);
+        assert_eq!(state.with(|state| state.cached).await, before);
+        let events = state.with(|state| mem::take(&mut state.events)).await;
+        assert_eq!(
+            serde_json::to_value(&events).unwrap(),
+            json!([{
+                "event": "transaction_failed",
+                "message": "the device didn't take mix, mute",
+                "rejected": [
+                    {"field": "mix", "written": 80, "read": 50},
+                    {"field": "mute", "written": false, "read": true},
+                ],
+                "rolled_back": true,
+            }]),
+        );
+
+        // Without a failure, a transaction is like any other line
+        apply(&device, &state, line(json)).await.unwrap();
+        assert_eq!(read(&mock.config()).mix, 80);
+        assert!(state.with(|state| state.events.is_empty()).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reuses_the_cache_while_it_is_fresh() {
+        let mock = Arc::new(MockDevice::new());
+        let device = Device::mock(Arc::clone(&mock));
+        let state = StateBus::spawn(UiState {
+            max_cache_age: Some(Duration::from_secs(1)),
+            ..UiState::default()
+        });
+        let line = |json: &str| serde_json::from_str::<Line>(json).unwrap();
+        let mut config = read(&mock.config());
+        config.mute = true;
+
+        apply(&device, &state, line(r#"{"mix": 60}"#))
+            .await
+            .unwrap();
+
+        // Not read again, so the device-side mute is lost
+        mock.set_config(&write(&config));
+        apply(&device, &state, line(r#"{"mix": 70}"#))
+            .await
+            .unwrap();
+        assert!(!read(&mock.config()).mute);
+
+        // Unless asked for
+        mock.set_config(&write(&config));
+        let json = r#"{"mix": 80, "use_cached": false}"#;
+        apply(&device, &state, line(json)).await.unwrap();
+        assert!(read(&mock.config()).mute);
+
+        // Or once the cache is old
+        config.mute = false;
+        mock.set_config(&write(&config));
+        sleep(Duration::from_secs(2)).await;
+        apply(&device, &state, line(r#"{"mix": 90}"#))
+            .await
+            .unwrap();
+        let config = read(&mock.config());
+        assert_eq!((config.mute, config.mix), (false, 90));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refuses_lines_for_an_outdated_revision() {
+        let mut harness = Harness::start().await;
+
+        harness.send(r#"{"mute": true}"#).await;
+        let line = harness.next_raw().await;
+        let revision = line["revision"].as_u64().unwrap();
+
+        // The knob turned in between
+        let mut config = harness.config();
+        config.mix = 80;
+        harness.mock.set_config(&write(&config));
+        let json = json!({"mix": 20, "if_revision": revision});
+        harness.send(&json.to_string()).await;
+        assert_eq!(
+            harness.next_raw().await,
+            json!({"event": "conflict", "expected": revision, "revision": revision + 1}),
+        );
+        let line = harness.next_raw().await;
+        assert!(line["err"].is_string(), "{line}");
+        assert_eq!(line["revision"], revision + 1);
+        assert_eq!(harness.config().mix, 80);
+
+        let json = json!({"mix": 20, "if_revision": revision + 1});
+        harness.send(&json.to_string()).await;
+        assert_eq!(
+            harness.next_raw().await,
+            json!({"mix": 20, "balance": -30, "revision": revision + 2}),
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stores_only_persistent_lines() {
+        let mut harness = Harness::start().await;
+        let stored = harness.mock.stored();
+
+        harness.send(r#"{"mute": true}"#).await;
+        harness.next().await;
+        assert!(harness.config().mute);
+        assert_eq!(harness.mock.stored(), stored);
+
+        harness.send(r#"{"mix": 10, "persistent": true}"#).await;
+        harness.next().await;
+        let stored = read(&harness.mock.stored());
+        assert!(stored.mute);
+        assert_eq!(stored.mix, 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_the_raw_buffer() {
+        let mut harness = Harness::start_with(Options {
+            report_raw: true,
+            ..Options::default()
+        })
+        .await;
+        let hex = raw::hex(&harness.mock.config());
+        assert_eq!(harness.next().await, json!({"raw": hex}));
+
+        harness.send(r#"{"get": "raw"}"#).await;
+        assert_eq!(harness.next().await, json!({"event": "raw", "data": hex}));
+
+        harness.send(r#"{"mute": true}"#).await;
+        let line = harness.next().await;
+        assert_eq!(line["raw"], raw::hex(&harness.mock.config()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_colors_as_hex_when_asked() {
+        let mut harness = Harness::start().await;
+        harness.send(r#"{"color_mute": "lime"}"#).await;
+        assert_eq!(harness.next().await, json!({"color_mute": [0, 255, 0]}));
+
+        let mut harness = Harness::start_with(Options {
+            hex_colors: true,
+            tag_device: true,
+            ..Options::default()
+        })
+        .await;
+        harness.send(r#"{"color_mute": "lime"}"#).await;
+        assert_eq!(harness.next().await["fields"]["color_mute"], "#00ff00");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_raw_buffers_only_when_allowed() {
+        let mut config = DeviceConfiguration {
+            mix: 33,
+            ..DeviceConfiguration::default()
+        };
+        let line = json!({"raw": raw::hex(&write(&config)), "persistent": true}).to_string();
+
+        let mut harness = Harness::start().await;
+        let before = harness.config();
+        harness.send(&line).await;
+        let output = harness.next().await;
+        assert!(output["err"].is_string(), "{output}");
+        assert_eq!(harness.config(), before);
+
+        let mut harness = Harness::start_with(Options {
+            allow_raw_write: true,
+            ..Options::default()
+        })
+        .await;
+        harness.send(&line).await;
+        harness.next().await;
+        assert_eq!(harness.config(), config);
+        assert_eq!(read(&harness.mock.stored()), config);
+
+        config.mix = 34;
+        harness
+            .send(&format!(r#"{{"raw": "{}00"}}"#, raw::hex(&write(&config))))
+            .await;
+        let output = harness.next().await;
+        assert!(output["err"].is_string(), "{output}");
+        assert_eq!(harness.config().mix, 33);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exposes_unknown_fields_when_experimental() {
+        let mut harness = Harness::start().await;
+        harness.send(r#"{"unknown": {"byte_11": "05"}}"#).await;
+        let output = harness.next().await;
+        assert!(output["err"].is_string(), "{output}");
+
+        let mut harness = Harness::start_with(Options {
+            experimental: true,
+            ..Options::default()
+        })
+        .await;
+        let unknown = &harness.next().await["unknown"];
+        assert_eq!(unknown["word_2"], "00ec");
+        assert_eq!(unknown["byte_11"], "00");
+
+        harness.send(r#"{"unknown": {"byte_11": "05"}}"#).await;
+        assert_eq!(harness.next().await["event"], "undecoded_change");
+        assert_eq!(harness.next().await["unknown"]["byte_11"], "05");
+        assert_eq!(harness.mock.config()[11], 0x05);
+
+        // Kept on later writes
+        harness.send(r#"{"mute": true}"#).await;
+        harness.next().await;
+        assert_eq!(harness.mock.config()[11], 0x05);
+
+        // Values have to fit the field exactly
+        for value in ["0607", "", "6"] {
+            let line = json!({"unknown": {"byte_11": value}}).to_string();
+            harness.send(&line).await;
+            let output = harness.next().await;
+            assert!(output["err"].is_string(), "{value:?}: {output}");
+        }
+        assert_eq!(harness.mock.config()[11], 0x05);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ends_with_the_input() {
+        let Harness { input, task, .. } = Harness::start().await;
+
+        drop(input);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ends_when_the_output_is_closed() {
+        let Harness {
+            mut input,
+            output,
+            task,
+            ..
+        } = Harness::start().await;
+
+        drop(output);
+        input.write_all(b"{\"get\": \"info\"}\n").await.unwrap();
+        let err = task.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "writing output");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restores_the_startup_config_on_shutdown() {
+        let shutdown = Arc::new(Notify::new());
+        let mut harness = Harness::start_with(Options {
+            shutdown: Some(Arc::clone(&shutdown)),
+            restore_on_exit: true,
+            ..Options::default()
+        })
+        .await;
+        let before = harness.config();
+
+        harness.send(r#"{"mute": true, "mix": 80}"#).await;
+        harness.next().await;
+        assert_ne!(harness.config(), before);
+
+        shutdown.notify_one();
+        harness.task.await.unwrap().unwrap();
+        assert_eq!(read(&harness.mock.config()), before);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_monitoring_after_the_input_when_asked() {
+        let mut harness = Harness::start_with(Options {
+            monitor_after_eof: true,
+            ..Options::default()
+        })
+        .await;
+
+        harness.input.shutdown().await.unwrap();
+        let mut config = harness.config();
+        config.mute = true;
+        harness.mock.set_config(&write(&config));
+        assert_eq!(harness.next().await, json!({"mute": true}));
+        assert!(!harness.task.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_invalid_input() {
+        let mut harness = Harness::start().await;
+
+        harness.send(r#"{"gain": "loud"}"#).await;
+        let line = harness.next().await;
+        assert!(line["err"].is_string(), "{line}");
+
+        harness.send("not json").await;
+        let line = harness.next().await;
+        assert!(line["err"].is_string(), "{line}");
+
+        // Ambiguous together unless they agree, rather than one silently winning
+        for json in [
+            r#"{"gain": "30dB", "gain_raw": 100}"#,
+            r#"{"gain": 30, "gain_raw": 100}"#,
+            r#"{"volume": "-10dB", "volume_raw": -100}"#,
+        ] {
+            harness.send(json).await;
+            let line = harness.next().await;
+            assert!(line["err"].is_string(), "{json}: {line}");
+        }
+        assert_ne!(harness.config().gain, 100);
+
+        harness.send(r#"{"gain": "10dB", "gain_raw": 2560}"#).await;
+        assert_eq!(
+            harness.next().await,
+            json!({"gain": 2560, "gain_raw": 2560})
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejects_bare_numbers_for_levels() {
+        let mut harness = Harness::start().await;
+        let before = harness.config();
+
+        for json in [r#"{"gain": 40}"#, r#"{"volume": -12}"#] {
+            harness.send(json).await;
+            let line = harness.next().await;
+            let err = line["err"].as_str().unwrap();
+            assert!(err.contains("dB\""), "{json}: {err}");
+        }
+        assert_eq!(harness.config(), before);
+        assert_ne!(harness.config().gain, 40);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn echoes_invalid_input_with_context() {
+        let mut harness = Harness::start_with(Options {
+            on_invalid_input: OnInvalidInput::EchoWithContext,
+            ..Options::default()
+        })
+        .await;
+
+        harness.send(r#"{"mute": true}"#).await;
+        harness.next().await;
+        harness.send("{mute: false}").await;
+        let line = harness.next().await;
+        assert_eq!(line["event"], "invalid_input");
+        assert_eq!(line["input"], "{mute: false}");
+        assert_eq!(line["offset"], 15);
+        assert!(line["message"].is_string(), "{line}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn aborts_on_invalid_input_when_asked() {
+        let mut harness = Harness::start_with(Options {
+            on_invalid_input: OnInvalidInput::Abort,
+            ..Options::default()
+        })
+        .await;
+
+        harness.send(r#"{"gain": "loud"}"#).await;
+        let err = harness.task.await.unwrap().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid input at byte 0: {"gain": "loud"}"#
+        );
+        assert_eq!(report::exit_code(&err), report::PROTOCOL_EXIT_CODE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_failed_reads() {
+        let corrupt = Fault::Corrupt {
+            offset: quirks::WAVE_XLR_LAYOUT.mute,
+        };
+        for fault in [
+            Fault::ShortRead,
+            Fault::InvalidBool,
+            Fault::Timeout,
+            corrupt,
+        ] {
+            let mut harness = Harness::start_retrying(Options::default(), RetryPolicy::NONE).await;
+            let before = harness.config();
+
+            harness.mock.inject(fault);
+            harness.send(r#"{"mix": 90}"#).await;
+            let line = harness.next().await;
+            assert!(line["err"].is_string(), "{fault:?}: {line}");
+            assert_eq!(harness.config(), before, "{fault:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_timed_out_transfers() {
+        let mut harness = Harness::start().await;
+
+        harness.mock.inject(Fault::Timeout);
+        harness.send(r#"{"mix": 90}"#).await;
+        assert_eq!(harness.next().await, json!({"mix": 90, "balance": 40}));
+        assert_eq!(harness.config().mix, 90);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recovers_from_transient_stalls() {
+        let mut harness = Harness::start().await;
+
+        // One stalled read and one stalled write, each retried after claiming the interface again
+        harness.mock.inject(Fault::Stall);
+        harness.mock.inject(Fault::Stall);
+        harness.send(r#"{"mix": 90}"#).await;
+        assert_eq!(harness.next().await, json!({"mix": 90, "balance": 40}));
+        assert_eq!(harness.config().mix, 90);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_failed_writes() {
+        let mut harness = Harness::start().await;
+
+        // Stalls again after claiming the interface again and after resetting the device
+        for _ in 0..3 {
+            harness.mock.inject(Fault::Stall);
+        }
+        harness.send(r#"{"mix": 90, "use_cached": true}"#).await;
+        let line = harness.next().await;
+        assert!(line["err"].is_string(), "{line}");
+        assert_ne!(harness.config().mix, 90);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_lost_and_found_devices() {
+        let mut harness = Harness::start().await;
+
+        harness.mock.inject(Fault::Disconnect);
+        assert_eq!(harness.next().await, json!({"event": "device_lost"}));
+        assert_eq!(
+            harness.next().await,
+            json!({"event": "device_found", "serial": "MOCK"})
+        );
+
+        harness.send(r#"{"mix": 90}"#).await;
+        assert_eq!(harness.next().await, json!({"mix": 90, "balance": 40}));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_losing_a_device_that_always_disconnects() {
+        let mut harness = Harness::start().await;
+
+        harness
+            .mock
+            .inject(Fault::RandomDisconnects { percent: 100 });
+        for _ in 0..3 {
+            assert_eq!(harness.next().await, json!({"event": "device_lost"}));
+            assert_eq!(
+                harness.next().await,
+                json!({"event": "device_found", "serial": "MOCK"})
+            );
+        }
+    }
+
+    #[test]
+    fn parses_faults() {
+        for (s, fault) in [
+            ("timeout", Fault::Timeout),
+            ("short-read", Fault::ShortRead),
+            ("corrupt:4", Fault::Corrupt { offset: 4 }),
+            (
+                "random-disconnects:10",
+                Fault::RandomDisconnects { percent: 10 },
+            ),
+        ] {
+            assert_eq!(s.parse::<Fault>(), Ok(fault));
+        }
+        for s in [
+            "melt",
+            "timeout:3",
+            "corrupt",
+            "corrupt:x",
+            "random-disconnects:101",
+        ] {
+            assert!(s.parse::<Fault>().is_err(), "{s}");
+        }
+    }
+}
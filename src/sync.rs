@@ -0,0 +1,51 @@
+//! Copy the settings of one unit to others
+
+use crate::{
+    profile,
+    usb_device::{Device, DeviceOptions, Mode, Selector},
+};
+use anyhow::Result;
+
+/// Print how each target differs from the source, then write the source's settings to them
+///
+/// With `dry_run` only the differences are printed. The units are picked by serial number,
+/// whatever the selector of `options`.
+pub async fn sync(
+    options: &DeviceOptions,
+    source: &str,
+    targets: &[String],
+    persistent: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let open = async |serial: &str| {
+        let options = DeviceOptions {
+            selector: Selector {
+                serial: Some(serial.to_owned()),
+                bus_address: None,
+            },
+            ..options.clone()
+        };
+        Device::try_initialize(&options).await
+    };
+
+    let source = open(source).await?;
+    let config = source.read_config(source.timeouts().read).await?;
+    let mode = match persistent {
+        true => Mode::Persistant,
+        false => Mode::Temporary,
+    };
+
+    for serial in targets {
+        let device = open(serial).await?;
+        let timeouts = device.timeouts();
+        let current = device.read_config(timeouts.read).await?;
+        for (field, target, source) in profile::diff(&current, &config)? {
+            println!("{serial}: {field}: {target} -> {source}");
+        }
+
+        if !dry_run {
+            device.write_config(&config, mode, timeouts.write).await?;
+        }
+    }
+    Ok(())
+}
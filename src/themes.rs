@@ -0,0 +1,102 @@
+//! LED color themes, setting all three colors at once
+//!
+//! Selected with the `theme` field of an input line. Besides the built-in themes, the config can
+//! define its own, which take precedence over built-ins of the same name:
+//!
+//! ```toml
+//! [theme.stream]
+//! color_gen = "#9146ff"
+//! color_mute = "red"
+//! color_gain_reduction = "orange"
+//! ```
+
+use crate::usb_device::Color;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Theme {
+    pub color_gen: Color,
+    pub color_mute: Color,
+    pub color_gain_reduction: Color,
+}
+
+pub const BUILTIN: &[(&str, Theme)] = &[
+    (
+        "classic",
+        Theme {
+            color_gen: Color([0xff, 0xff, 0xff]),
+            color_mute: Color([0xff, 0x00, 0x00]),
+            color_gain_reduction: Color([0xff, 0xa5, 0x00]),
+        },
+    ),
+    (
+        "ocean",
+        Theme {
+            color_gen: Color([0x00, 0x80, 0xff]),
+            color_mute: Color([0xff, 0x40, 0x40]),
+            color_gain_reduction: Color([0x00, 0xff, 0xc0]),
+        },
+    ),
+    (
+        "forest",
+        Theme {
+            color_gen: Color([0x22, 0x8b, 0x22]),
+            color_mute: Color([0xb2, 0x22, 0x22]),
+            color_gain_reduction: Color([0xda, 0xa5, 0x20]),
+        },
+    ),
+    (
+        "sunset",
+        Theme {
+            color_gen: Color([0xff, 0x60, 0x20]),
+            color_mute: Color([0x80, 0x00, 0x80]),
+            color_gain_reduction: Color([0xff, 0xd7, 0x00]),
+        },
+    ),
+    (
+        "night",
+        Theme {
+            color_gen: Color([0x30, 0x10, 0x00]),
+            color_mute: Color([0x40, 0x00, 0x00]),
+            color_gain_reduction: Color([0x30, 0x20, 0x00]),
+        },
+    ),
+];
+
+/// Look up a theme, preferring those defined in the config
+pub fn lookup(name: &str, user: &BTreeMap<String, Theme>) -> Option<Theme> {
+    user.get(name).copied().or_else(|| {
+        BUILTIN
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|&(_, theme)| theme)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_themes_from_the_config() {
+        let user: BTreeMap<String, Theme> = toml::from_str(
+            r##"
+            [ocean]
+            color_gen = "#000080"
+            color_mute = "red"
+            color_gain_reduction = [0, 255, 255]
+            "##,
+        )
+        .unwrap();
+
+        let ocean = lookup("ocean", &user).unwrap();
+        assert_eq!(ocean.color_gen, Color([0x00, 0x00, 0x80]));
+        assert_eq!(ocean.color_gain_reduction, Color([0x00, 0xff, 0xff]));
+        let night = lookup("night", &user).unwrap();
+        assert_eq!(night.color_gen, Color([0x30, 0x10, 0x00]));
+        assert!(lookup("Night", &user).is_none());
+        assert!(lookup("disco", &BTreeMap::new()).is_none());
+    }
+}
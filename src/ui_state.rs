@@ -1,189 +1,216 @@
-use crate::usb_device::{Color, DeviceConfiguration, LowcutFilter};
-use serde::{Deserialize, Serialize};
+use crate::{
+    animation::{Animation, Running},
+    capabilities::Query,
+    color::Dimming,
+    event::Event,
+    policy::Policy,
+    raw,
+    stdio::MIN_POLL_INTERVAL,
+    themes::{self, Theme},
+    usb_device::{Color, DeviceConfiguration, LowcutFilter},
+};
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    str::FromStr,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Highest gain the device accepts, 75dB in device units
+pub(crate) const MAX_GAIN: i32 = 75 * 256;
 
 #[derive(Debug, Default)]
 pub struct UiState {
     pub cached: DeviceConfiguration,
     pub io: Line,
+    pub policy: Policy,
+    /// Events waiting to be written by the output task
+    pub events: Vec<Event>,
+    pub animation: Option<Running>,
+    /// Themes defined in the config
+    pub themes: BTreeMap<String, Theme>,
+    pub dimming: Dimming,
+    /// How often the device is read, [`POLL_INTERVAL`](crate::stdio::POLL_INTERVAL) unless set
+    pub poll_interval: Option<Duration>,
+    /// Last write to the device or change seen on it, after which it's read more often
+    pub last_activity: Option<Instant>,
+    /// Read the config back after every write of a line and fail if it differs
+    pub verify_writes: bool,
+    /// When `cached` was last read from or written to the device
+    pub synced_at: Option<Instant>,
+    /// Apply lines to `cached` without reading the device first while it's at most this old
+    pub max_cache_age: Option<Duration>,
+    /// Bumped whenever `cached` changes, reported with every state line
+    pub revision: u64,
 }
 
 impl UiState {
     pub fn update_device_info(&mut self, config: DeviceConfiguration) -> Line {
+        self.set_cached(config);
+        self.synced_at = Some(Instant::now());
+        self.io.changes(config)
+    }
+
+    /// Whether there are events or the outcome of a line waiting to be written by the output task
+    pub fn has_output(&self) -> bool {
+        !self.events.is_empty() || self.io.err.is_some() || self.io.noop.is_some()
+    }
+
+    /// Replace `cached`, bumping the revision if that changes it
+    pub fn set_cached(&mut self, config: DeviceConfiguration) {
+        if config != self.cached {
+            self.revision += 1;
+        }
         self.cached = config;
-        let Line {
-            gain,
-            mute,
-            clipguard,
-            phantom,
-            lowcut,
-            volume,
-            mix,
-            color_mute,
-            color_gen,
-            gain_lock,
-            color_gain_reduction,
-            clipguard_indicator,
-            lim,
-            persistent: _,
-            use_cached: _,
-            err,
-        } = &mut self.io;
-
-        Line {
-            gain: match gain {
-                Some(gain) if config.gain != *gain => {
-                    *gain = config.gain;
-                    Some(config.gain)
-                }
-                None => {
-                    *gain = Some(config.gain);
-                    Some(config.gain)
-                }
-                _ => None,
-            },
-            mute: match mute {
-                Some(mute) if config.mute != *mute => {
-                    *mute = config.mute;
-                    Some(config.mute)
-                }
-                None => {
-                    *mute = Some(config.mute);
-                    Some(config.mute)
-                }
-                _ => None,
-            },
-            clipguard: match clipguard {
-                Some(clipguard) if config.clipguard != *clipguard => {
-                    *clipguard = config.clipguard;
-                    Some(config.clipguard)
-                }
-                None => {
-                    *clipguard = Some(config.clipguard);
-                    Some(config.clipguard)
-                }
-                _ => None,
-            },
-            phantom: match phantom {
-                Some(phantom) if config.phantom != *phantom => {
-                    *phantom = config.phantom;
-                    Some(config.phantom)
-                }
-                None => {
-                    *phantom = Some(config.phantom);
-                    Some(config.phantom)
-                }
-                _ => None,
-            },
-            lowcut: match lowcut {
-                Some(lowcut) if config.lowcut != *lowcut => {
-                    *lowcut = config.lowcut;
-                    Some(config.lowcut)
-                }
-                None => {
-                    *lowcut = Some(config.lowcut);
-                    Some(config.lowcut)
-                }
-                _ => None,
-            },
-            volume: match volume {
-                Some(volume) if config.volume != *volume => {
-                    *volume = config.volume;
-                    Some(config.volume)
-                }
-                None => {
-                    *volume = Some(config.volume);
-                    Some(config.volume)
-                }
-                _ => None,
-            },
-            mix: match mix {
-                Some(mix) if config.mix != *mix => {
-                    *mix = config.mix;
-                    Some(config.mix)
-                }
-                None => {
-                    *mix = Some(config.mix);
-                    Some(config.mix)
-                }
-                _ => None,
-            },
-            color_mute: match color_mute {
-                Some(color_mute) if config.color_mute != *color_mute => {
-                    *color_mute = config.color_mute;
-                    Some(config.color_mute)
-                }
-                None => {
-                    *color_mute = Some(config.color_mute);
-                    Some(config.color_mute)
-                }
-                _ => None,
-            },
-            color_gen: match color_gen {
-                Some(color_gen) if config.color_gen != *color_gen => {
-                    *color_gen = config.color_gen;
-                    Some(config.color_gen)
-                }
-                None => {
-                    *color_gen = Some(config.color_gen);
-                    Some(config.color_gen)
-                }
-                _ => None,
-            },
-            gain_lock: match gain_lock {
-                Some(gain_lock) if config.gain_lock != *gain_lock => {
-                    *gain_lock = config.gain_lock;
-                    Some(config.gain_lock)
-                }
-                None => {
-                    *gain_lock = Some(config.gain_lock);
-                    Some(config.gain_lock)
-                }
-                _ => None,
-            },
-            color_gain_reduction: match color_gain_reduction {
-                Some(color_gain_reduction)
-                    if config.color_gain_reduction != *color_gain_reduction =>
-                {
-                    *color_gain_reduction = config.color_gain_reduction;
-                    Some(config.color_gain_reduction)
-                }
-                None => {
-                    *color_gain_reduction = Some(config.color_gain_reduction);
-                    Some(config.color_gain_reduction)
-                }
-                _ => None,
-            },
-            clipguard_indicator: match clipguard_indicator {
-                Some(clipguard_indicator) if config.clipguard_indicator != *clipguard_indicator => {
-                    *clipguard_indicator = config.clipguard_indicator;
-                    Some(config.clipguard_indicator)
-                }
-                None => {
-                    *clipguard_indicator = Some(config.clipguard_indicator);
-                    Some(config.clipguard_indicator)
-                }
-                _ => None,
-            },
-            lim: match lim {
-                Some(lim) if config.lim != *lim => {
-                    *lim = config.lim;
-                    Some(config.lim)
-                }
-                None => {
-                    *lim = Some(config.lim);
-                    Some(config.lim)
-                }
-                _ => None,
-            },
-            persistent: None,
-            use_cached: None,
-            err: err.take(),
+    }
+
+    /// Fail with a conflict event if a line expects a different revision than the current one
+    pub fn check_revision(&mut self, line: &Line) -> Result<()> {
+        match line.if_revision {
+            Some(expected) if expected != self.revision => {
+                self.events.push(Event::Conflict {
+                    expected,
+                    revision: self.revision,
+                });
+                bail!(
+                    "expected revision {expected}, but the settings changed since, now at {}",
+                    self.revision
+                );
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// `config` as read from the device, with the colors changed on the way out by an animation
+    /// or dimming replaced by their regular values
+    pub fn regular(&self, mut config: DeviceConfiguration) -> DeviceConfiguration {
+        if let Some(running) = &self.animation {
+            config = running.mask(config, self.cached);
+        }
+
+        let cached = &self.cached;
+        for (color, regular) in [
+            (&mut config.color_mute, cached.color_mute),
+            (&mut config.color_gen, cached.color_gen),
+            (
+                &mut config.color_gain_reduction,
+                cached.color_gain_reduction,
+            ),
+        ] {
+            if *color == self.dimming.apply(regular) {
+                *color = regular;
+            }
         }
+        config
     }
 
-    pub fn update_state(&mut self, line: Line) -> DeviceConfiguration {
-        self.cached.merge(&line);
-        self.cached
+    /// `config` as it should be written to the device, with dimming applied to the colors
+    pub fn outgoing(&self, mut config: DeviceConfiguration) -> DeviceConfiguration {
+        for color in [
+            &mut config.color_mute,
+            &mut config.color_gen,
+            &mut config.color_gain_reduction,
+        ] {
+            *color = self.dimming.apply(*color);
+        }
+        config
+    }
+
+    pub fn update_state(&mut self, mut line: Line) -> Result<DeviceConfiguration> {
+        // Both come with every state line, so agreeing ones are fine to send back
+        if let (Some(gain), Some(raw)) = (line.gain, line.gain_raw)
+            && gain.0 != raw
+        {
+            bail!("gain {gain} and gain_raw {raw} disagree, set only one of them");
+        }
+        if let (Some(volume), Some(raw)) = (line.volume, line.volume_raw)
+            && volume.0 != raw
+        {
+            bail!("volume {volume} and volume_raw {raw} disagree, set only one of them");
+        }
+
+        for toggle in line.toggle.take().unwrap_or_default() {
+            let (field, current) = match toggle {
+                Toggle::Mute => (&mut line.mute, self.cached.mute),
+                Toggle::Clipguard => (&mut line.clipguard, self.cached.clipguard),
+                Toggle::Phantom => (&mut line.phantom, self.cached.phantom),
+                Toggle::GainLock => (&mut line.gain_lock, self.cached.gain_lock),
+                Toggle::ClipguardIndicator => (
+                    &mut line.clipguard_indicator,
+                    self.cached.clipguard_indicator,
+                ),
+                Toggle::Lim => (&mut line.lim, self.cached.lim),
+            };
+            if field.replace(!current).is_some() {
+                bail!("a setting can't be both set and toggled: {toggle:?}");
+            }
+        }
+
+        if let Some(delta) = line.gain_delta.take() {
+            if line.gain.is_some() || line.gain_raw.is_some() {
+                bail!("the gain can't be both set and adjusted");
+            }
+            let gain = (i32::from(self.cached.gain) + delta.0).clamp(0, MAX_GAIN);
+            line.gain_raw = Some(gain as u16);
+        }
+        if let Some(delta) = line.volume_delta.take() {
+            if line.volume.is_some() || line.volume_raw.is_some() {
+                bail!("the volume can't be both set and adjusted");
+            }
+            let volume = (i32::from(self.cached.volume) + delta.0).clamp(i16::MIN.into(), 0);
+            line.volume_raw = Some(volume as i16);
+        }
+        if let Some(delta) = line.mix_delta.take() {
+            if line.mix.is_some() || line.balance.is_some() {
+                bail!("the mix can't be both set and adjusted");
+            }
+            line.mix = Some(self.cached.mix.saturating_add_signed(delta).min(100));
+        }
+
+        if let Some(name) = line.theme.take() {
+            let theme = themes::lookup(&name, &self.themes)
+                .with_context(|| format!("unknown theme `{name}`"))?;
+            line.color_gen.get_or_insert(theme.color_gen);
+            line.color_mute.get_or_insert(theme.color_mute);
+            line.color_gain_reduction
+                .get_or_insert(theme.color_gain_reduction);
+        }
+        if let Some(brightness) = line.brightness.take() {
+            if !(0.0..=1.0).contains(&brightness) {
+                bail!("brightness must be between 0 and 1, got {brightness}");
+            }
+            self.dimming.brightness = brightness;
+        }
+        if let Some(gamma) = line.gamma.take() {
+            if !(0.1..=10.0).contains(&gamma) {
+                bail!("gamma must be between 0.1 and 10, got {gamma}");
+            }
+            self.dimming.gamma = gamma;
+        }
+        if let Some(ms) = line.poll_interval_ms.take() {
+            let interval = Duration::from_millis(ms);
+            if interval < MIN_POLL_INTERVAL {
+                bail!(
+                    "poll_interval_ms must be at least {}, got {ms}",
+                    MIN_POLL_INTERVAL.as_millis()
+                );
+            }
+            self.poll_interval = Some(interval);
+        }
+        if let Some(animation) = line.animation.take() {
+            self.animation = animation.start()?;
+        }
+
+        let mut config = self.cached;
+        config.merge(&line);
+        let events = self.policy.apply(&self.cached, &mut config, &line)?;
+        self.events.extend(events);
+
+        self.set_cached(config);
+        Ok(config)
     }
 }
 
@@ -192,8 +219,19 @@ pub struct Line {
     /// Input Gain
     ///
     /// Input Gain in dB. Range 0dB to 75dB
+    ///
+    /// Taken as a level like `"40dB"`, but reported as a number in device units like it always
+    /// was, so existing readers of the output keep working.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_raw"
+    )]
+    pub gain: Option<Decibel<u16>>,
+
+    /// Input Gain in device units of 1/256 dB
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub gain: Option<u16>,
+    pub gain_raw: Option<u16>,
 
     // Mute
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -214,8 +252,18 @@ pub struct Line {
     /// Monitor Volume
     ///
     /// Monitor volume in dB. Range 0dB to -128dB
+    ///
+    /// Reported as a number in device units, like `gain`.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_raw"
+    )]
+    pub volume: Option<Decibel<i16>>,
+
+    /// Monitor Volume in device units of 1/256 dB
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub volume: Option<i16>,
+    pub volume_raw: Option<i16>,
 
     /// Monitor Mix
     ///
@@ -223,6 +271,12 @@ pub struct Line {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub mix: Option<u8>,
 
+    /// Monitor Mix as balance
+    ///
+    /// Range -50 (only microphone) to +50 (only PC audio), 0 being an even mix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<Balance>,
+
     /// Mute Color
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub color_mute: Option<Color>,
@@ -255,44 +309,301 @@ pub struct Line {
     #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
     pub use_cached: Option<bool>,
 
+    /// Information to report back as an event instead of changing settings
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub get: Option<Query>,
+
+    /// Class control transfer or config buffer to send as is, see [`raw`](crate::raw)
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub raw: Option<raw::Request>,
+
+    /// Unit the line is meant for, by its serial number, when several are connected
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub device: Option<String>,
+
+    /// Change the gain even if gain lock is enabled on the device
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub override_lock: Option<bool>,
+
+    /// Boolean settings to flip, relative to the current state of the device
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub toggle: Option<Vec<Toggle>>,
+
+    /// Change the gain relative to its current level, e.g. `"+3dB"`
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub gain_delta: Option<Decibel<i32>>,
+
+    /// Change the monitor volume relative to its current level, e.g. `"-6dB"`
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub volume_delta: Option<Decibel<i32>>,
+
+    /// Change the monitor mix relative to its current value, in percentage points
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub mix_delta: Option<i8>,
+
+    /// Start or stop an LED animation, see [`animation`](crate::animation)
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub animation: Option<Animation>,
+
+    /// Set all colors that aren't given explicitly from a theme, see [`themes`](crate::themes)
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub theme: Option<String>,
+
+    /// Brightness from 0 to 1 applied to all colors written to the device, which are still
+    /// reported unscaled
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub brightness: Option<f64>,
+
+    /// Gamma applied to all colors written to the device, which are still reported unscaled
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub gamma: Option<f64>,
+
+    /// Read the settings back after writing them, and restore the previous ones if the device
+    /// didn't take all of them
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub transaction: Option<bool>,
+
+    /// Only apply the line if the settings are still at this revision, see [`UiState::revision`]
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub if_revision: Option<u64>,
+
+    /// Read the device every this many milliseconds from now on, at least
+    /// [`MIN_POLL_INTERVAL`]
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_serializing)]
+    pub poll_interval_ms: Option<u64>,
+
+    /// Fields whose meaning isn't known yet, by provisional name with hex values, see
+    /// [`UnknownField`](crate::quirks::UnknownField)
+    ///
+    /// Only reported and accepted with `--experimental`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown: Option<BTreeMap<String, String>>,
+
+    /// Last configuration buffer read from the device as hex, reported with `--report-raw`
+    #[serde(
+        rename = "raw",
+        default,
+        skip_serializing_if = "Option::is_none",
+        skip_deserializing
+    )]
+    pub raw_buffer: Option<String>,
+
+    /// Revision of the settings after the changes of this line, see [`UiState::revision`]
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_deserializing)]
+    pub revision: Option<u64>,
+
+    /// The last input line asked for what the device already had, so nothing was written
+    #[serde(default, skip_serializing_if = "Option::is_none", skip_deserializing)]
+    pub noop: Option<bool>,
+
     #[serde(default, skip_serializing_if = "Option::is_none", skip_deserializing)]
     pub err: Option<String>,
 }
 
-impl Line {
-    pub fn is_empty(&self) -> bool {
-        let Line {
-            gain,
-            mute,
-            clipguard,
-            phantom,
-            lowcut,
-            volume,
-            mix,
-            color_mute,
-            color_gen,
-            gain_lock,
-            color_gain_reduction,
-            clipguard_indicator,
-            lim,
-            err,
-            persistent: _,
-            use_cached: _,
-        } = &self;
-
-        gain.is_none()
-            && mute.is_none()
-            && clipguard.is_none()
-            && phantom.is_none()
-            && lowcut.is_none()
-            && volume.is_none()
-            && mix.is_none()
-            && color_mute.is_none()
-            && color_gen.is_none()
-            && gain_lock.is_none()
-            && color_gain_reduction.is_none()
-            && clipguard_indicator.is_none()
-            && lim.is_none()
-            && err.is_none()
+/// The settings of a [`Line`] that map one to one to a field of [`DeviceConfiguration`], as
+/// `line field => config field`
+///
+/// Merging a line into a config, reporting the changes of a config and checking a line for
+/// settings are all generated from this list, so a new setting only needs adding here and to both
+/// structs. The dB and balance forms are derived from the raw ones by hand.
+macro_rules! settings {
+    ($($line:ident => $config:ident),* $(,)?) => {
+        impl DeviceConfiguration {
+            pub fn merge(&mut self, line: &Line) {
+                if let Some(gain) = line.gain {
+                    self.gain = gain.0;
+                }
+                if let Some(volume) = line.volume {
+                    self.volume = volume.0;
+                }
+                if let Some(balance) = line.balance {
+                    self.mix = balance.mix();
+                }
+                $(
+                    if let Some(value) = line.$line {
+                        self.$config = value;
+                    }
+                )*
+            }
+        }
+
+        impl Line {
+            pub fn is_empty(&self) -> bool {
+                self.gain.is_none()
+                    && self.volume.is_none()
+                    && self.balance.is_none()
+                    $(&& self.$line.is_none())*
+                    && self.unknown.is_none()
+                    && self.raw_buffer.is_none()
+                    && self.noop.is_none()
+                    && self.err.is_none()
+            }
+
+            /// The settings of `config` that differ from the ones last reported in `self`, which
+            /// is updated to them
+            ///
+            /// Also takes the pending error and `noop`, so they're reported once.
+            fn changes(&mut self, config: DeviceConfiguration) -> Line {
+                let mut changes = Line {
+                    $($line: changed(&mut self.$line, config.$config),)*
+                    noop: self.noop.take(),
+                    err: self.err.take(),
+                    ..Line::default()
+                };
+                changes.gain = changes.gain_raw.map(Decibel);
+                changes.volume = changes.volume_raw.map(Decibel);
+                changes.balance = changes.mix.map(Balance::from_mix);
+                changes
+            }
+        }
+    };
+}
+
+settings! {
+    gain_raw => gain,
+    mute => mute,
+    clipguard => clipguard,
+    phantom => phantom,
+    lowcut => lowcut,
+    volume_raw => volume,
+    mix => mix,
+    color_mute => color_mute,
+    color_gen => color_gen,
+    gain_lock => gain_lock,
+    color_gain_reduction => color_gain_reduction,
+    clipguard_indicator => clipguard_indicator,
+    lim => lim,
+}
+
+/// A level of a [`Line`] as its number in device units
+fn serialize_raw<S: Serializer, T: Serialize + Copy>(
+    level: &Option<Decibel<T>>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    level.map(|level| level.0).serialize(serializer)
+}
+
+/// `value` if it differs from `last`, which is updated to it
+fn changed<T: Copy + PartialEq>(last: &mut Option<T>, value: T) -> Option<T> {
+    (*last != Some(value)).then(|| *last.insert(value))
+}
+
+/// Boolean setting that can be flipped with the `toggle` field of a [`Line`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Toggle {
+    Mute,
+    Clipguard,
+    Phantom,
+    GainLock,
+    ClipguardIndicator,
+    Lim,
+}
+
+/// Level in dB, stored in device units of 1/256 dB
+///
+/// Parsed from a string with an explicit unit, like `"40dB"` or `"-12.5dB"`, so it can't be
+/// confused with the raw device units of the `*_raw` fields, and serialized the same way. The
+/// exception are `gain` and `volume` of a [`Line`], which are reported as numbers in device units,
+/// as they were before levels existed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decibel<T>(pub T);
+
+impl<T: Copy + Into<f64>> Display for Decibel<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}dB", self.0.into() / 256.0)
+    }
+}
+
+impl<T: Copy + Into<f64>> Serialize for Decibel<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<T: TryFrom<i64>> FromStr for Decibel<T> {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let db: f64 = s
+            .trim()
+            .strip_suffix("dB")
+            .ok_or_else(|| format!("expected a level like \"40dB\", got {s:?}"))?
+            .trim()
+            .parse()
+            .map_err(|err| format!("{err}"))?;
+
+        let raw = (db * 256.0).round();
+        if !raw.is_finite() {
+            return Err(format!("{s} is out of range"));
+        }
+        T::try_from(raw as i64)
+            .map(Decibel)
+            .map_err(|_| format!("{s} is out of range"))
+    }
+}
+
+impl<'de, T: TryFrom<i64>> Deserialize<'de> for Decibel<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Monitor mix as balance between microphone (-50) and PC audio (+50)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "i8")]
+pub struct Balance(i8);
+
+impl Balance {
+    pub fn from_mix(mix: u8) -> Self {
+        Self(mix.min(100) as i8 - 50)
+    }
+
+    pub fn mix(self) -> u8 {
+        (self.0 + 50) as u8
+    }
+}
+
+impl TryFrom<i8> for Balance {
+    type Error = String;
+
+    fn try_from(balance: i8) -> std::result::Result<Self, Self::Error> {
+        match balance {
+            -50..=50 => Ok(Self(balance)),
+            _ => Err(format!("balance {balance} is outside of -50..=50")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn serializes_levels_with_their_unit() {
+        assert_eq!(
+            serde_json::to_value(Decibel::<i16>(-12 * 256 - 128)).unwrap(),
+            json!("-12.5dB")
+        );
+        assert_eq!("-12.5dB".parse(), Ok(Decibel::<i16>(-12 * 256 - 128)));
+    }
+
+    #[test]
+    fn reports_line_levels_in_device_units() {
+        let line = Line {
+            gain: Some(Decibel(40 * 256)),
+            volume: Some(Decibel(-12 * 256)),
+            ..Line::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&line).unwrap(),
+            json!({"gain": 40 * 256, "volume": -12 * 256})
+        );
+
+        let line: Line = serde_json::from_value(json!({"gain": "40dB"})).unwrap();
+        assert_eq!(line.gain, Some(Decibel(40 * 256)));
     }
 }
@@ -1,96 +1,1018 @@
-use crate::ui_state::Line as UserConfig;
-use anyhow::{Context, Result, anyhow};
+use crate::{
+    capabilities::Capabilities,
+    color,
+    quirks::{self, Layout, Quirks},
+    raw,
+    usb_session::{self, Entry},
+};
+use anyhow::{Context, Result, anyhow, bail};
 use nusb::{
     Interface,
-    transfer::{ControlIn, ControlOut, ControlType, Recipient},
+    transfer::{
+        ControlIn, ControlOut, ControlType, Direction, In, Interrupt, Recipient, TransferError,
+        TransferType,
+    },
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    mem,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use serde::{Deserialize, Serialize};
-use std::{fmt::Display, time::Duration};
+use tokio::{sync::watch, task::JoinHandle};
 
+/// An opened Wave XLR with its vendor interface claimed
+///
+/// Cheap to clone, all clones share the same handle.
 #[derive(Clone)]
 pub struct Device {
-    iface: Interface,
+    handle: Arc<Mutex<Option<Handle>>>,
+    undecoded: Arc<Mutex<Undecoded>>,
+    /// Unit to open again after a reset, so it's the same one with several connected
+    selector: Selector,
+    quirks: &'static Quirks,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+    interrupts: Arc<Mutex<Interrupts>>,
+}
+
+/// The task reading the interrupt IN endpoint, started with the first subscriber
+#[derive(Default)]
+struct Interrupts {
+    reports: watch::Sender<()>,
+    reader: Option<JoinHandle<()>>,
+}
+
+/// Which unit to open and how to talk to it, given to [`Device::try_initialize`] and friends
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceOptions {
+    pub selector: Selector,
+    pub retry: RetryPolicy,
+    pub timeouts: Timeouts,
+}
+
+/// How long a single read or write of the configuration may take before it fails
+///
+/// Part of the [`DeviceOptions`] a device is opened with, or changed with
+/// [`Device::with_timeouts`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub read: Duration,
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            read: Duration::from_secs(1),
+            write: Duration::from_secs(1),
+        }
+    }
+}
+
+/// How often transfers that failed transiently, like by timing out, are tried again
+///
+/// Part of the [`DeviceOptions`] a device is opened with, or changed with [`Device::with_retry`].
+/// Permanent failures, like the device going away, are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one
+    pub retries: u32,
+    /// Wait before the first retry, doubled for every further one up to
+    /// [`RetryPolicy::MAX_BACKOFF`]
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub const NONE: Self = Self {
+        retries: 0,
+        backoff: Duration::ZERO,
+    };
+
+    /// Longest the doubling makes a wait, a longer `backoff` is still waited as is
+    pub const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+    /// Wait before the retry numbered `retry`, counting from 0
+    pub fn wait(&self, retry: u32) -> Duration {
+        let wait = self.backoff.saturating_mul(2u32.saturating_pow(retry));
+        wait.min(Self::MAX_BACKOFF.max(self.backoff))
+    }
+
+    /// The longest an operation can take with all of its attempts timing out after `timeout`
+    pub fn budget(&self, timeout: Duration) -> Duration {
+        let waits = (0..self.retries).map(|retry| self.wait(retry));
+        timeout * (self.retries + 1) + waits.sum::<Duration>()
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 2,
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Which unit to open when several are connected
+///
+/// An empty selector picks the first unit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selector {
+    pub serial: Option<String>,
+    pub bus_address: Option<BusAddress>,
+}
+
+/// The position of a device on the bus, written as `BUS:ADDRESS`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusAddress {
+    pub bus_id: String,
+    pub address: u8,
+}
+
+#[cfg(any(test, feature = "mock"))]
+impl Handle {
+    fn mock(mock: Arc<crate::mock::MockDevice>) -> Self {
+        let quirks = mock.quirks();
+        Self {
+            info: mock.info(),
+            quirks,
+            layout: &quirks.layouts[0],
+            firmware: 0,
+            transport: Transport::Mock(mock),
+        }
+    }
+}
+
+/// What the control transfers go to
+#[derive(Clone)]
+enum Transport {
+    Usb {
+        dev: nusb::Device,
+        iface: Interface,
+    },
+    #[cfg(any(test, feature = "mock"))]
+    Mock(Arc<crate::mock::MockDevice>),
+}
+
+/// Tracks the bytes of the configuration that aren't decoded into any field
+#[derive(Default)]
+struct Undecoded {
+    last: Option<Vec<u8>>,
+    changes: Vec<UndecodedChange>,
+    reserved: Option<Reserved>,
+    unexpected: Vec<ReservedByte>,
+    /// Values set for unknown fields, written instead of their defaults
+    overrides: BTreeMap<&'static str, Vec<u8>>,
+}
+
+/// The bytes of the configuration that usually hold the same value
+///
+/// Their meaning is unknown, so writing the configuration leaves them alone, or puts back what
+/// they usually hold if nothing was read yet.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Reserved(pub Vec<ReservedByte>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ReservedByte {
+    pub offset: usize,
+    /// As read from the device
+    pub value: u8,
+    /// What it usually holds
+    pub usual: u8,
+}
+
+/// A range of undecoded bytes that changed between two reads
+#[derive(Debug, Clone)]
+pub struct UndecodedChange {
+    pub offset: usize,
+    pub before: Vec<u8>,
+    pub after: Vec<u8>,
+}
+
+struct Handle {
+    transport: Transport,
+    info: DeviceInfo,
+    quirks: &'static Quirks,
+    /// Layout for the firmware version, which may change across a reopen after an update
+    layout: &'static Layout,
+    firmware: u16,
+}
+
+/// Identifying information about the opened device
+#[derive(Debug, Serialize, Clone)]
+pub struct DeviceInfo {
+    pub model: String,
+    pub manufacturer: Option<String>,
+    pub serial: Option<String>,
+    pub vendor_id: String,
+    pub product_id: String,
+    /// USB version from `bcdUSB`
+    pub usb_version: String,
+    /// Firmware version as reported by `bcdDevice`
+    pub firmware: String,
+    pub bus_id: String,
+    pub address: u8,
+    /// Number of the claimed vendor interface
+    pub interface: u8,
+}
+
+impl Selector {
+    pub fn is_empty(&self) -> bool {
+        self.serial.is_none() && self.bus_address.is_none()
+    }
+
+    fn matches(&self, dev: &nusb::DeviceInfo) -> bool {
+        self.serial
+            .as_deref()
+            .is_none_or(|serial| dev.serial_number() == Some(serial))
+            && self
+                .bus_address
+                .as_ref()
+                .is_none_or(|bus_address| *bus_address == BusAddress::of(dev))
+    }
+}
+
+impl Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.serial, &self.bus_address) {
+            (Some(serial), Some(bus_address)) => {
+                write!(f, "serial {serial} at bus address {bus_address}")
+            }
+            (Some(serial), None) => write!(f, "serial {serial}"),
+            (None, Some(bus_address)) => write!(f, "bus address {bus_address}"),
+            (None, None) => write!(f, "no selector"),
+        }
+    }
+}
+
+impl BusAddress {
+    fn of(dev: &nusb::DeviceInfo) -> Self {
+        Self {
+            bus_id: dev.bus_id().to_owned(),
+            address: dev.device_address(),
+        }
+    }
+}
+
+impl Display for BusAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.bus_id, self.address)
+    }
+}
+
+impl FromStr for BusAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (bus_id, address) = s
+            .rsplit_once(':')
+            .context("expected BUS:ADDRESS, like 1:7")?;
+        Ok(Self {
+            bus_id: bus_id.to_owned(),
+            address: address.parse().context("invalid address")?,
+        })
+    }
+}
+
+/// Format a binary coded version like `bcdDevice`
+fn bcd(version: u16) -> String {
+    format!(
+        "{}.{}.{}",
+        version >> 8,
+        (version >> 4) & 0xF,
+        version & 0xF
+    )
+}
+
+/// A connected Elgato device, as listed by [`Device::list`]
+#[derive(Debug, Serialize)]
+pub struct Candidate {
+    pub vendor_id: String,
+    pub product_id: String,
+    /// Whether this is a model tidal-wave knows how to control
+    pub supported: bool,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub firmware: String,
+    pub bus_id: String,
+    pub address: u8,
+    /// Number of the vendor interface, if the device has one
+    pub interface: Option<u8>,
+    pub claimable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claim_error: Option<String>,
+}
+
+impl DeviceInfo {
+    /// Describe a device found by enumeration, `None` if it lacks the vendor interface
+    fn new(dev: &nusb::DeviceInfo, quirks: &Quirks) -> Option<Self> {
+        let iface = dev.interfaces().find(|iface| {
+            [iface.class(), iface.subclass(), iface.protocol()] == quirks.interface
+        })?;
+
+        Some(Self {
+            model: dev
+                .product_string()
+                .unwrap_or(quirks.capabilities.model)
+                .to_owned(),
+            manufacturer: dev.manufacturer_string().map(str::to_owned),
+            serial: dev.serial_number().map(str::to_owned),
+            vendor_id: format!("{:04x}", dev.vendor_id()),
+            product_id: format!("{:04x}", dev.product_id()),
+            usb_version: bcd(dev.usb_version()),
+            firmware: bcd(dev.device_version()),
+            bus_id: dev.bus_id().to_owned(),
+            address: dev.device_address(),
+            interface: iface.interface_number(),
+        })
+    }
+
+    /// Identifies the unit among several connected ones: the serial number if it has one,
+    /// otherwise its bus and address
+    pub fn id(&self) -> String {
+        match &self.serial {
+            Some(serial) => serial.clone(),
+            None => format!("{}:{}", self.bus_id, self.address),
+        }
+    }
 }
 
 impl Device {
-    const VENDOR_ID: u16 = 0x0FD9;
-    const PRODUCT_ID: u16 = 0x007D;
+    /// Open the Wave XLR picked by the [`Selector`] of `options` and claim its vendor interface
+    ///
+    /// With an empty selector that's the first connected one.
+    pub async fn try_initialize(options: &DeviceOptions) -> Result<Self> {
+        Self::from_handle(Self::open(&options.selector).await?, options.clone())
+    }
+
+    /// Retry transient failures of this device and its clones according to `policy`
+    pub fn with_retry(self, policy: RetryPolicy) -> Self {
+        Self {
+            retry: policy,
+            ..self
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
 
-    pub async fn try_initialize() -> Result<Self> {
-        let dev = nusb::list_devices()
+    /// Use `timeouts` for this device and its clones
+    pub fn with_timeouts(self, timeouts: Timeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Open every connected Wave XLR, ignoring the selector of `options`
+    pub async fn try_initialize_all(options: &DeviceOptions) -> Result<Vec<Self>> {
+        #[cfg(any(test, feature = "mock"))]
+        if crate::mock::shared().is_some() {
+            let options = DeviceOptions {
+                selector: Selector::default(),
+                ..options.clone()
+            };
+            return Ok(vec![Self::try_initialize(&options).await?]);
+        }
+
+        let devs: Vec<_> = nusb::list_devices().await?.filter(Self::matches).collect();
+        if devs.is_empty() {
+            return Err(MissingDevice(None).into());
+        }
+
+        let mut devices = Vec::new();
+        for dev in &devs {
+            // Units without a serial number, or sharing one, can only be told apart by position
+            let serial = dev.serial_number();
+            let unique = serial.is_some()
+                && devs
+                    .iter()
+                    .filter(|other| other.serial_number() == serial)
+                    .count()
+                    == 1;
+            let selector = match unique {
+                true => Selector {
+                    serial: serial.map(str::to_owned),
+                    bus_address: None,
+                },
+                false => Selector {
+                    serial: None,
+                    bus_address: Some(BusAddress::of(dev)),
+                },
+            };
+            let handle = Self::open(&selector).await?;
+            let options = DeviceOptions {
+                selector,
+                ..options.clone()
+            };
+            devices.push(Self::from_handle(handle, options)?);
+        }
+        Ok(devices)
+    }
+
+    /// Enumerate every connected Elgato device, without keeping any of them open
+    ///
+    /// Each one that has a vendor interface is briefly claimed to tell whether it's usable.
+    pub async fn list() -> Result<Vec<Candidate>> {
+        let mut candidates = Vec::new();
+        for dev in nusb::list_devices().await?.filter(|dev| {
+            quirks::ALL
+                .iter()
+                .any(|quirks| quirks.vendor_id == dev.vendor_id())
+        }) {
+            // Unknown products may still use the interface of a known one
+            let interface = quirks::ALL
+                .iter()
+                .find_map(|quirks| DeviceInfo::new(&dev, quirks))
+                .map(|info| info.interface);
+            let claim_error = match interface {
+                Some(interface) => match dev.open().await {
+                    Ok(opened) => opened.claim_interface(interface).await.err(),
+                    Err(err) => Some(err),
+                }
+                .map(|err| format!("{:#}", with_platform_hint(err))),
+                None => Some("missing interface".to_owned()),
+            };
+
+            candidates.push(Candidate {
+                vendor_id: format!("{:04x}", dev.vendor_id()),
+                product_id: format!("{:04x}", dev.product_id()),
+                supported: Self::matches(&dev),
+                model: dev.product_string().map(str::to_owned),
+                serial: dev.serial_number().map(str::to_owned),
+                firmware: bcd(dev.device_version()),
+                bus_id: dev.bus_id().to_owned(),
+                address: dev.device_address(),
+                interface,
+                claimable: claim_error.is_none(),
+                claim_error,
+            });
+        }
+        Ok(candidates)
+    }
+
+    /// Pins the serial number of the opened unit if it has one, as it survives replugging
+    fn from_handle(handle: Handle, options: DeviceOptions) -> Result<Self> {
+        let DeviceOptions {
+            selector,
+            retry,
+            timeouts,
+        } = options;
+        let selector = match &handle.info.serial {
+            Some(serial) if selector.bus_address.is_none() => Selector {
+                serial: Some(serial.clone()),
+                bus_address: None,
+            },
+            _ => selector,
+        };
+        Ok(Self {
+            selector,
+            quirks: handle.quirks,
+            handle: Arc::new(Mutex::new(Some(handle))),
+            undecoded: Arc::default(),
+            retry,
+            timeouts,
+            interrupts: Arc::default(),
+        })
+    }
+
+    /// Talk to an emulated device instead of a real one
+    #[cfg(any(test, feature = "mock"))]
+    pub fn mock(mock: Arc<crate::mock::MockDevice>) -> Self {
+        Self {
+            selector: Selector::default(),
+            quirks: mock.quirks(),
+            handle: Arc::new(Mutex::new(Some(Handle::mock(mock)))),
+            undecoded: Arc::default(),
+            retry: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            interrupts: Arc::default(),
+        }
+    }
+
+    /// Whether this is an emulated device, which never needs reopening
+    fn is_mock(&self) -> bool {
+        #[cfg(any(test, feature = "mock"))]
+        if let Some(Handle {
+            transport: Transport::Mock(_),
+            ..
+        }) = &*self.handle.lock().unwrap()
+        {
+            return true;
+        }
+        false
+    }
+
+    fn matches(dev: &nusb::DeviceInfo) -> bool {
+        quirks::lookup(dev).is_some()
+    }
+
+    /// Like [`Device::try_initialize`], but retry with exponential backoff until the device shows
+    /// up or `timeout` runs out
+    pub async fn wait_for(options: &DeviceOptions, timeout: Option<Duration>) -> Result<Self> {
+        const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+        let start = Instant::now();
+        let mut backoff = Duration::from_millis(100);
+        loop {
+            let err = match Self::try_initialize(options).await {
+                Ok(device) => return Ok(device),
+                Err(err) => err,
+            };
+            if let Some(timeout) = timeout
+                && start.elapsed() + backoff > timeout
+            {
+                return Err(err.context(format!("device not ready after {timeout:?}")));
+            }
+
+            log::info!("waiting for device: {err:#}, retrying in {backoff:?}");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Open the device picked by `selector`
+    ///
+    /// Fails if a non-empty selector matches several devices, an empty one picks the first.
+    async fn open(selector: &Selector) -> Result<Handle> {
+        #[cfg(any(test, feature = "mock"))]
+        if let Some(mock) = crate::mock::shared() {
+            return Ok(Handle::mock(mock));
+        }
+
+        let mut devs: Vec<_> = nusb::list_devices()
             .await?
-            .find(|dev| dev.vendor_id() == Self::VENDOR_ID && dev.product_id() == Self::PRODUCT_ID)
-            .context("missing device")?;
-        let iface = dev
-            .interfaces()
-            .find(|iface| {
-                iface.class() == 0xFF && iface.subclass() == 0xF0 && iface.protocol() == 0x00
-            })
-            .context("missing interface")?;
+            .filter(|dev| Self::matches(dev) && selector.matches(dev))
+            .collect();
+        let dev = match devs.len() {
+            0 if selector.is_empty() => return Err(MissingDevice(None).into()),
+            0 => return Err(MissingDevice(Some(selector.to_string())).into()),
+            1 => devs.remove(0),
+            n if selector.is_empty() => {
+                log::warn!(
+                    "{n} devices connected, using the first one, choose with --serial or \
+                     --bus-address"
+                );
+                devs.remove(0)
+            }
+            _ => bail!(
+                "{selector} matches several devices, choose with --bus-address: {}",
+                devs.iter()
+                    .map(|dev| format!(
+                        "{} (serial {})",
+                        BusAddress::of(dev),
+                        dev.serial_number().unwrap_or("none")
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let quirks = quirks::lookup(&dev).context("unsupported device")?;
+        let info = DeviceInfo::new(&dev, quirks).context("missing interface")?;
+        let firmware = dev.device_version();
+        let layout = quirks.layout(firmware)?;
+
+        let dev = dev
+            .open()
+            .await
+            .map_err(with_platform_hint)
+            .context(anyhow!("dev"))?;
+        let iface = match dev.claim_interface(info.interface).await {
+            Err(err) if err.kind() == nusb::ErrorKind::Busy => {
+                let hint = match other_instances().as_slice() {
+                    [] => "another program, like Wave Link, is using the device".to_owned(),
+                    pids => format!(
+                        "another tidal-wave instance (pid {}) is already running, send the \
+                         changes to its stdin instead of starting a second one",
+                        pids.iter()
+                            .map(u32::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                };
+                return Err(anyhow!(err).context(format!("claiming the interface failed: {hint}")));
+            }
+            res => res.map_err(with_platform_hint).context(anyhow!("iface"))?,
+        };
+
+        Ok(Handle {
+            transport: Transport::Usb { dev, iface },
+            info,
+            quirks,
+            layout,
+            firmware,
+        })
+    }
+
+    /// The settings this model supports
+    pub fn capabilities(&self) -> Capabilities {
+        self.quirks.capabilities.clone()
+    }
+
+    /// Identifying information about the device, fails while it is being reset
+    pub fn info(&self) -> Result<DeviceInfo> {
+        match &*self.handle.lock().unwrap() {
+            Some(handle) => Ok(handle.info.clone()),
+            None => Err(anyhow!("device is being reset")),
+        }
+    }
+
+    fn transport(&self) -> Result<Transport> {
+        match &*self.handle.lock().unwrap() {
+            Some(handle) => Ok(handle.transport.clone()),
+            None => Err(anyhow!("device is being reset")),
+        }
+    }
+
+    fn layout(&self) -> Result<(&'static Layout, u16)> {
+        match &*self.handle.lock().unwrap() {
+            Some(handle) => Ok((handle.layout, handle.firmware)),
+            None => Err(anyhow!("device is being reset")),
+        }
+    }
+
+    /// Drop the current handle and open the device again, e.g. after it was unplugged
+    pub async fn reopen(&self) -> Result<()> {
+        if self.is_mock() {
+            return Ok(());
+        }
+        self.stop_reader().await;
+        self.handle.lock().unwrap().take();
+        let handle = Self::open(&self.selector).await?;
+        *self.handle.lock().unwrap() = Some(handle);
+        self.restart_reader();
+        Ok(())
+    }
 
-        let dev = dev.open().await.context(anyhow!("dev"))?;
-        let iface = dev
-            .claim_interface(iface.interface_number())
+    /// Release the interface, reset the device and claim the interface again
+    ///
+    /// Last resort for transfers that neither complete nor time out.
+    pub async fn reset(&self) -> Result<()> {
+        if self.is_mock() {
+            return Ok(());
+        }
+        // Dropping the old interface releases the claim, so it can be claimed again below
+        self.stop_reader().await;
+        let dev = self
+            .handle
+            .lock()
+            .unwrap()
+            .take()
+            .map(|Handle { transport, .. }| transport);
+        if let Some(Transport::Usb { dev, .. }) = dev
+            && let Err(err) = dev.reset().await
+        {
+            log::warn!("resetting device failed: {err}");
+        }
+
+        let handle = Self::open(&self.selector)
             .await
-            .context(anyhow!("iface"))?;
+            .context("reopening device after reset")?;
+        *self.handle.lock().unwrap() = Some(handle);
+        self.restart_reader();
+        Ok(())
+    }
+
+    /// Issue an arbitrary device-to-host control request, for probing the protocol
+    pub async fn control_in(&self, control: ControlIn, timeout: Duration) -> Result<Vec<u8>> {
+        let (request, value, index) = (control.request, control.value, control.index);
+        let entry = usb_session::is_recording().then(|| Entry::control_in(&control));
+        let start = Instant::now();
+        let res = match self.transport()? {
+            Transport::Usb { iface, .. } => iface.control_in(control, timeout).await,
+            #[cfg(any(test, feature = "mock"))]
+            Transport::Mock(mock) => mock.control_in(control, timeout).await,
+        };
+        if let Some(entry) = entry {
+            usb_session::record(entry.finish(&res));
+        }
+        trace(
+            "in",
+            request,
+            value,
+            index,
+            start,
+            res.as_deref().ok(),
+            res.as_ref().err(),
+        );
+        Ok(res?)
+    }
+
+    /// Issue an arbitrary host-to-device control request
+    pub async fn control_out(&self, control: ControlOut<'_>, timeout: Duration) -> Result<()> {
+        let (request, value, index, data) =
+            (control.request, control.value, control.index, control.data);
+        let entry = usb_session::is_recording().then(|| Entry::control_out(&control));
+        let start = Instant::now();
+        let res = match self.transport()? {
+            Transport::Usb { iface, .. } => iface.control_out(control, timeout).await,
+            #[cfg(any(test, feature = "mock"))]
+            Transport::Mock(mock) => mock.control_out(control, timeout).await,
+        };
+        if let Some(entry) = entry {
+            usb_session::record(entry.finish(&res.as_ref().map(|()| [0u8; 0])));
+        }
+        trace(
+            "out",
+            request,
+            value,
+            index,
+            start,
+            Some(data),
+            res.as_ref().err(),
+        );
+        Ok(res?)
+    }
 
-        Ok(Self { iface })
+    /// Subscribe to the reports on the interrupt IN endpoint of the interface
+    ///
+    /// `None` if the interface has no such endpoint. The endpoint is opened once and read by a
+    /// single task for all subscribers, which stops when the last one is gone or a transfer
+    /// fails, and is started again by the next subscriber or after [`Device::reopen`]. Whether
+    /// and what the firmware reports there isn't known, so this is only used to read the
+    /// configuration early, never instead of polling it.
+    pub fn interrupts(&self) -> Option<watch::Receiver<()>> {
+        let mut interrupts = self.interrupts.lock().unwrap();
+        let rx = interrupts.reports.subscribe();
+        if interrupts
+            .reader
+            .as_ref()
+            .is_none_or(|reader| reader.is_finished())
+        {
+            interrupts.reader = Some(self.spawn_reader(interrupts.reports.clone())?);
+        }
+        Some(rx)
+    }
+
+    /// Start reading the interrupt endpoint, `None` if there is none or the device is being reset
+    fn spawn_reader(&self, reports: watch::Sender<()>) -> Option<JoinHandle<()>> {
+        let iface = match self.transport().ok()? {
+            Transport::Usb { iface, .. } => iface,
+            #[cfg(any(test, feature = "mock"))]
+            Transport::Mock(mock) => {
+                return Some(tokio::spawn(async move {
+                    loop {
+                        mock.interrupt().await;
+                        if reports.send(()).is_err() {
+                            return;
+                        }
+                    }
+                }));
+            }
+        };
+        let address = iface.descriptor().and_then(|descriptor| {
+            descriptor
+                .endpoints()
+                .find(|endpoint| {
+                    endpoint.transfer_type() == TransferType::Interrupt
+                        && endpoint.direction() == Direction::In
+                })
+                .map(|endpoint| endpoint.address())
+        })?;
+        let mut endpoint = match iface.endpoint::<Interrupt, In>(address) {
+            Ok(endpoint) => endpoint,
+            Err(err) => {
+                log::debug!("opening the interrupt endpoint failed: {err}");
+                return None;
+            }
+        };
+
+        Some(tokio::spawn(async move {
+            // Keep a transfer queued while the last report is handled, so none is missed
+            const IN_FLIGHT: usize = 2;
+
+            loop {
+                while endpoint.pending() < IN_FLIGHT {
+                    let buf = endpoint.allocate(endpoint.max_packet_size());
+                    endpoint.submit(buf);
+                }
+                let completion = endpoint.next_complete().await;
+                if let Err(err) = completion.status {
+                    log::debug!("interrupt transfer on {address:#04x} failed: {err}");
+                    return;
+                }
+                log::trace!(target: "usb", "interrupt {address:#04x}: {}", raw::hex(&completion.buffer));
+                if reports.send(()).is_err() {
+                    return;
+                }
+            }
+        }))
+    }
+
+    /// Stop the reader, so it no longer holds on to the interface
+    async fn stop_reader(&self) {
+        let reader = self.interrupts.lock().unwrap().reader.take();
+        if let Some(reader) = reader {
+            reader.abort();
+            let _ = reader.await;
+        }
     }
 
+    /// Start the reader again on the new interface if anyone is still listening
+    fn restart_reader(&self) {
+        let mut interrupts = self.interrupts.lock().unwrap();
+        if interrupts.reports.receiver_count() > 0 {
+            interrupts.reader = self.spawn_reader(interrupts.reports.clone());
+        }
+    }
+
+    /// Read the current configuration from the device
     pub async fn read_config(&self, timeout: Duration) -> Result<DeviceConfiguration> {
+        let (layout, firmware) = self.layout()?;
+        let control = ControlIn {
+            control_type: ControlType::Class,
+            recipient: Recipient::Endpoint,
+            request: self.quirks.read_request,
+            value: 0x0000,
+            index: self.quirks.index,
+            length: layout.length as u16,
+        };
         let buf_out = self
-            .iface
-            .control_in(
-                ControlIn {
-                    control_type: ControlType::Class,
-                    recipient: Recipient::Endpoint,
-                    request: 0x0085,
-                    value: 0x0000,
-                    index: 0x3300,
-                    length: 34,
-                },
-                timeout,
-            )
+            .retrying("read", || self.control_in(control, timeout))
             .await
             .context("read control")?;
 
-        if buf_out.len() != 34 {
-            return Err(anyhow!("buffer has wrong size"));
+        layout
+            .check(&buf_out, firmware)
+            .and_then(|()| {
+                self.track_undecoded(&buf_out, layout);
+                DeviceConfiguration::read(&buf_out, layout)
+            })
+            // Keeps the whole message, as state lines only report the outermost one
+            .map_err(|err| ProtocolError(format!("{err:#}")).into())
+    }
+
+    fn track_undecoded(&self, buf: &[u8], layout: &Layout) {
+        let mut undecoded = self.undecoded.lock().unwrap();
+
+        let reserved = Reserved::read(buf, layout, &undecoded.overrides);
+        let last = undecoded.reserved.take().unwrap_or_default();
+        if reserved != last {
+            log::debug!("reserved bytes: {reserved}");
+            // Only once per value, not on every read
+            let unexpected = reserved.unexpected().filter(|byte| !last.0.contains(byte));
+            undecoded.unexpected.extend(unexpected);
+        }
+        undecoded.reserved = Some(reserved);
+
+        // A different length means a different layout after a firmware update, not a change
+        if let Some(last) = undecoded.last.replace(buf.to_vec())
+            && last.len() == buf.len()
+        {
+            for range in layout.undecoded {
+                if last[range.clone()] != buf[range.clone()] {
+                    undecoded.changes.push(UndecodedChange {
+                        offset: range.start,
+                        before: last[range.clone()].to_vec(),
+                        after: buf[range.clone()].to_vec(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Changes of undecoded bytes seen by [`Device::read_config`] since the last call
+    ///
+    /// Such a change is a strong hint that the firmware has a feature this tool doesn't model yet.
+    pub fn take_undecoded_changes(&self) -> Vec<UndecodedChange> {
+        mem::take(&mut self.undecoded.lock().unwrap().changes)
+    }
+
+    /// Reserved bytes holding something else than usual, seen by [`Device::read_config`] since the
+    /// last call
+    ///
+    /// Likely a setting this tool doesn't know about, or a different firmware.
+    pub fn take_unexpected_reserved(&self) -> Vec<ReservedByte> {
+        mem::take(&mut self.undecoded.lock().unwrap().unexpected)
+    }
+
+    /// The fields whose meaning isn't known yet, by name, as last read by [`Device::read_config`]
+    pub fn unknown_fields(&self) -> Option<BTreeMap<&'static str, Vec<u8>>> {
+        let (layout, _) = self.layout().ok()?;
+        let buf = self.last_buffer()?;
+        let fields = layout.unknown.iter();
+        Some(
+            fields
+                .map(|field| (field.name, field.read(&buf).to_vec()))
+                .collect(),
+        )
+    }
+
+    /// Write `value` to an unknown field from now on, instead of its default
+    pub fn set_unknown(&self, name: &str, value: Vec<u8>) -> Result<()> {
+        let (layout, _) = self.layout()?;
+        let Some(field) = layout.unknown.iter().find(|field| field.name == name) else {
+            bail!("no unknown field {name}");
+        };
+        if value.len() != field.length {
+            bail!("{name} has {} bytes, got {}", field.length, value.len());
         }
+        let mut undecoded = self.undecoded.lock().unwrap();
+        undecoded.overrides.insert(field.name, value);
+        Ok(())
+    }
 
-        DeviceConfiguration::read(buf_out.split_first_chunk().context("buffer too short")?.0)
+    /// The configuration buffer as last read by [`Device::read_config`]
+    pub fn last_buffer(&self) -> Option<Vec<u8>> {
+        self.undecoded.lock().unwrap().last.clone()
     }
 
+    /// Write a full configuration to the device
+    ///
+    /// Only the fields that differ from the last [`Device::read_config`] are changed, every other
+    /// byte is written back as read. With [`Mode::Persistant`] it survives power cycles, otherwise
+    /// only until the next one.
     pub async fn write_config(
         &self,
         config: &DeviceConfiguration,
         mode: Mode,
         timeout: Duration,
     ) -> Result<()> {
-        let mut buf = [0; 34];
-        config.write(&mut buf);
-        self.iface
-            .control_out(
-                ControlOut {
-                    control_type: ControlType::Class,
-                    recipient: Recipient::Endpoint,
-                    request: 0x0005,
-                    value: mode as _,
-                    index: 0x3300,
-                    data: &buf,
-                },
-                timeout,
-            )
-            .await?;
-        Ok(())
+        let (layout, _) = self.layout()?;
+        // Starting from what the device last reported keeps the bytes this tool doesn't decode
+        let last = self.last_buffer().filter(|buf| buf.len() == layout.length);
+        let base = last.and_then(|buf| Some((DeviceConfiguration::read(&buf, layout).ok()?, buf)));
+        let mut buf = match base {
+            Some((base, mut buf)) => {
+                config.patch(&mut buf, layout, &base);
+                buf
+            }
+            None => {
+                let mut buf = vec![0; layout.length];
+                config.write(&mut buf, layout);
+                buf
+            }
+        };
+        let overrides = self.undecoded.lock().unwrap().overrides.clone();
+        for field in layout.unknown {
+            if let Some(value) = overrides.get(field.name) {
+                buf[field.offset..][..field.length].copy_from_slice(value);
+            }
+        }
+        self.write_buffer(&buf, mode, timeout).await
+    }
+
+    /// Write a configuration buffer as is, without going through [`DeviceConfiguration`]
+    ///
+    /// Only its length is checked, any byte can be set to anything.
+    pub async fn write_buffer(&self, buf: &[u8], mode: Mode, timeout: Duration) -> Result<()> {
+        let (layout, _) = self.layout()?;
+        if buf.len() != layout.length {
+            bail!(
+                "config buffer must be {} bytes, got {}",
+                layout.length,
+                buf.len()
+            );
+        }
+        let control = ControlOut {
+            control_type: ControlType::Class,
+            recipient: Recipient::Endpoint,
+            request: self.quirks.write_request,
+            value: mode as _,
+            index: self.quirks.index,
+            data: buf,
+        };
+        self.retrying("write", || self.control_out(control, timeout))
+            .await
+    }
+
+    /// Run a transfer, trying it again after transient failures according to the retry policy
+    async fn retrying<T, F, Fut>(&self, op: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 0;
+        loop {
+            match f().await {
+                Err(err) if retry < self.retry.retries && is_transient(&err) => {
+                    let wait = self.retry.wait(retry);
+                    retry += 1;
+                    log::warn!(
+                        "{op} failed ({err:#}), retrying in {wait:?} ({retry}/{})",
+                        self.retry.retries
+                    );
+                    tokio::time::sleep(wait).await;
+                }
+                res => return res,
+            }
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy)]
+/// The full configuration of the device, as read and written in one go
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
 pub struct DeviceConfiguration {
     /// Input Gain
     ///
-    /// Input Gain in dB. Range 0dB to 75dB
+    /// Input Gain in device units of 1/256 dB. Range 0dB to 75dB
     pub gain: u16,
 
     // Mute
@@ -107,7 +1029,7 @@ pub struct DeviceConfiguration {
 
     /// Monitor Volume
     ///
-    /// Monitor volume in dB. Range 0dB to -128dB
+    /// Monitor volume in device units of 1/256 dB. Range 0dB to -128dB
     pub volume: i16,
 
     /// Monitor Mix
@@ -136,172 +1058,393 @@ pub struct DeviceConfiguration {
     pub lim: bool,
 }
 
-impl DeviceConfiguration {
-    fn read(buf: &[u8; 34]) -> Result<Self> {
-        Ok(Self {
-            gain: read_field::<0, 2, _>(buf, u16::from_le_bytes),
-            mute: read_bool::<4, 1>(buf)?,
-            clipguard: read_bool::<5, 1>(buf)?,
-            phantom: read_bool::<6, 1>(buf)?,
-            lowcut: try_read_field::<7, 2, _, _>(buf, "Lowcut Filter", |data| {
-                match u16::from_le_bytes(data) {
-                    0x0000 => Ok(LowcutFilter::Off),
-                    0x0001 => Ok(LowcutFilter::Cutoff080Hz),
-                    0x0100 => Ok(LowcutFilter::Cutoff120Hz),
-                    err => Err(err),
-                }
-            })?,
-            volume: read_field::<9, 2, _>(buf, i16::from_le_bytes),
-            mix: read_field::<13, 1, _>(buf, u8::from_le_bytes),
-            color_mute: Color::read::<15, 3>(buf),
-            color_gen: Color::read::<18, 9>(buf),
-            gain_lock: read_bool::<28, 1>(buf)?,
-            color_gain_reduction: Color::read::<29, 3>(buf),
-            clipguard_indicator: read_bool::<32, 1>(buf)?,
-            lim: read_bool::<33, 1>(buf)?,
-        })
+impl Reserved {
+    /// Read the reserved bytes, except those of unknown fields set to some value
+    pub(crate) fn read(
+        buf: &[u8],
+        layout: &Layout,
+        overrides: &BTreeMap<&'static str, Vec<u8>>,
+    ) -> Self {
+        Self(
+            layout
+                .unknown
+                .iter()
+                .filter(|field| !overrides.contains_key(field.name))
+                .filter_map(|field| Some((field.offset, field.default?)))
+                .flat_map(|(offset, default)| (offset..).zip(default))
+                .map(|(offset, &usual)| ReservedByte {
+                    offset,
+                    value: buf[offset],
+                    usual,
+                })
+                .collect(),
+        )
     }
 
-    fn write(&self, buf: &mut [u8; 34]) {
-        write_field::<0, 2>(buf, self.gain.to_le_bytes());
-        write_field::<2, 2>(buf, [0, 0xec]);
-        write_field::<4, 1>(buf, [self.mute as u8]);
-        write_field::<5, 1>(buf, [self.clipguard as u8]);
-        write_field::<6, 1>(buf, [self.phantom as u8]);
-        write_field::<7, 2>(buf, (self.lowcut as u16).to_le_bytes());
-        write_field::<9, 2>(buf, self.volume.to_le_bytes());
-        write_field::<11, 1>(buf, [0u8]);
-
-        // Who knows why this is in the protocol, but it is inside of there apparently *shrug*
-        write_field::<12, 1>(
-            buf,
-            [match self.mix {
-                41 | 47 => 0b0000_0001,
-                _ => 0b0000_0000,
-            }],
-        );
+    /// The bytes that differ from what they usually hold
+    pub fn unexpected(&self) -> impl Iterator<Item = ReservedByte> + '_ {
+        self.0
+            .iter()
+            .copied()
+            .filter(|byte| byte.value != byte.usual)
+    }
+}
 
-        write_field::<13, 1>(buf, self.mix.to_le_bytes());
-        write_field::<14, 1>(buf, [0b0000_0001]);
-        write_field::<15, 3>(buf, self.color_mute.0);
+impl Display for Reserved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}={:02x}", byte.offset, byte.value)?;
+        }
+        Ok(())
+    }
+}
 
-        // For some reasons the protocol includes the base color three times
-        write_field::<18, 3>(buf, self.color_gen.0);
-        write_field::<21, 3>(buf, self.color_gen.0);
-        write_field::<24, 3>(buf, self.color_gen.0);
+impl DeviceConfiguration {
+    /// Write every field, and the default of every unknown field
+    pub(crate) fn write(&self, buf: &mut [u8], layout: &Layout) {
+        for field in layout.unknown {
+            if let Some(default) = field.default {
+                buf[field.offset..][..default.len()].copy_from_slice(default);
+            }
+        }
 
-        write_field::<27, 1>(buf, [0b0000_0001]);
+        self.write_fields(buf, layout, None);
+    }
 
-        write_field::<28, 1>(buf, [self.gain_lock as u8]);
-        write_field::<29, 3>(buf, self.color_gain_reduction.0);
-        write_field::<32, 1>(buf, [self.clipguard_indicator as u8]);
-        write_field::<33, 1>(buf, [self.lim as u8]);
+    /// Write only the fields that differ from `base`, the config `buf` was read as
+    ///
+    /// Every other byte keeps what the device reported, including the ones nobody understands.
+    pub(crate) fn patch(&self, buf: &mut [u8], layout: &Layout, base: &Self) {
+        self.write_fields(buf, layout, Some(base));
     }
+}
 
-    pub fn merge(&mut self, user_config: &UserConfig) {
-        let UserConfig {
-            gain,
-            mute,
-            clipguard,
-            phantom,
-            lowcut,
-            volume,
-            mix,
-            color_mute,
-            color_gen,
-            gain_lock,
-            color_gain_reduction,
-            clipguard_indicator,
-            lim,
-            persistent: _,
-            use_cached: _,
-            err: _,
-        } = user_config;
+/// The fields of [`DeviceConfiguration`] stored at the offset of the same name in a [`Layout`]
+///
+/// Reading and writing are both generated from this one list, so they can't disagree on where a
+/// field is or how it's encoded. The general color and the flag derived from the mix don't fit and
+/// are handled by hand.
+macro_rules! fields {
+    ($($field:ident),* $(,)?) => {
+        impl DeviceConfiguration {
+            pub(crate) fn read(buf: &[u8], layout: &Layout) -> Result<Self> {
+                Ok(Self {
+                    $($field: read_field(buf, layout.$field)?,)*
+                    color_gen: read_field(buf, layout.color_gen[0])?,
+                })
+            }
 
-        if let Some(gain) = gain {
-            self.gain = *gain;
-        }
-        if let Some(mute) = mute {
-            self.mute = *mute;
-        }
-        if let Some(clipguard) = clipguard {
-            self.clipguard = *clipguard;
-        }
-        if let Some(phantom) = phantom {
-            self.phantom = *phantom;
-        }
-        if let Some(lowcut) = lowcut {
-            self.lowcut = *lowcut;
-        }
-        if let Some(volume) = volume {
-            self.volume = *volume;
-        }
-        if let Some(mix) = mix {
-            self.mix = *mix;
-        }
-        if let Some(color_mute) = color_mute {
-            self.color_mute = *color_mute;
-        }
-        if let Some(color_gen) = color_gen {
-            self.color_gen = *color_gen;
-        }
-        if let Some(gain_lock) = gain_lock {
-            self.gain_lock = *gain_lock;
-        }
-        if let Some(color_gain_reduction) = color_gain_reduction {
-            self.color_gain_reduction = *color_gain_reduction;
+            fn write_fields(&self, buf: &mut [u8], layout: &Layout, base: Option<&Self>) {
+                let (all, base) = match base {
+                    Some(base) => (false, *base),
+                    None => (true, *self),
+                };
+
+                $(
+                    if all || self.$field != base.$field {
+                        write_field(buf, layout.$field, self.$field);
+                    }
+                )*
+
+                if all || self.mix != base.mix {
+                    // Who knows why this is in the protocol, but it is inside of there apparently
+                    // *shrug*
+                    let flag: u8 = match self.mix {
+                        41 | 47 => 0b0000_0001,
+                        _ => 0b0000_0000,
+                    };
+                    write_field(buf, layout.mix_flag, flag);
+                }
+
+                if all || self.color_gen != base.color_gen {
+                    // For some reasons the protocol includes the base color three times
+                    for &offset in layout.color_gen {
+                        write_field(buf, offset, self.color_gen);
+                    }
+                }
+            }
         }
-        if let Some(clipguard_indicator) = clipguard_indicator {
-            self.clipguard_indicator = *clipguard_indicator;
+    };
+}
+
+fields! {
+    gain,
+    mute,
+    clipguard,
+    phantom,
+    lowcut,
+    volume,
+    mix,
+    color_mute,
+    gain_lock,
+    color_gain_reduction,
+    clipguard_indicator,
+    lim,
+}
+
+/// How a field is encoded in the configuration buffer
+trait Codec: Sized {
+    /// Number of bytes
+    const LEN: usize;
+    /// What is expected, for errors
+    const NAME: &str;
+    /// `None` if the bytes aren't a valid value
+    fn decode(bytes: &[u8]) -> Option<Self>;
+    fn encode(self, bytes: &mut [u8]);
+}
+
+impl Codec for bool {
+    const LEN: usize = 1;
+    const NAME: &str = "bool";
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes[0] {
+            0b0000_0000 => Some(false),
+            0b0000_0001 => Some(true),
+            _ => None,
         }
-        if let Some(lim) = lim {
-            self.lim = *lim;
+    }
+    fn encode(self, bytes: &mut [u8]) {
+        bytes[0] = self as u8;
+    }
+}
+
+impl Codec for u8 {
+    const LEN: usize = 1;
+    const NAME: &str = "u8";
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes[0])
+    }
+    fn encode(self, bytes: &mut [u8]) {
+        bytes[0] = self;
+    }
+}
+
+impl Codec for u16 {
+    const LEN: usize = 2;
+    const NAME: &str = "u16";
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(Self::from_le_bytes(bytes.try_into().ok()?))
+    }
+    fn encode(self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Codec for i16 {
+    const LEN: usize = 2;
+    const NAME: &str = "i16";
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(Self::from_le_bytes(bytes.try_into().ok()?))
+    }
+    fn encode(self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Codec for Color {
+    const LEN: usize = 3;
+    const NAME: &str = "color";
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+    fn encode(self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.0);
+    }
+}
+
+impl Codec for LowcutFilter {
+    const LEN: usize = 2;
+    const NAME: &str = "Lowcut Filter";
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let value = u16::decode(bytes)?;
+        Self::ALL.into_iter().find(|lowcut| *lowcut as u16 == value)
+    }
+    fn encode(self, bytes: &mut [u8]) {
+        (self as u16).encode(bytes);
+    }
+}
+
+fn read_field<T: Codec>(buf: &[u8], offset: usize) -> Result<T> {
+    let bytes = &buf[offset..][..T::LEN];
+    T::decode(bytes).with_context(|| {
+        let (name, len, got) = (T::NAME, T::LEN, crate::raw::hex(bytes));
+        format!("expected {name} at {offset}:{len} got {got}")
+    })
+}
+
+fn write_field<T: Codec>(buf: &mut [u8], offset: usize, value: T) {
+    value.encode(&mut buf[offset..][..T::LEN]);
+}
+
+/// Log a control transfer with a hexdump of its data at trace level, with the target `usb`
+///
+/// Enabled with `--trace-usb` or `RUST_LOG=usb=trace`.
+fn trace(
+    direction: &str,
+    request: u8,
+    value: u16,
+    index: u16,
+    start: Instant,
+    data: Option<&[u8]>,
+    err: Option<&TransferError>,
+) {
+    if !log::log_enabled!(target: "usb", log::Level::Trace) {
+        return;
+    }
+
+    let elapsed_us = start.elapsed().as_micros() as u64;
+    let mut dump = String::new();
+    for (i, line) in data.unwrap_or_default().chunks(16).enumerate() {
+        dump.push_str(&format!("\n{:04x} ", i * 16));
+        for byte in line {
+            dump.push_str(&format!(" {byte:02x}"));
         }
     }
+    let params = format!("request={request:#04x} value={value:#06x} index={index:#06x}");
+    match err {
+        Some(err) => log::trace!(
+            target: "usb", direction, request, value, index, elapsed_us, error:% = err;
+            "control {direction} {params} failed: {err}{dump}"
+        ),
+        None => log::trace!(
+            target: "usb", direction, request, value, index, elapsed_us;
+            "control {direction} {params}{dump}"
+        ),
+    }
 }
 
-fn read_field<const OFFSET: usize, const LEN: usize, T>(
-    buf: &[u8; 34],
-    f: impl FnOnce([u8; LEN]) -> T,
-) -> T {
-    let data = *buf
-        .get(OFFSET..(OFFSET + LEN))
-        .expect("failed to get slice")
-        .first_chunk()
-        .expect("couldn't get array");
-    f(data)
+/// No device matched, with the selector if there was one
+#[derive(Debug)]
+pub struct MissingDevice(pub Option<String>);
+
+impl Display for MissingDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(selector) => write!(f, "missing device with {selector}"),
+            None => write!(f, "missing device"),
+        }
+    }
 }
 
-fn try_read_field<const OFFSET: usize, const LEN: usize, T, E: Display>(
-    buf: &[u8; 34],
-    typ: &str,
-    f: impl FnOnce([u8; LEN]) -> std::result::Result<T, E>,
-) -> Result<T> {
-    let res = read_field::<OFFSET, LEN, _>(buf, f);
-    match res {
-        Ok(ok) => Ok(ok),
-        Err(err) => Err(anyhow!("expected {typ} at {OFFSET}:{LEN} got {err}")),
+impl std::error::Error for MissingDevice {}
+
+/// The device or an input line didn't follow the protocol, either the error or its context
+#[derive(Debug)]
+pub struct ProtocolError(pub String);
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
-fn read_bool<const OFFSET: usize, const LEN: usize>(buf: &[u8; 34]) -> Result<bool> {
-    try_read_field::<OFFSET, 1, _, _>(buf, "bool", |b| match u8::from_be_bytes(b) {
-        0b0000_0000 => Ok(false),
-        0b0000_0001 => Ok(true),
-        err => Err(err),
+impl std::error::Error for ProtocolError {}
+
+/// Whether an error was caused by no device being connected
+pub fn is_missing(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| err.is::<MissingDevice>())
+}
+
+/// Whether an error was caused by not being allowed to open the device
+pub fn is_permission_denied(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        err.downcast_ref::<nusb::Error>()
+            .is_some_and(|err| err.kind() == nusb::ErrorKind::PermissionDenied)
+            || err
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|err| err.kind() == std::io::ErrorKind::PermissionDenied)
     })
 }
 
-fn write_field<const OFFSET: usize, const LEN: usize>(buf: &mut [u8; 34], src: [u8; LEN]) {
-    let buf: &mut [u8; LEN] = buf
-        .get_mut(OFFSET..(OFFSET + LEN))
-        .expect("failed to get slice")
-        .first_chunk_mut()
-        .expect("couldn't get array");
+/// Whether an error has a [`ProtocolError`] as context
+pub fn is_protocol_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ProtocolError>().is_some()
+}
+
+/// Whether an error was caused by the device going away
+pub fn is_disconnected(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        matches!(
+            err.downcast_ref::<TransferError>(),
+            Some(TransferError::Disconnected)
+        ) || err
+            .downcast_ref::<nusb::Error>()
+            .is_some_and(|err| err.kind() == nusb::ErrorKind::Disconnected)
+    })
+}
+
+/// Whether an error is likely gone when trying again, like a transfer timing out
+///
+/// Stalls aren't, they need the interface claimed again, see
+/// [`watchdog::guard`](crate::watchdog::guard).
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        matches!(
+            err.downcast_ref::<TransferError>(),
+            Some(TransferError::Cancelled | TransferError::Fault | TransferError::Unknown(_))
+        )
+    })
+}
+
+/// Whether an error was caused by the device stalling a transfer
+pub fn is_stall(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        matches!(
+            err.downcast_ref::<TransferError>(),
+            Some(TransferError::Stall)
+        )
+    })
+}
+
+/// Attach an explanation to errors that have a known, platform-specific cause
+fn with_platform_hint(err: nusb::Error) -> anyhow::Error {
+    let hint = match err.kind() {
+        #[cfg(target_os = "linux")]
+        nusb::ErrorKind::PermissionDenied => Some(
+            "no permission to access the device, add a udev rule granting your user access to \
+             0fd9:007d",
+        ),
+        #[cfg(target_os = "macos")]
+        nusb::ErrorKind::PermissionDenied => Some(
+            "macOS denied access to the device, signed or sandboxed builds need the \
+             com.apple.security.device.usb entitlement",
+        ),
+        _ => None,
+    };
+
+    match hint {
+        Some(hint) => anyhow!(err).context(hint),
+        None => err.into(),
+    }
+}
+
+/// Pids of other running tidal-wave processes, which are the likeliest to hold the interface
+#[cfg(target_os = "linux")]
+fn other_instances() -> Vec<u32> {
+    let own = std::process::id();
+    let Ok(procs) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    procs
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|&pid| pid != own)
+        .filter(|pid| {
+            std::fs::read_to_string(format!("/proc/{pid}/comm"))
+                .is_ok_and(|comm| comm.trim_end() == env!("CARGO_PKG_NAME"))
+        })
+        .collect()
+}
 
-    buf.copy_from_slice(&src);
+#[cfg(not(target_os = "linux"))]
+fn other_instances() -> Vec<u32> {
+    Vec::new()
 }
 
+/// Cutoff frequency of the low cut filter
 #[repr(u16)]
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LowcutFilter {
@@ -311,17 +1454,297 @@ pub enum LowcutFilter {
     Cutoff120Hz = 0x0001,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Color([u8; 3]);
+impl LowcutFilter {
+    pub const ALL: [Self; 3] = [Self::Off, Self::Cutoff080Hz, Self::Cutoff120Hz];
+}
+
+/// An LED color as `[red, green, blue]`
+///
+/// Deserialized from either a byte array, a hex string like `"#ff8800"` or `"ff8800"` or a CSS
+/// color name like `"teal"`, or from HSV/HSL like `{"h": 120, "s": 1.0, "v": 0.8}`.
+/// Serialized as a byte array, wrap the output in [`color::hex_colors`] for hex strings instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Color(pub [u8; 3]);
+
+impl Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Color([r, g, b]) = self;
+        write!(f, "#{r:02x}{g:02x}{b:02x}")
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(color) = color::named(s) {
+            return Ok(color);
+        }
+
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!(
+                "expected a hex color like \"#ff8800\" or a CSS color name, got {s:?}"
+            ));
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).expect("checked above");
+        Ok(Color([channel(0), channel(2), channel(4)]))
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(color::NEWTYPE, &self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct Visitor;
 
-impl Color {
-    fn read<const OFFSET: usize, const LEN: usize>(buf: &[u8; 34]) -> Self {
-        read_field::<OFFSET, 3, _>(buf, Color)
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Color;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(
+                    "a color as [r, g, b], a hex string, a color name or {h, s, v}/{h, s, l}",
+                )
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> std::result::Result<Color, E> {
+                s.parse().map_err(E::custom)
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(
+                self,
+                seq: A,
+            ) -> std::result::Result<Color, A::Error> {
+                <[u8; 3]>::deserialize(de::value::SeqAccessDeserializer::new(seq)).map(Color)
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> std::result::Result<Color, A::Error> {
+                color::Hsx::deserialize(de::value::MapAccessDeserializer::new(map))?
+                    .try_into()
+                    .map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
     }
 }
 
+/// Whether a written configuration survives power cycles
 #[repr(u16)]
+#[derive(Clone, Copy)]
 pub enum Mode {
+    /// Lost on the next power cycle
     Temporary = 0x0000,
+    /// Stored on the device
     Persistant = 0x0002,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::WAVE_XLR_LAYOUT as LAYOUT;
+    use proptest::prelude::*;
+
+    fn lowcut() -> impl Strategy<Value = LowcutFilter> {
+        proptest::sample::select(&LowcutFilter::ALL[..])
+    }
+
+    fn config() -> impl Strategy<Value = DeviceConfiguration> {
+        let levels = (any::<u16>(), lowcut(), any::<i16>(), any::<u8>());
+        let switches = any::<[bool; 6]>();
+        let colors = any::<[[u8; 3]; 3]>();
+        (levels, switches, colors).prop_map(
+            |(
+                (gain, lowcut, volume, mix),
+                [
+                    mute,
+                    clipguard,
+                    phantom,
+                    gain_lock,
+                    clipguard_indicator,
+                    lim,
+                ],
+                [color_mute, color_gen, color_gain_reduction],
+            )| DeviceConfiguration {
+                gain,
+                mute,
+                clipguard,
+                phantom,
+                lowcut,
+                volume,
+                mix,
+                color_mute: Color(color_mute),
+                color_gen: Color(color_gen),
+                gain_lock,
+                color_gain_reduction: Color(color_gain_reduction),
+                clipguard_indicator,
+                lim,
+            },
+        )
+    }
+
+    /// Any buffer the device could report, with valid booleans and lowcut
+    fn buffer() -> impl Strategy<Value = Vec<u8>> {
+        let bools = [
+            LAYOUT.mute,
+            LAYOUT.clipguard,
+            LAYOUT.phantom,
+            LAYOUT.gain_lock,
+            LAYOUT.clipguard_indicator,
+            LAYOUT.lim,
+        ];
+        let bytes = proptest::collection::vec(any::<u8>(), LAYOUT.length);
+        (bytes, lowcut()).prop_map(move |(mut buf, lowcut)| {
+            for offset in bools {
+                buf[offset] &= 0x01;
+            }
+            buf[LAYOUT.lowcut..][..2].copy_from_slice(&(lowcut as u16).to_le_bytes());
+            buf
+        })
+    }
+
+    /// Clear the bytes `write` doesn't reproduce: the unknown fields, which it writes with their
+    /// defaults, and the repetitions of the general color, which `read` ignores
+    fn without_quirks(mut buf: Vec<u8>) -> Vec<u8> {
+        for field in LAYOUT.unknown {
+            buf[field.offset..][..field.length].fill(0);
+        }
+        for &offset in &LAYOUT.color_gen[1..] {
+            buf[offset..][..3].fill(0);
+        }
+        buf
+    }
+
+    proptest! {
+        #[test]
+        fn write_then_read_roundtrips(config in config()) {
+            let mut buf = vec![0; LAYOUT.length];
+            config.write(&mut buf, &LAYOUT);
+            prop_assert_eq!(DeviceConfiguration::read(&buf, &LAYOUT).unwrap(), config);
+        }
+
+        #[test]
+        fn read_then_write_reproduces_the_buffer(buf in buffer()) {
+            let config = DeviceConfiguration::read(&buf, &LAYOUT).unwrap();
+            let mut written = vec![0; LAYOUT.length];
+            config.write(&mut written, &LAYOUT);
+            prop_assert_eq!(without_quirks(written), without_quirks(buf));
+        }
+
+        #[test]
+        fn patch_only_touches_changed_fields(buf in buffer(), config in config()) {
+            let base = DeviceConfiguration::read(&buf, &LAYOUT).unwrap();
+            let mut patched = buf.clone();
+            config.patch(&mut patched, &LAYOUT, &base);
+            prop_assert_eq!(DeviceConfiguration::read(&patched, &LAYOUT).unwrap(), config);
+
+            base.patch(&mut patched, &LAYOUT, &config);
+            prop_assert_eq!(without_quirks(patched), without_quirks(buf));
+        }
+    }
+
+    #[test]
+    fn lowcut_matches_the_dissector() {
+        let mut buf = vec![0; LAYOUT.length];
+        for (bytes, lowcut) in [
+            ([0x00, 0x00], LowcutFilter::Off),
+            ([0x00, 0x01], LowcutFilter::Cutoff080Hz),
+            ([0x01, 0x00], LowcutFilter::Cutoff120Hz),
+        ] {
+            buf[LAYOUT.lowcut..][..2].copy_from_slice(&bytes);
+            let config = DeviceConfiguration::read(&buf, &LAYOUT).unwrap();
+            assert_eq!(config.lowcut, lowcut);
+        }
+    }
+
+    #[test]
+    fn doubles_the_backoff_up_to_a_cap() {
+        let ms = Duration::from_millis;
+        let retry = RetryPolicy {
+            retries: 8,
+            backoff: ms(50),
+        };
+        let waits: Vec<_> = (0..retry.retries).map(|n| retry.wait(n)).collect();
+        assert_eq!(waits, [50, 100, 200, 400, 800, 1600, 3200, 5000].map(ms));
+        assert_eq!(retry.wait(u32::MAX), RetryPolicy::MAX_BACKOFF);
+        assert_eq!(retry.budget(ms(100)), ms(9 * 100 + 11350));
+
+        let slow = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_secs(10),
+        };
+        assert_eq!(slow.wait(1), Duration::from_secs(10));
+        assert_eq!(RetryPolicy::NONE.budget(ms(100)), ms(100));
+    }
+
+    #[test]
+    fn tells_transient_from_permanent_failures() {
+        let err = |err: TransferError| anyhow::Error::new(err).context("read control");
+        for transfer in [
+            TransferError::Cancelled,
+            TransferError::Fault,
+            TransferError::Unknown(0),
+        ] {
+            let err = err(transfer);
+            assert!(is_transient(&err), "{err:#}");
+            assert!(!is_stall(&err) && !is_disconnected(&err), "{err:#}");
+        }
+
+        assert!(is_stall(&err(TransferError::Stall)));
+        assert!(is_disconnected(&err(TransferError::Disconnected)));
+        for transfer in [
+            TransferError::Stall,
+            TransferError::Disconnected,
+            TransferError::InvalidArgument,
+        ] {
+            assert!(!is_transient(&err(transfer)));
+        }
+        assert!(!is_transient(&anyhow!("config buffer must be 34 bytes")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_transient_failures_as_often_as_allowed() {
+        use crate::mock::{Fault, MockDevice};
+        use tokio::time::Instant;
+
+        let ms = Duration::from_millis;
+        let mock = Arc::new(MockDevice::new());
+        let device = Device::mock(Arc::clone(&mock)).with_retry(RetryPolicy {
+            retries: 2,
+            backoff: ms(100),
+        });
+
+        // Two timeouts are retried after waiting 100ms and 200ms
+        mock.inject(Fault::Timeout);
+        mock.inject(Fault::Timeout);
+        let start = Instant::now();
+        device.read_config(ms(10)).await.unwrap();
+        assert_eq!(start.elapsed(), ms(2 * 10 + 100 + 200));
+
+        // A third one is one too many
+        for _ in 0..3 {
+            mock.inject(Fault::Timeout);
+        }
+        let err = device.read_config(ms(10)).await.unwrap_err();
+        assert!(is_transient(&err), "{err:#}");
+        device.read_config(ms(10)).await.unwrap();
+
+        // Permanent failures are given up on right away, leaving the faults after them unused
+        for fault in [Fault::Stall, Fault::Disconnect] {
+            mock.inject(fault);
+            mock.inject(Fault::Timeout);
+            let start = Instant::now();
+            assert!(device.read_config(ms(10)).await.is_err(), "{fault:?}");
+            assert_eq!(start.elapsed(), Duration::ZERO, "{fault:?}");
+            device.read_config(ms(10)).await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Recording every control transfer of a session and replaying them
+//!
+//! Unlike [`script`](crate::script), which records input lines, this works below the protocol,
+//! so it also captures the reads and what the device answered. A recording has one JSON object
+//! per transfer:
+//!
+//! ```json
+//! {"delay_ms":0,"direction":"in","kind":"class","recipient":"endpoint","request":133,"value":0,"index":13056,"length":34,"data":"0028..."}
+//! {"delay_ms":12,"direction":"out","kind":"class","recipient":"endpoint","request":5,"value":1,"index":13056,"data":"0028..."}
+//! ```
+
+use crate::{
+    probe::{Kind, Recipient},
+    raw,
+    usb_device::Device,
+};
+use anyhow::{Context, Result};
+use nusb::transfer::{ControlIn, ControlOut};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+
+/// Timeout of the replayed transfers
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub delay_ms: u64,
+    pub direction: Direction,
+    pub kind: Kind,
+    pub recipient: Recipient,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// Requested length of IN transfers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u16>,
+    /// Hex encoded payload of OUT transfers or response to IN transfers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+struct Recorder {
+    file: File,
+    last: Instant,
+}
+
+/// The recording of this process, if `--record-usb` was given
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+/// Record every control transfer from now on to a new file at `path`
+pub fn start(path: &Path) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+    *RECORDER.lock().unwrap() = Some(Recorder {
+        file,
+        last: Instant::now(),
+    });
+    Ok(())
+}
+
+/// Whether transfers are being recorded
+pub(crate) fn is_recording() -> bool {
+    RECORDER.lock().unwrap().is_some()
+}
+
+/// Record a finished transfer, if recording
+///
+/// Errors writing the recording are logged rather than failing the transfer.
+pub(crate) fn record(entry: Entry) {
+    let mut recorder = RECORDER.lock().unwrap();
+    let Some(recorder) = &mut *recorder else {
+        return;
+    };
+
+    let now = Instant::now();
+    let entry = Entry {
+        delay_ms: (now - recorder.last).as_millis() as u64,
+        ..entry
+    };
+    recorder.last = now;
+
+    let res = serde_json::to_vec(&entry)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut buf| {
+            buf.push(b'\n');
+            // Written in one go, so an interrupted session leaves a usable recording behind
+            Ok(recorder.file.write_all(&buf)?)
+        });
+    if let Err(err) = res {
+        log::warn!("recording transfer failed: {err:#}");
+    }
+}
+
+impl Entry {
+    /// An IN transfer about to be sent, see [`Entry::finish`]
+    pub(crate) fn control_in(control: &ControlIn) -> Self {
+        Self {
+            delay_ms: 0,
+            direction: Direction::In,
+            kind: control.control_type.into(),
+            recipient: control.recipient.into(),
+            request: control.request,
+            value: control.value,
+            index: control.index,
+            length: Some(control.length),
+            data: None,
+            error: None,
+        }
+    }
+
+    /// An OUT transfer about to be sent, see [`Entry::finish`]
+    pub(crate) fn control_out(control: &ControlOut) -> Self {
+        Self {
+            delay_ms: 0,
+            direction: Direction::Out,
+            kind: control.control_type.into(),
+            recipient: control.recipient.into(),
+            request: control.request,
+            value: control.value,
+            index: control.index,
+            length: None,
+            data: Some(raw::hex(control.data)),
+            error: None,
+        }
+    }
+
+    /// Add the outcome of the transfer
+    pub(crate) fn finish<E: Display>(self, res: &Result<impl AsRef<[u8]>, E>) -> Self {
+        match res {
+            Ok(data) if self.direction == Direction::In => Self {
+                data: Some(raw::hex(data.as_ref())),
+                ..self
+            },
+            Ok(_) => self,
+            Err(err) => Self {
+                error: Some(err.to_string()),
+                ..self
+            },
+        }
+    }
+}
+
+/// Send the transfers of a recording to the device again, keeping their timing
+///
+/// Prints the transfers whose outcome differs from the recorded one. Fails on the first OUT
+/// transfer that fails although it succeeded when recorded.
+pub async fn replay(path: &Path, device: &Device) -> Result<()> {
+    let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+    for (i, text) in text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        let context = || format!("{}:{}", path.display(), i + 1);
+        let entry: Entry = serde_json::from_str(text).with_context(context)?;
+        sleep(Duration::from_millis(entry.delay_ms)).await;
+
+        match entry.direction {
+            Direction::In => {
+                let control = ControlIn {
+                    control_type: entry.kind.into(),
+                    recipient: entry.recipient.into(),
+                    request: entry.request,
+                    value: entry.value,
+                    index: entry.index,
+                    length: entry.length.context("IN transfer without length")?,
+                };
+                let (data, error) = match device.control_in(control, TIMEOUT).await {
+                    Ok(data) => (Some(raw::hex(&data)), None),
+                    Err(err) => (None, Some(format!("{err:#}"))),
+                };
+                if data != entry.data || error.is_some() != entry.error.is_some() {
+                    print_difference(&context(), &entry, &data, &error);
+                }
+            }
+            Direction::Out => {
+                let data = raw::parse_hex(entry.data.as_deref().unwrap_or_default())
+                    .with_context(context)?;
+                let control = ControlOut {
+                    control_type: entry.kind.into(),
+                    recipient: entry.recipient.into(),
+                    request: entry.request,
+                    value: entry.value,
+                    index: entry.index,
+                    data: &data,
+                };
+                match device.control_out(control, TIMEOUT).await {
+                    Err(err) if entry.error.is_none() => return Err(err.context(context())),
+                    Err(err) => log::info!("{}: failed again: {err:#}", context()),
+                    Ok(()) if entry.error.is_some() => {
+                        print_difference(&context(), &entry, &entry.data, &None);
+                    }
+                    Ok(()) => {}
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn print_difference(context: &str, entry: &Entry, data: &Option<String>, error: &Option<String>) {
+    let show = |data: &Option<String>, error: &Option<String>| match (data, error) {
+        (_, Some(error)) => format!("error: {error}"),
+        (Some(data), None) => data.clone(),
+        (None, None) => "(empty)".to_owned(),
+    };
+    println!(
+        "{context}: request={:#04x} value={:#06x} index={:#06x}: {} -> {}",
+        entry.request,
+        entry.value,
+        entry.index,
+        show(&entry.data, &entry.error),
+        show(data, error),
+    );
+}
@@ -0,0 +1,141 @@
+use crate::{backend::DeviceBackend, state_bus::StateBus, usb_device};
+use anyhow::{Result, anyhow};
+use std::{
+    future::Future,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::time::{sleep, timeout};
+
+/// Extra time a transfer gets on top of its own timeout before it counts as stuck
+const GRACE: Duration = Duration::from_secs(2);
+
+/// How often a stuck transfer is cancelled and resubmitted before resetting the device
+const RESUBMITS: u32 = 2;
+
+/// How long the task owning the state may take to answer before it is reported as stuck
+const STATE_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// How far the wall clock may run ahead of the monotonic clock before it counts as a resume
+const RESUME_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Notices when the system was suspended in between two checks
+///
+/// The monotonic clock stops during suspend while the wall clock keeps going, so after a resume
+/// the wall clock is suddenly ahead.
+#[derive(Debug)]
+pub struct ResumeDetector {
+    wall: SystemTime,
+    monotonic: Instant,
+}
+
+impl ResumeDetector {
+    pub fn start() -> Self {
+        Self {
+            wall: SystemTime::now(),
+            monotonic: Instant::now(),
+        }
+    }
+
+    /// Whether the system was suspended since the last check
+    pub fn resumed(&mut self) -> bool {
+        let wall = self.wall.elapsed().unwrap_or_default();
+        let monotonic = self.monotonic.elapsed();
+        *self = Self::start();
+        wall > monotonic + RESUME_THRESHOLD
+    }
+}
+
+/// Run a USB operation, cancelling and resubmitting it when it gets stuck
+///
+/// nusb should time out transfers on its own, but if a transfer neither completes nor times out
+/// the calling loop would go quiet forever. Dropping the future cancels the transfer, so after
+/// [`RESUBMITS`] failed attempts the device gets reset and the operation is tried one last time.
+///
+/// A stalled transfer is retried after claiming the interface again, see [`recover`].
+pub async fn guard<D, T, F, Fut>(
+    device: &D,
+    op: &str,
+    transfer_timeout: Duration,
+    mut f: F,
+) -> Result<T>
+where
+    D: DeviceBackend,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let deadline = device.retry_policy().budget(transfer_timeout) + GRACE;
+
+    for attempt in 1..=RESUBMITS {
+        let start = Instant::now();
+        match timeout(deadline, f()).await {
+            Ok(Err(err)) if usb_device::is_stall(&err) => {
+                return recover(device, op, err, deadline, f).await;
+            }
+            Ok(res) => return res,
+            Err(_) => log::warn!(
+                "watchdog: {op} stuck for {:?} (transfer timeout {transfer_timeout:?}), \
+                 resubmitting ({attempt}/{RESUBMITS})",
+                start.elapsed(),
+            ),
+        }
+    }
+
+    log::error!("watchdog: {op} still stuck after {RESUBMITS} resubmits, resetting device");
+    device.reset().await?;
+
+    match timeout(deadline, f()).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow!("{op} stuck even after resetting the device")),
+    }
+}
+
+/// Retry a stalled operation after claiming the interface again, and after resetting the device if
+/// it still stalls
+async fn recover<D, T, F, Fut>(
+    device: &D,
+    op: &str,
+    err: anyhow::Error,
+    deadline: Duration,
+    mut f: F,
+) -> Result<T>
+where
+    D: DeviceBackend,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    log::warn!("watchdog: {op} stalled ({err:#}), claiming the interface again");
+    device.reopen().await?;
+
+    match timeout(deadline, f()).await {
+        Ok(Err(err)) if usb_device::is_stall(&err) => {
+            log::error!("watchdog: {op} still stalls ({err:#}), resetting device");
+            device.reset().await?;
+            match timeout(deadline, f()).await {
+                Ok(res) => res,
+                Err(_) => Err(anyhow!("{op} stuck after resetting the device")),
+            }
+        }
+        Ok(res) => res,
+        Err(_) => Err(anyhow!("{op} stuck after claiming the interface again")),
+    }
+}
+
+/// Periodically check whether the task owning the state still answers
+///
+/// A command stuck in it can't be broken from the outside, but at least it doesn't go unnoticed.
+pub async fn watch_state(state: StateBus) {
+    loop {
+        let answer = state.with(|_| ());
+        tokio::pin!(answer);
+        if timeout(STATE_THRESHOLD, &mut answer).await.is_err() {
+            log::error!(
+                "watchdog: the state didn't answer within {STATE_THRESHOLD:?}, a command is \
+                 probably stuck"
+            );
+            answer.await;
+            log::info!("watchdog: the state answers again");
+        }
+
+        sleep(Duration::from_secs(1)).await;
+    }
+}